@@ -0,0 +1,13 @@
+#![deny(rust_2018_idioms, warnings)]
+#![deny(clippy::all, clippy::pedantic)]
+#![allow(
+    clippy::default_trait_access,
+    clippy::missing_errors_doc,
+    clippy::must_use_candidate,
+    clippy::too_many_lines,
+    clippy::uninlined_format_args,
+)]
+
+// The MQTT 3.1.1 control packets and their encoding, as used by the broker.
+
+pub mod proto;
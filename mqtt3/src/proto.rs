@@ -0,0 +1,1081 @@
+// The MQTT 3.1.1 control packets, and their encoding on the wire.
+//
+// Reference: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html
+
+// The largest remaining length that fits in the four bytes the fixed header allows for it.
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl QoS {
+    fn from_u8(raw: u8) -> Option<Self> {
+        match raw {
+            0 => Some(QoS::AtMostOnce),
+            1 => Some(QoS::AtLeastOnce),
+            2 => Some(QoS::ExactlyOnce),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+            QoS::ExactlyOnce => 2,
+        }
+    }
+}
+
+// Zero is not a valid packet identifier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PacketIdentifier(std::num::NonZeroU16);
+
+impl PacketIdentifier {
+    pub fn new(raw: u16) -> Option<Self> {
+        std::num::NonZeroU16::new(raw).map(PacketIdentifier)
+    }
+
+    pub fn get(self) -> u16 {
+        self.0.get()
+    }
+}
+
+// The QoS of a publish, along with the packet identifier and DUP flag that only publishes above QoS 0 have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketIdentifierDupQoS {
+    AtMostOnce,
+    AtLeastOnce(PacketIdentifier, bool),
+    ExactlyOnce(PacketIdentifier, bool),
+}
+
+impl PacketIdentifierDupQoS {
+    pub fn qos(self) -> QoS {
+        match self {
+            PacketIdentifierDupQoS::AtMostOnce => QoS::AtMostOnce,
+            PacketIdentifierDupQoS::AtLeastOnce(_, _) => QoS::AtLeastOnce,
+            PacketIdentifierDupQoS::ExactlyOnce(_, _) => QoS::ExactlyOnce,
+        }
+    }
+}
+
+// The client ID of a CONNECT, along with its clean session flag.
+//
+// An empty client ID with the clean session flag set asks the server to generate one, and decodes as `ServerGenerated`.
+// An empty client ID without it decodes as `IdWithExistingSession` with the empty string, which the server must refuse.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientId {
+    ServerGenerated,
+    IdWithCleanSession(String),
+    IdWithExistingSession(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionRefusedReason {
+    UnacceptableProtocolVersion,
+    IdentifierRejected,
+    ServerUnavailable,
+    BadUserNameOrPassword,
+    NotAuthorized,
+
+    // A return code that MQTT 3.1.1 reserves for future use.
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectReturnCode {
+    Accepted,
+    Refused(ConnectionRefusedReason),
+}
+
+impl ConnectReturnCode {
+    fn from_u8(raw: u8) -> Self {
+        match raw {
+            0 => ConnectReturnCode::Accepted,
+            1 => ConnectReturnCode::Refused(ConnectionRefusedReason::UnacceptableProtocolVersion),
+            2 => ConnectReturnCode::Refused(ConnectionRefusedReason::IdentifierRejected),
+            3 => ConnectReturnCode::Refused(ConnectionRefusedReason::ServerUnavailable),
+            4 => ConnectReturnCode::Refused(ConnectionRefusedReason::BadUserNameOrPassword),
+            5 => ConnectReturnCode::Refused(ConnectionRefusedReason::NotAuthorized),
+            raw => ConnectReturnCode::Refused(ConnectionRefusedReason::Other(raw)),
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            ConnectReturnCode::Accepted => 0,
+            ConnectReturnCode::Refused(ConnectionRefusedReason::UnacceptableProtocolVersion) => 1,
+            ConnectReturnCode::Refused(ConnectionRefusedReason::IdentifierRejected) => 2,
+            ConnectReturnCode::Refused(ConnectionRefusedReason::ServerUnavailable) => 3,
+            ConnectReturnCode::Refused(ConnectionRefusedReason::BadUserNameOrPassword) => 4,
+            ConnectReturnCode::Refused(ConnectionRefusedReason::NotAuthorized) => 5,
+            ConnectReturnCode::Refused(ConnectionRefusedReason::Other(raw)) => raw,
+        }
+    }
+}
+
+// A message that isn't sent as a PUBLISH of its own, like the will of a CONNECT.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publication {
+    pub topic_name: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: bytes::Bytes,
+}
+
+// The only protocol name and level that are decoded are those of MQTT 3.1.1, so these are always "MQTT" and 4
+// in a decoded CONNECT.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Connect {
+    pub username: Option<String>,
+    pub password: Option<bytes::Bytes>,
+    pub will: Option<Publication>,
+    pub client_id: ClientId,
+    pub keep_alive: std::time::Duration,
+    pub protocol_name: String,
+    pub protocol_level: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnAck {
+    pub session_present: bool,
+    pub return_code: ConnectReturnCode,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Disconnect;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PingReq;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PingResp;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PubAck {
+    pub packet_identifier: PacketIdentifier,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PubComp {
+    pub packet_identifier: PacketIdentifier,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PubRec {
+    pub packet_identifier: PacketIdentifier,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PubRel {
+    pub packet_identifier: PacketIdentifier,
+}
+
+// The payload of a decoded PUBLISH shares the allocation of the buffer it was decoded from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publish {
+    pub packet_identifier_dup_qos: PacketIdentifierDupQoS,
+    pub retain: bool,
+    pub topic_name: String,
+    pub payload: bytes::Bytes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubAckQos {
+    Success(QoS),
+    Failure,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubAck {
+    pub packet_identifier: PacketIdentifier,
+    pub qos: Vec<SubAckQos>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubscribeTo {
+    pub topic_filter: String,
+    pub qos: QoS,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subscribe {
+    pub packet_identifier: PacketIdentifier,
+    pub subscribe_to: Vec<SubscribeTo>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnsubAck {
+    pub packet_identifier: PacketIdentifier,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unsubscribe {
+    pub packet_identifier: PacketIdentifier,
+    pub unsubscribe_from: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Packet {
+    ConnAck(ConnAck),
+    Connect(Connect),
+    Disconnect(Disconnect),
+    PingReq(PingReq),
+    PingResp(PingResp),
+    PubAck(PubAck),
+    PubComp(PubComp),
+    Publish(Publish),
+    PubRec(PubRec),
+    PubRel(PubRel),
+    SubAck(SubAck),
+    Subscribe(Subscribe),
+    UnsubAck(UnsubAck),
+    Unsubscribe(Unsubscribe),
+}
+
+// Decodes the packets of one connection, by way of `decode`.
+//
+// It holds no state of its own yet, since a packet is only decoded once all of it has been received. It's still kept
+// per connection so that one that does can be added without changing the callers.
+#[derive(Debug, Default)]
+pub struct PacketDecoder {
+    _private: (),
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    RemainingLengthTooLarge,
+    UnrecognizedPacket { packet_type: u8, flags: u8 },
+    UnexpectedEndOfPacket,
+    TrailingData(usize),
+    InvalidUtf8,
+    NulInString,
+    UnrecognizedProtocolName(String),
+    UnrecognizedProtocolLevel(u8),
+    InvalidConnectFlags(u8),
+    InvalidConnAckFlags(u8),
+    UnrecognizedQoS(u8),
+    PublishDupAtMostOnce,
+    ZeroPacketIdentifier,
+    InvalidSubscriptionOptions(u8),
+    UnrecognizedSubAckReturnCode(u8),
+    NoTopics,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::RemainingLengthTooLarge => f.write_str("remaining length is longer than four bytes"),
+            DecodeError::UnrecognizedPacket { packet_type, flags } =>
+                write!(f, "unrecognized packet type {} with flags 0x{:x}", packet_type, flags),
+            DecodeError::UnexpectedEndOfPacket => f.write_str("packet ended before all of its fields"),
+            DecodeError::TrailingData(len) => write!(f, "{} bytes left over after the end of the packet", len),
+            DecodeError::InvalidUtf8 => f.write_str("string is not valid UTF-8"),
+            DecodeError::NulInString => f.write_str("string contains U+0000"),
+            DecodeError::UnrecognizedProtocolName(protocol_name) => write!(f, "unrecognized protocol name {:?}", protocol_name),
+            DecodeError::UnrecognizedProtocolLevel(protocol_level) => write!(f, "unrecognized protocol level {}", protocol_level),
+            DecodeError::InvalidConnectFlags(flags) => write!(f, "invalid CONNECT flags 0x{:02x}", flags),
+            DecodeError::InvalidConnAckFlags(flags) => write!(f, "invalid CONNACK flags 0x{:02x}", flags),
+            DecodeError::UnrecognizedQoS(qos) => write!(f, "unrecognized QoS {}", qos),
+            DecodeError::PublishDupAtMostOnce => f.write_str("PUBLISH has the DUP flag set at QoS 0"),
+            DecodeError::ZeroPacketIdentifier => f.write_str("packet identifier is 0"),
+            DecodeError::InvalidSubscriptionOptions(options) => write!(f, "invalid subscription options 0x{:02x}", options),
+            DecodeError::UnrecognizedSubAckReturnCode(return_code) => write!(f, "unrecognized SUBACK return code 0x{:02x}", return_code),
+            DecodeError::NoTopics => f.write_str("packet has no topic filters"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug)]
+pub enum EncodeError {
+    PacketTooLarge(usize),
+    StringTooLarge(usize),
+    BinaryTooLarge(usize),
+    PasswordWithoutUsername,
+    NoTopics,
+    InsufficientBuffer { required: usize, available: usize },
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::PacketTooLarge(len) => write!(f, "remaining length of {} bytes is too large to be encoded", len),
+            EncodeError::StringTooLarge(len) => write!(f, "string of {} bytes is too large to be encoded", len),
+            EncodeError::BinaryTooLarge(len) => write!(f, "binary data of {} bytes is too large to be encoded", len),
+            EncodeError::PasswordWithoutUsername => f.write_str("CONNECT has a password but no username"),
+            EncodeError::NoTopics => f.write_str("packet has no topic filters"),
+            EncodeError::InsufficientBuffer { required, available } =>
+                write!(f, "packet of {} bytes doesn't fit in the {} bytes left in the buffer", required, available),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+// Decodes the packet at the start of src, and removes it from src.
+//
+// Returns None and leaves src as it is if the packet hasn't been completely received yet. Once the fixed header has been
+// received, the caller can use the remaining length in it to find out how much more of the packet there is to receive.
+pub fn decode(_decoder: &mut PacketDecoder, src: &mut bytes::BytesMut) -> Result<Option<Packet>, DecodeError> {
+    let (first_byte, fixed_header_len, remaining_length) = match decode_fixed_header(src)? {
+        Some(fixed_header) => fixed_header,
+        None => return Ok(None),
+    };
+
+    if src.len() < fixed_header_len + remaining_length {
+        return Ok(None);
+    }
+
+    let mut src = src.split_to(fixed_header_len + remaining_length).freeze();
+    bytes::Buf::advance(&mut src, fixed_header_len);
+
+    let packet_type = first_byte >> 4;
+    let flags = first_byte & 0x0F;
+
+    let packet = match (packet_type, flags) {
+        (1, 0) => Packet::Connect(decode_connect(&mut src)?),
+
+        (2, 0) => {
+            let ack_flags = decode_u8(&mut src)?;
+            if ack_flags & !0x01 != 0 {
+                return Err(DecodeError::InvalidConnAckFlags(ack_flags));
+            }
+
+            let return_code = ConnectReturnCode::from_u8(decode_u8(&mut src)?);
+
+            Packet::ConnAck(ConnAck {
+                session_present: ack_flags & 0x01 != 0,
+                return_code,
+            })
+        },
+
+        (3, flags) => {
+            let dup = flags & 0x08 != 0;
+            let qos = (flags >> 1) & 0x03;
+            let retain = flags & 0x01 != 0;
+
+            let topic_name = decode_string(&mut src)?;
+
+            let packet_identifier_dup_qos = match QoS::from_u8(qos).ok_or(DecodeError::UnrecognizedQoS(qos))? {
+                QoS::AtMostOnce if dup => return Err(DecodeError::PublishDupAtMostOnce),
+                QoS::AtMostOnce => PacketIdentifierDupQoS::AtMostOnce,
+                QoS::AtLeastOnce => PacketIdentifierDupQoS::AtLeastOnce(decode_packet_identifier(&mut src)?, dup),
+                QoS::ExactlyOnce => PacketIdentifierDupQoS::ExactlyOnce(decode_packet_identifier(&mut src)?, dup),
+            };
+
+            // The payload is the rest of the packet, and may be empty.
+            let payload = std::mem::take(&mut src);
+
+            Packet::Publish(Publish {
+                packet_identifier_dup_qos,
+                retain,
+                topic_name,
+                payload,
+            })
+        },
+
+        (4, 0) => Packet::PubAck(PubAck { packet_identifier: decode_packet_identifier(&mut src)? }),
+
+        (5, 0) => Packet::PubRec(PubRec { packet_identifier: decode_packet_identifier(&mut src)? }),
+
+        (6, 2) => Packet::PubRel(PubRel { packet_identifier: decode_packet_identifier(&mut src)? }),
+
+        (7, 0) => Packet::PubComp(PubComp { packet_identifier: decode_packet_identifier(&mut src)? }),
+
+        (8, 2) => {
+            let packet_identifier = decode_packet_identifier(&mut src)?;
+
+            let mut subscribe_to = vec![];
+            while !src.is_empty() {
+                let topic_filter = decode_string(&mut src)?;
+
+                let options = decode_u8(&mut src)?;
+                if options & !0x03 != 0 {
+                    return Err(DecodeError::InvalidSubscriptionOptions(options));
+                }
+                let qos = QoS::from_u8(options).ok_or(DecodeError::UnrecognizedQoS(options))?;
+
+                subscribe_to.push(SubscribeTo { topic_filter, qos });
+            }
+
+            if subscribe_to.is_empty() {
+                return Err(DecodeError::NoTopics);
+            }
+
+            Packet::Subscribe(Subscribe { packet_identifier, subscribe_to })
+        },
+
+        (9, 0) => {
+            let packet_identifier = decode_packet_identifier(&mut src)?;
+
+            let mut qos = vec![];
+            while !src.is_empty() {
+                let return_code = decode_u8(&mut src)?;
+                qos.push(match return_code {
+                    0x80 => SubAckQos::Failure,
+                    return_code => SubAckQos::Success(
+                        QoS::from_u8(return_code).ok_or(DecodeError::UnrecognizedSubAckReturnCode(return_code))?,
+                    ),
+                });
+            }
+
+            Packet::SubAck(SubAck { packet_identifier, qos })
+        },
+
+        (10, 2) => {
+            let packet_identifier = decode_packet_identifier(&mut src)?;
+
+            let mut unsubscribe_from = vec![];
+            while !src.is_empty() {
+                unsubscribe_from.push(decode_string(&mut src)?);
+            }
+
+            if unsubscribe_from.is_empty() {
+                return Err(DecodeError::NoTopics);
+            }
+
+            Packet::Unsubscribe(Unsubscribe { packet_identifier, unsubscribe_from })
+        },
+
+        (11, 0) => Packet::UnsubAck(UnsubAck { packet_identifier: decode_packet_identifier(&mut src)? }),
+
+        (12, 0) => Packet::PingReq(PingReq),
+
+        (13, 0) => Packet::PingResp(PingResp),
+
+        (14, 0) => Packet::Disconnect(Disconnect),
+
+        (packet_type, flags) => return Err(DecodeError::UnrecognizedPacket { packet_type, flags }),
+    };
+
+    if !src.is_empty() {
+        return Err(DecodeError::TrailingData(src.len()));
+    }
+
+    Ok(Some(packet))
+}
+
+// Encodes the packet onto the end of dst.
+//
+// Nothing is written to dst if the packet can't be encoded.
+//
+// The packet is taken by value, since the caller is always done with it once it has been encoded.
+#[allow(clippy::needless_pass_by_value)]
+pub fn encode<B>(packet: Packet, dst: &mut B) -> Result<(), EncodeError> where B: bytes::BufMut {
+    // The packet is encoded twice, first only to find out its length, since the remaining length precedes the rest of it.
+    let mut counter = Counter(0);
+    let first_byte = encode_body(&packet, &mut counter)?;
+    let remaining_length = counter.0;
+    if remaining_length > MAX_REMAINING_LENGTH {
+        return Err(EncodeError::PacketTooLarge(remaining_length));
+    }
+
+    let mut remaining_length_bytes = [0_u8; 4];
+    let mut remaining_length_len = 0;
+    let mut rest = remaining_length;
+    loop {
+        #[allow(clippy::cast_possible_truncation)]
+        let digit = (rest & 0x7F) as u8;
+        rest >>= 7;
+        remaining_length_bytes[remaining_length_len] = if rest == 0 { digit } else { digit | 0x80 };
+        remaining_length_len += 1;
+        if rest == 0 {
+            break;
+        }
+    }
+
+    let required = 1 + remaining_length_len + remaining_length;
+    let available = dst.remaining_mut();
+    if available < required {
+        return Err(EncodeError::InsufficientBuffer { required, available });
+    }
+
+    dst.put_u8(first_byte);
+    dst.put_slice(&remaining_length_bytes[..remaining_length_len]);
+    let _ = encode_body(&packet, &mut Writer(dst))?;
+
+    Ok(())
+}
+
+// Returns the first byte of the fixed header, the length of the fixed header, and the remaining length,
+// or None if the fixed header hasn't been completely received yet.
+fn decode_fixed_header(src: &[u8]) -> Result<Option<(u8, usize, usize)>, DecodeError> {
+    let first_byte = match src.first() {
+        Some(&first_byte) => first_byte,
+        None => return Ok(None),
+    };
+
+    let mut remaining_length = 0;
+    for (i, &digit) in src.iter().skip(1).take(4).enumerate() {
+        remaining_length |= usize::from(digit & 0x7F) << (7 * i);
+        if digit & 0x80 == 0 {
+            return Ok(Some((first_byte, 1 + i + 1, remaining_length)));
+        }
+    }
+
+    if src.len() > 4 {
+        return Err(DecodeError::RemainingLengthTooLarge);
+    }
+
+    Ok(None)
+}
+
+fn decode_connect(src: &mut bytes::Bytes) -> Result<Connect, DecodeError> {
+    let protocol_name = decode_string(src)?;
+    if protocol_name != "MQTT" {
+        return Err(DecodeError::UnrecognizedProtocolName(protocol_name));
+    }
+
+    let protocol_level = decode_u8(src)?;
+    if protocol_level != 4 {
+        return Err(DecodeError::UnrecognizedProtocolLevel(protocol_level));
+    }
+
+    let connect_flags = decode_u8(src)?;
+    let has_username = connect_flags & 0x80 != 0;
+    let has_password = connect_flags & 0x40 != 0;
+    let will_retain = connect_flags & 0x20 != 0;
+    let will_qos = (connect_flags >> 3) & 0x03;
+    let has_will = connect_flags & 0x04 != 0;
+    let clean_session = connect_flags & 0x02 != 0;
+
+    // The reserved flag must be unset, the will's QoS and retain flag must be unset if there's no will,
+    // and MQTT 3.1.1 doesn't allow a password without a username.
+    if connect_flags & 0x01 != 0 || (!has_will && (will_qos != 0 || will_retain)) || (has_password && !has_username) {
+        return Err(DecodeError::InvalidConnectFlags(connect_flags));
+    }
+
+    let keep_alive = std::time::Duration::from_secs(decode_u16(src)?.into());
+
+    let client_id = decode_string(src)?;
+    let client_id =
+        if client_id.is_empty() && clean_session { ClientId::ServerGenerated }
+        else if clean_session { ClientId::IdWithCleanSession(client_id) }
+        else { ClientId::IdWithExistingSession(client_id) };
+
+    let will =
+        if has_will {
+            let topic_name = decode_string(src)?;
+            let payload = decode_binary(src)?;
+            Some(Publication {
+                topic_name,
+                qos: QoS::from_u8(will_qos).ok_or(DecodeError::UnrecognizedQoS(will_qos))?,
+                retain: will_retain,
+                payload,
+            })
+        }
+        else {
+            None
+        };
+
+    let username = if has_username { Some(decode_string(src)?) } else { None };
+    let password = if has_password { Some(decode_binary(src)?) } else { None };
+
+    Ok(Connect {
+        username,
+        password,
+        will,
+        client_id,
+        keep_alive,
+        protocol_name,
+        protocol_level,
+    })
+}
+
+fn decode_u8(src: &mut bytes::Bytes) -> Result<u8, DecodeError> {
+    if src.is_empty() {
+        return Err(DecodeError::UnexpectedEndOfPacket);
+    }
+
+    Ok(bytes::Buf::get_u8(src))
+}
+
+fn decode_u16(src: &mut bytes::Bytes) -> Result<u16, DecodeError> {
+    if src.len() < 2 {
+        return Err(DecodeError::UnexpectedEndOfPacket);
+    }
+
+    Ok(bytes::Buf::get_u16(src))
+}
+
+fn decode_packet_identifier(src: &mut bytes::Bytes) -> Result<PacketIdentifier, DecodeError> {
+    PacketIdentifier::new(decode_u16(src)?).ok_or(DecodeError::ZeroPacketIdentifier)
+}
+
+fn decode_binary(src: &mut bytes::Bytes) -> Result<bytes::Bytes, DecodeError> {
+    let len = usize::from(decode_u16(src)?);
+    if src.len() < len {
+        return Err(DecodeError::UnexpectedEndOfPacket);
+    }
+
+    Ok(src.split_to(len))
+}
+
+fn decode_string(src: &mut bytes::Bytes) -> Result<String, DecodeError> {
+    let raw = decode_binary(src)?;
+    let s = std::str::from_utf8(&raw).map_err(|_| DecodeError::InvalidUtf8)?;
+    if s.contains('\u{0000}') {
+        return Err(DecodeError::NulInString);
+    }
+
+    Ok(s.to_owned())
+}
+
+// Where `encode_body` writes to. Either counts the bytes, or writes them out.
+trait Sink {
+    fn put_slice(&mut self, src: &[u8]);
+
+    fn put_u8(&mut self, n: u8) {
+        self.put_slice(&[n]);
+    }
+
+    fn put_u16(&mut self, n: u16) {
+        self.put_slice(&n.to_be_bytes());
+    }
+}
+
+struct Counter(usize);
+
+impl Sink for Counter {
+    fn put_slice(&mut self, src: &[u8]) {
+        self.0 += src.len();
+    }
+}
+
+struct Writer<'a, B>(&'a mut B);
+
+impl<B> Sink for Writer<'_, B> where B: bytes::BufMut {
+    fn put_slice(&mut self, src: &[u8]) {
+        self.0.put_slice(src);
+    }
+}
+
+// Encodes everything after the remaining length, and returns the first byte of the fixed header.
+fn encode_body(packet: &Packet, dst: &mut impl Sink) -> Result<u8, EncodeError> {
+    match packet {
+        Packet::Connect(Connect { username, password, will, client_id, keep_alive, protocol_name, protocol_level }) => {
+            if password.is_some() && username.is_none() {
+                return Err(EncodeError::PasswordWithoutUsername);
+            }
+
+            encode_string(protocol_name, dst)?;
+            dst.put_u8(*protocol_level);
+
+            let mut connect_flags = 0;
+            if username.is_some() {
+                connect_flags |= 0x80;
+            }
+            if password.is_some() {
+                connect_flags |= 0x40;
+            }
+            if let Some(will) = will {
+                if will.retain {
+                    connect_flags |= 0x20;
+                }
+                connect_flags |= will.qos.to_u8() << 3;
+                connect_flags |= 0x04;
+            }
+            let client_id = match client_id {
+                ClientId::ServerGenerated => {
+                    connect_flags |= 0x02;
+                    ""
+                },
+                ClientId::IdWithCleanSession(client_id) => {
+                    connect_flags |= 0x02;
+                    client_id
+                },
+                ClientId::IdWithExistingSession(client_id) => client_id,
+            };
+            dst.put_u8(connect_flags);
+
+            // Keep alives too long to be encoded are capped, rather than wrapping around to shorter ones.
+            dst.put_u16(std::convert::TryInto::try_into(keep_alive.as_secs()).unwrap_or(u16::MAX));
+
+            encode_string(client_id, dst)?;
+
+            if let Some(will) = will {
+                encode_string(&will.topic_name, dst)?;
+                encode_binary(&will.payload, dst)?;
+            }
+
+            if let Some(username) = username {
+                encode_string(username, dst)?;
+            }
+
+            if let Some(password) = password {
+                encode_binary(password, dst)?;
+            }
+
+            Ok(0x10)
+        },
+
+        Packet::ConnAck(ConnAck { session_present, return_code }) => {
+            dst.put_u8(u8::from(*session_present));
+            dst.put_u8(return_code.to_u8());
+            Ok(0x20)
+        },
+
+        Packet::Publish(Publish { packet_identifier_dup_qos, retain, topic_name, payload }) => {
+            encode_string(topic_name, dst)?;
+
+            let mut first_byte = 0x30 | (packet_identifier_dup_qos.qos().to_u8() << 1);
+            match packet_identifier_dup_qos {
+                PacketIdentifierDupQoS::AtMostOnce => (),
+                PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, dup) |
+                PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, dup) => {
+                    if *dup {
+                        first_byte |= 0x08;
+                    }
+                    dst.put_u16(packet_identifier.get());
+                },
+            }
+            if *retain {
+                first_byte |= 0x01;
+            }
+
+            dst.put_slice(payload);
+
+            Ok(first_byte)
+        },
+
+        Packet::PubAck(PubAck { packet_identifier }) => {
+            dst.put_u16(packet_identifier.get());
+            Ok(0x40)
+        },
+
+        Packet::PubRec(PubRec { packet_identifier }) => {
+            dst.put_u16(packet_identifier.get());
+            Ok(0x50)
+        },
+
+        Packet::PubRel(PubRel { packet_identifier }) => {
+            dst.put_u16(packet_identifier.get());
+            Ok(0x62)
+        },
+
+        Packet::PubComp(PubComp { packet_identifier }) => {
+            dst.put_u16(packet_identifier.get());
+            Ok(0x70)
+        },
+
+        Packet::Subscribe(Subscribe { packet_identifier, subscribe_to }) => {
+            if subscribe_to.is_empty() {
+                return Err(EncodeError::NoTopics);
+            }
+
+            dst.put_u16(packet_identifier.get());
+            for SubscribeTo { topic_filter, qos } in subscribe_to {
+                encode_string(topic_filter, dst)?;
+                dst.put_u8(qos.to_u8());
+            }
+
+            Ok(0x82)
+        },
+
+        Packet::SubAck(SubAck { packet_identifier, qos }) => {
+            dst.put_u16(packet_identifier.get());
+            for qos in qos {
+                dst.put_u8(match qos {
+                    SubAckQos::Success(qos) => qos.to_u8(),
+                    SubAckQos::Failure => 0x80,
+                });
+            }
+
+            Ok(0x90)
+        },
+
+        Packet::Unsubscribe(Unsubscribe { packet_identifier, unsubscribe_from }) => {
+            if unsubscribe_from.is_empty() {
+                return Err(EncodeError::NoTopics);
+            }
+
+            dst.put_u16(packet_identifier.get());
+            for topic_filter in unsubscribe_from {
+                encode_string(topic_filter, dst)?;
+            }
+
+            Ok(0xA2)
+        },
+
+        Packet::UnsubAck(UnsubAck { packet_identifier }) => {
+            dst.put_u16(packet_identifier.get());
+            Ok(0xB0)
+        },
+
+        Packet::PingReq(PingReq) => Ok(0xC0),
+
+        Packet::PingResp(PingResp) => Ok(0xD0),
+
+        Packet::Disconnect(Disconnect) => Ok(0xE0),
+    }
+}
+
+fn encode_binary(src: &[u8], dst: &mut impl Sink) -> Result<(), EncodeError> {
+    let len = std::convert::TryInto::try_into(src.len()).map_err(|_| EncodeError::BinaryTooLarge(src.len()))?;
+    dst.put_u16(len);
+    dst.put_slice(src);
+    Ok(())
+}
+
+fn encode_string(src: &str, dst: &mut impl Sink) -> Result<(), EncodeError> {
+    let len = std::convert::TryInto::try_into(src.len()).map_err(|_| EncodeError::StringTooLarge(src.len()))?;
+    dst.put_u16(len);
+    dst.put_slice(src.as_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    fn packet_identifier(raw: u16) -> super::PacketIdentifier {
+        super::PacketIdentifier::new(raw).unwrap()
+    }
+
+    fn encode(packet: super::Packet) -> Vec<u8> {
+        let mut encoded = vec![];
+        super::encode(packet, &mut encoded).unwrap();
+        encoded
+    }
+
+    fn decode(encoded: &[u8]) -> Result<Option<super::Packet>, super::DecodeError> {
+        let mut src = bytes::BytesMut::from(encoded);
+        let packet = super::decode(&mut Default::default(), &mut src);
+        if let Ok(Some(_)) = &packet {
+            assert!(src.is_empty());
+        }
+        packet
+    }
+
+    #[test]
+    fn round_trip() {
+        let packets = vec![
+            super::Packet::Connect(super::Connect {
+                username: Some("user".to_owned()),
+                password: Some(bytes::Bytes::from_static(b"\x00\xffpass")),
+                will: Some(super::Publication {
+                    topic_name: "will/topic".to_owned(),
+                    qos: super::QoS::ExactlyOnce,
+                    retain: true,
+                    payload: bytes::Bytes::from_static(b"gone"),
+                }),
+                client_id: super::ClientId::IdWithExistingSession("client".to_owned()),
+                keep_alive: std::time::Duration::from_secs(60),
+                protocol_name: "MQTT".to_owned(),
+                protocol_level: 4,
+            }),
+            super::Packet::Connect(super::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: super::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(0),
+                protocol_name: "MQTT".to_owned(),
+                protocol_level: 4,
+            }),
+            super::Packet::ConnAck(super::ConnAck { session_present: true, return_code: super::ConnectReturnCode::Accepted }),
+            super::Packet::ConnAck(super::ConnAck {
+                session_present: false,
+                return_code: super::ConnectReturnCode::Refused(super::ConnectionRefusedReason::IdentifierRejected),
+            }),
+            super::Packet::Publish(super::Publish {
+                packet_identifier_dup_qos: super::PacketIdentifierDupQoS::AtMostOnce,
+                retain: false,
+                topic_name: "a/b".to_owned(),
+                payload: bytes::Bytes::new(),
+            }),
+            super::Packet::Publish(super::Publish {
+                packet_identifier_dup_qos: super::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier(0xABCD), true),
+                retain: true,
+                topic_name: "a/b".to_owned(),
+                payload: bytes::Bytes::from(vec![7; 300]),
+            }),
+            super::Packet::PubAck(super::PubAck { packet_identifier: packet_identifier(1) }),
+            super::Packet::PubRec(super::PubRec { packet_identifier: packet_identifier(2) }),
+            super::Packet::PubRel(super::PubRel { packet_identifier: packet_identifier(3) }),
+            super::Packet::PubComp(super::PubComp { packet_identifier: packet_identifier(4) }),
+            super::Packet::Subscribe(super::Subscribe {
+                packet_identifier: packet_identifier(5),
+                subscribe_to: vec![
+                    super::SubscribeTo { topic_filter: "a/+".to_owned(), qos: super::QoS::AtLeastOnce },
+                    super::SubscribeTo { topic_filter: "#".to_owned(), qos: super::QoS::AtMostOnce },
+                ],
+            }),
+            super::Packet::SubAck(super::SubAck {
+                packet_identifier: packet_identifier(5),
+                qos: vec![super::SubAckQos::Success(super::QoS::AtLeastOnce), super::SubAckQos::Failure],
+            }),
+            super::Packet::Unsubscribe(super::Unsubscribe { packet_identifier: packet_identifier(6), unsubscribe_from: vec!["a/+".to_owned()] }),
+            super::Packet::UnsubAck(super::UnsubAck { packet_identifier: packet_identifier(6) }),
+            super::Packet::PingReq(super::PingReq),
+            super::Packet::PingResp(super::PingResp),
+            super::Packet::Disconnect(super::Disconnect),
+        ];
+
+        for packet in packets {
+            let encoded = encode(packet.clone());
+            assert_eq!(decode(&encoded).unwrap(), Some(packet));
+        }
+    }
+
+    #[test]
+    fn encode_connect() {
+        let encoded = encode(super::Packet::Connect(super::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: super::ClientId::IdWithCleanSession("c".to_owned()),
+            keep_alive: std::time::Duration::from_secs(10),
+            protocol_name: "MQTT".to_owned(),
+            protocol_level: 4,
+        }));
+        assert_eq!(encoded, [0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 10, 0, 1, b'c']);
+    }
+
+    #[test]
+    fn encode_multi_byte_remaining_length() {
+        let encoded = encode(super::Packet::Publish(super::Publish {
+            packet_identifier_dup_qos: super::PacketIdentifierDupQoS::AtMostOnce,
+            retain: false,
+            topic_name: "t".to_owned(),
+            payload: bytes::Bytes::from(vec![0; 200 - 3]),
+        }));
+        assert_eq!(encoded[..5], [0x30, 0xC8, 0x01, 0, 1]);
+        assert_eq!(encoded.len(), 3 + 200);
+    }
+
+    #[test]
+    fn encode_invalid() {
+        let mut dst = vec![];
+
+        let err = super::encode(super::Packet::Subscribe(super::Subscribe { packet_identifier: packet_identifier(1), subscribe_to: vec![] }), &mut dst);
+        assert!(matches!(err, Err(super::EncodeError::NoTopics)));
+
+        let err = super::encode(super::Packet::Publish(super::Publish {
+            packet_identifier_dup_qos: super::PacketIdentifierDupQoS::AtMostOnce,
+            retain: false,
+            topic_name: "t".repeat(70_000),
+            payload: bytes::Bytes::new(),
+        }), &mut dst);
+        assert!(matches!(err, Err(super::EncodeError::StringTooLarge(70_000))));
+
+        let mut small = [0_u8; 2];
+        let err = super::encode(super::Packet::PubAck(super::PubAck { packet_identifier: packet_identifier(1) }), &mut &mut small[..]);
+        assert!(matches!(err, Err(super::EncodeError::InsufficientBuffer { required: 4, available: 2 })));
+
+        // Nothing is written for a packet that can't be encoded.
+        assert!(dst.is_empty());
+    }
+
+    #[test]
+    fn decode_partial() {
+        let encoded = encode(super::Packet::Unsubscribe(super::Unsubscribe {
+            packet_identifier: packet_identifier(1),
+            unsubscribe_from: vec!["a/b".to_owned()],
+        }));
+
+        for len in 0..encoded.len() {
+            let mut src = bytes::BytesMut::from(&encoded[..len]);
+            assert!(super::decode(&mut Default::default(), &mut src).unwrap().is_none());
+            assert_eq!(src.len(), len);
+        }
+    }
+
+    #[test]
+    fn decode_leaves_next_packet() {
+        let mut src = bytes::BytesMut::from(&[0xC0, 0, 0xE0, 0, 0x30][..]);
+        let mut decoder = Default::default();
+        assert_eq!(super::decode(&mut decoder, &mut src).unwrap(), Some(super::Packet::PingReq(super::PingReq)));
+        assert_eq!(super::decode(&mut decoder, &mut src).unwrap(), Some(super::Packet::Disconnect(super::Disconnect)));
+        assert_eq!(super::decode(&mut decoder, &mut src).unwrap(), None);
+        assert_eq!(&src[..], [0x30]);
+    }
+
+    #[test]
+    fn decode_connect_client_id() {
+        let connect = |flags, client_id: &[u8]| {
+            let mut encoded = vec![0x10, 0, 0, 4, b'M', b'Q', b'T', b'T', 4, flags, 0, 0];
+            encoded.extend_from_slice(&[0, std::convert::TryInto::<u8>::try_into(client_id.len()).unwrap()]);
+            encoded.extend_from_slice(client_id);
+            encoded[1] = std::convert::TryInto::<u8>::try_into(encoded.len() - 2).unwrap();
+            match decode(&encoded) {
+                Ok(Some(super::Packet::Connect(connect))) => connect.client_id,
+                packet => panic!("{:?}", packet),
+            }
+        };
+
+        assert_eq!(connect(0x02, b""), super::ClientId::ServerGenerated);
+        assert_eq!(connect(0x00, b""), super::ClientId::IdWithExistingSession(String::new()));
+        assert_eq!(connect(0x02, b"c"), super::ClientId::IdWithCleanSession("c".to_owned()));
+        assert_eq!(connect(0x00, b"c"), super::ClientId::IdWithExistingSession("c".to_owned()));
+    }
+
+    #[test]
+    fn decode_malformed() {
+        // The remaining length takes more than four bytes.
+        assert!(matches!(decode(&[0x30, 0x80, 0x80, 0x80, 0x80, 0x01]), Err(super::DecodeError::RemainingLengthTooLarge)));
+
+        // Reserved flags.
+        assert!(matches!(decode(&[0xC1, 0]), Err(super::DecodeError::UnrecognizedPacket { packet_type: 12, flags: 1 })));
+        assert!(matches!(decode(&[0x60, 2, 0, 1]), Err(super::DecodeError::UnrecognizedPacket { packet_type: 6, flags: 0 })));
+
+        // Reserved packet types.
+        assert!(matches!(decode(&[0x00, 0]), Err(super::DecodeError::UnrecognizedPacket { packet_type: 0, flags: 0 })));
+        assert!(matches!(decode(&[0xF0, 0]), Err(super::DecodeError::UnrecognizedPacket { packet_type: 15, flags: 0 })));
+
+        assert!(matches!(decode(&[0x40, 2, 0, 0]), Err(super::DecodeError::ZeroPacketIdentifier)));
+        assert!(matches!(decode(&[0x40, 1, 0]), Err(super::DecodeError::UnexpectedEndOfPacket)));
+        assert!(matches!(decode(&[0x40, 3, 0, 1, 0]), Err(super::DecodeError::TrailingData(1))));
+        assert!(matches!(decode(&[0xC0, 1, 0]), Err(super::DecodeError::TrailingData(1))));
+
+        // QoS 3, and DUP at QoS 0.
+        assert!(matches!(decode(&[0x36, 5, 0, 1, b't', 0, 1]), Err(super::DecodeError::UnrecognizedQoS(3))));
+        assert!(matches!(decode(&[0x38, 3, 0, 1, b't']), Err(super::DecodeError::PublishDupAtMostOnce)));
+
+        // Topic names that aren't valid UTF-8, or contain U+0000.
+        assert!(matches!(decode(&[0x30, 3, 0, 1, 0xFF]), Err(super::DecodeError::InvalidUtf8)));
+        assert!(matches!(decode(&[0x30, 3, 0, 1, 0x00]), Err(super::DecodeError::NulInString)));
+
+        // A SUBSCRIBE with no topic filters, or with reserved bits set in its options.
+        assert!(matches!(decode(&[0x82, 2, 0, 1]), Err(super::DecodeError::NoTopics)));
+        assert!(matches!(decode(&[0x82, 6, 0, 1, 0, 1, b't', 0x04]), Err(super::DecodeError::InvalidSubscriptionOptions(0x04))));
+        assert!(matches!(decode(&[0x82, 6, 0, 1, 0, 1, b't', 0x03]), Err(super::DecodeError::UnrecognizedQoS(3))));
+        assert!(matches!(decode(&[0xA2, 2, 0, 1]), Err(super::DecodeError::NoTopics)));
+
+        assert!(matches!(decode(&[0x90, 3, 0, 1, 0x03]), Err(super::DecodeError::UnrecognizedSubAckReturnCode(3))));
+        assert!(matches!(decode(&[0x20, 2, 0x02, 0]), Err(super::DecodeError::InvalidConnAckFlags(0x02))));
+    }
+
+    #[test]
+    fn decode_malformed_connect() {
+        let connect = |protocol_name: &[u8], protocol_level, flags| {
+            let mut encoded = vec![0x10, 0, 0, std::convert::TryInto::<u8>::try_into(protocol_name.len()).unwrap()];
+            encoded.extend_from_slice(protocol_name);
+            encoded.extend_from_slice(&[protocol_level, flags, 0, 0, 0, 1, b'c']);
+            encoded[1] = std::convert::TryInto::<u8>::try_into(encoded.len() - 2).unwrap();
+            decode(&encoded)
+        };
+
+        assert!(connect(b"MQTT", 4, 0x02).unwrap().is_some());
+
+        assert!(matches!(connect(b"MQIsdp", 3, 0x02), Err(super::DecodeError::UnrecognizedProtocolName(_))));
+        assert!(matches!(connect(b"MQTT", 5, 0x02), Err(super::DecodeError::UnrecognizedProtocolLevel(5))));
+
+        // The reserved flag.
+        assert!(matches!(connect(b"MQTT", 4, 0x03), Err(super::DecodeError::InvalidConnectFlags(0x03))));
+
+        // Will QoS and retain without a will.
+        assert!(matches!(connect(b"MQTT", 4, 0x0A), Err(super::DecodeError::InvalidConnectFlags(0x0A))));
+        assert!(matches!(connect(b"MQTT", 4, 0x22), Err(super::DecodeError::InvalidConnectFlags(0x22))));
+
+        // A password without a username.
+        assert!(matches!(connect(b"MQTT", 4, 0x42), Err(super::DecodeError::InvalidConnectFlags(0x42))));
+
+        // A username that isn't there.
+        assert!(matches!(connect(b"MQTT", 4, 0x82), Err(super::DecodeError::UnexpectedEndOfPacket)));
+    }
+}
@@ -1,36 +1,134 @@
+// The number of connections the acceptor rejects each time it's polled before it yields, so that a flood of connections
+// that are rejected as soon as they're accepted can't starve every other source.
+const REJECT_BUDGET: usize = 32;
+
 pub struct Acceptor {
     inner: std::net::TcpListener,
     session: std::rc::Rc<crate::Session>,
+    config: AcceptorConfig,
+
+    // The accept rate limit, if max_accept_rate is set.
+    accept_rate: Option<crate::TokenBucket>,
+
+    // Whether the last poll is blocked on the number of connections or on congestion rather than on the listener,
+    // in which case the listener doesn't need to be watched until the acceptor is woken.
+    paused: bool,
+}
+
+pub struct AcceptorConfig {
+    // The most clients that can be connected at once. Once there are this many, no more connections are accepted
+    // until one of them disconnects.
+    pub max_connections: Option<usize>,
+
+    // The most clients that can be connected at once from a single address. Connections beyond this are closed
+    // as soon as they're accepted.
+    pub max_connections_per_ip: Option<usize>,
+
+    // The most connections accepted per second, on average. Connections beyond this are closed as soon as they're accepted.
+    pub max_accept_rate: Option<u32>,
+
+    // The addresses that connections are accepted from. Connections from other addresses are closed as soon as they're accepted.
+    // This is the broker's only access list, since the runtime serves a single acceptor, and it's the one the admin API manages.
+    pub access_list: crate::AccessList,
+
+    // Overrides `SessionConfig::max_packet_size` for every client the acceptor accepts.
+    pub max_packet_size: Option<usize>,
+}
+
+impl Default for AcceptorConfig {
+    fn default() -> Self {
+        AcceptorConfig {
+            max_connections: Some(50_000),
+            max_connections_per_ip: Some(1_000),
+            max_accept_rate: Some(1_000),
+            access_list: Default::default(),
+            max_packet_size: None,
+        }
+    }
 }
 
 impl Acceptor {
     pub fn bind(
         addr: impl std::net::ToSocketAddrs,
         session: std::rc::Rc<crate::Session>,
-    ) -> std::io::Result<Self> {
+        config: AcceptorConfig,
+    ) -> Result<Self, crate::Error> {
+        if config.max_accept_rate == Some(0) {
+            return Err(crate::Error::config("max_accept_rate must not be zero"));
+        }
+
         let inner = std::net::TcpListener::bind(addr)?;
         inner.set_nonblocking(true)?;
+
+        let accept_rate = config.max_accept_rate.map(crate::TokenBucket::new);
+
         Ok(Acceptor {
             inner,
             session,
+            config,
+
+            accept_rate,
+
+            paused: false,
         })
     }
 
-    pub(crate) fn poll(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<crate::Reader>> {
-        match self.session.poll_accept_ready(cx) {
-            std::task::Poll::Ready(()) => (),
-            std::task::Poll::Pending => return std::task::Poll::Pending,
-        }
+    // The acceptor's access list, which the admin API can replace.
+    pub(crate) fn access_list(&self) -> &crate::AccessList {
+        &self.config.access_list
+    }
+
+    pub(crate) fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub(crate) fn poll(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<crate::Reader, crate::Error>> {
+        self.paused = false;
+
+        // Only rejected connections use up the budget, since an accepted one is returned right away.
+        let mut budget = crate::Budget::new(REJECT_BUDGET);
+
+        loop {
+            match budget.poll_consume(cx) {
+                std::task::Poll::Ready(()) => (),
+                std::task::Poll::Pending => return std::task::Poll::Pending,
+            }
+
+            match self.session.poll_accept_ready(cx, self.config.max_connections) {
+                std::task::Poll::Ready(()) => (),
+                std::task::Poll::Pending => {
+                    self.paused = true;
+                    return std::task::Poll::Pending;
+                },
+            }
 
-        match self.inner.accept() {
-            Ok((stream, _)) => {
-                let reader = self.session.clone().accept(stream)?;
-                std::task::Poll::Ready(Ok(reader))
-            },
+            let (stream, addr) = match self.inner.accept() {
+                Ok((stream, addr)) => (stream, addr),
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return std::task::Poll::Pending,
+                Err(err) => return std::task::Poll::Ready(Err(err.into())),
+            };
 
-            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => std::task::Poll::Pending,
+            // Rejected connections are closed as soon as they're dropped.
+            if !self.config.access_list.check(addr.ip()) {
+                warn!(peer_addr = addr; "rejecting connection: address is not allowed");
+                continue;
+            }
 
-            Err(err) => std::task::Poll::Ready(Err(err)),
+            if let Some(max_connections_per_ip) = self.config.max_connections_per_ip {
+                if self.session.connections_from(addr.ip()) >= max_connections_per_ip {
+                    warn!(peer_addr = addr; "rejecting connection: too many connections from its address");
+                    continue;
+                }
+            }
+
+            // Checked last, so that connections rejected for any other reason don't use up the rate for everyone else.
+            if !self.accept_rate.as_mut().map_or(true, crate::TokenBucket::try_consume) {
+                warn!(peer_addr = addr; "rejecting connection: too many connections per second");
+                continue;
+            }
+
+            let reader = self.session.clone().accept(stream, addr, self.config.max_packet_size)?;
+            return std::task::Poll::Ready(Ok(reader));
         }
     }
 }
@@ -40,3 +138,98 @@ impl std::os::unix::io::AsRawFd for Acceptor {
         self.inner.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    fn acceptor(config: super::AcceptorConfig) -> (super::Acceptor, std::rc::Rc<crate::Session>) {
+        let session = crate::test_util::session();
+        let acceptor = super::Acceptor::bind("127.0.0.1:0", session.clone(), config).unwrap();
+        (acceptor, session)
+    }
+
+    fn connect(acceptor: &super::Acceptor) -> std::net::TcpStream {
+        let stream = std::net::TcpStream::connect(acceptor.inner.local_addr().unwrap()).unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        stream
+    }
+
+    fn poll(acceptor: &mut super::Acceptor) -> std::task::Poll<Result<crate::Reader, crate::Error>> {
+        let waker = crate::test_util::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        acceptor.poll(&mut cx)
+    }
+
+    // Whether the connection was closed by the acceptor.
+    fn is_closed(mut stream: &std::net::TcpStream) -> bool {
+        let mut buf = [0_u8; 1];
+        match std::io::Read::read(&mut stream, &mut buf) {
+            Ok(read) => read == 0,
+            Err(err) => err.kind() == std::io::ErrorKind::ConnectionReset,
+        }
+    }
+
+    #[test]
+    fn max_connections() {
+        let (mut acceptor, session) = acceptor(super::AcceptorConfig {
+            max_connections: Some(1),
+            ..Default::default()
+        });
+
+        let _stream1 = connect(&acceptor);
+        let _stream2 = connect(&acceptor);
+        let reader1 = match poll(&mut acceptor) {
+            std::task::Poll::Ready(Ok(reader)) => reader,
+            _ => panic!("first connection was not accepted"),
+        };
+
+        // The second connection is left in the backlog until the first is disconnected.
+        assert!(poll(&mut acceptor).is_pending());
+        assert!(acceptor.paused());
+
+        session.disconnect(reader1.id());
+        assert!(matches!(poll(&mut acceptor), std::task::Poll::Ready(Ok(_))));
+        assert!(!acceptor.paused());
+    }
+
+    #[test]
+    fn max_connections_per_ip() {
+        let (mut acceptor, session) = acceptor(super::AcceptorConfig {
+            max_connections_per_ip: Some(1),
+            max_accept_rate: Some(2),
+            ..Default::default()
+        });
+
+        let _stream1 = connect(&acceptor);
+        let stream2 = connect(&acceptor);
+        let reader1 = match poll(&mut acceptor) {
+            std::task::Poll::Ready(Ok(reader)) => reader,
+            _ => panic!("first connection was not accepted"),
+        };
+
+        // The second connection is from the same address, so it's closed.
+        assert!(poll(&mut acceptor).is_pending());
+        assert!(!acceptor.paused());
+        assert!(is_closed(&stream2));
+
+        // Rejecting it didn't use up the accept rate, so there's still room for one more.
+        session.disconnect(reader1.id());
+        let _stream3 = connect(&acceptor);
+        assert!(matches!(poll(&mut acceptor), std::task::Poll::Ready(Ok(_))));
+    }
+
+    #[test]
+    fn max_accept_rate() {
+        let (mut acceptor, _session) = acceptor(super::AcceptorConfig {
+            max_connections_per_ip: None,
+            max_accept_rate: Some(1),
+            ..Default::default()
+        });
+
+        let _stream1 = connect(&acceptor);
+        let stream2 = connect(&acceptor);
+        assert!(matches!(poll(&mut acceptor), std::task::Poll::Ready(Ok(_))));
+
+        assert!(poll(&mut acceptor).is_pending());
+        assert!(is_closed(&stream2));
+    }
+}
@@ -0,0 +1,212 @@
+// The addresses that an Acceptor accepts connections from.
+//
+// This is a handle to a shared list, so that a clone of it kept outside the Acceptor can replace the list while the broker
+// is running.
+#[derive(Clone, Default)]
+pub struct AccessList {
+    inner: std::rc::Rc<std::cell::RefCell<AccessListInner>>,
+}
+
+#[derive(Default)]
+struct AccessListInner {
+    // If non-empty, only addresses in one of these ranges are allowed.
+    allow: Vec<Cidr>,
+
+    // Addresses in any of these ranges are rejected, even if they're also allowed.
+    deny: Vec<Cidr>,
+
+    // The number of connections that have been rejected.
+    rejected: u64,
+}
+
+// A range of IP addresses, such as "10.0.0.0/8" or "fd00::/8". A single address without a prefix length is a range of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: std::net::IpAddr,
+    prefix_len: u8,
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        let result: Self = Default::default();
+        result.reload(allow, deny);
+        result
+    }
+
+    // Replaces the list. Connections that have already been accepted are not affected.
+    pub fn reload(&self, allow: Vec<Cidr>, deny: Vec<Cidr>) {
+        let mut inner = self.inner.borrow_mut();
+        inner.allow = allow;
+        inner.deny = deny;
+    }
+
+    // The allowed and denied ranges.
+    pub fn get(&self) -> (Vec<Cidr>, Vec<Cidr>) {
+        let inner = self.inner.borrow();
+        (inner.allow.clone(), inner.deny.clone())
+    }
+
+    // The number of connections that have been rejected by this list.
+    pub fn rejected(&self) -> u64 {
+        self.inner.borrow().rejected
+    }
+
+    // Returns whether a connection from the given address should be accepted, and counts it if not.
+    pub(crate) fn check(&self, addr: std::net::IpAddr) -> bool {
+        let mut inner = self.inner.borrow_mut();
+
+        let allowed =
+            (inner.allow.is_empty() || inner.allow.iter().any(|cidr| cidr.contains(addr))) &&
+            !inner.deny.iter().any(|cidr| cidr.contains(addr));
+        if !allowed {
+            inner.rejected += 1;
+        }
+
+        allowed
+    }
+}
+
+impl Cidr {
+    pub fn new(addr: std::net::IpAddr, prefix_len: u8) -> Result<Self, crate::Error> {
+        let max_prefix_len = match addr {
+            std::net::IpAddr::V4(_) => 32,
+            std::net::IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(crate::Error::config(format!("prefix length {} is too long for {}", prefix_len, addr)));
+        }
+
+        Ok(Cidr {
+            addr,
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, addr: std::net::IpAddr) -> bool {
+        // A listener bound to an IPv6 address reports IPv4 peers as IPv4-mapped IPv6 addresses, so those are compared
+        // as the IPv4 addresses they are.
+        let addr = match addr {
+            std::net::IpAddr::V6(addr) => addr.to_ipv4_mapped().map_or(std::net::IpAddr::V6(addr), std::net::IpAddr::V4),
+            addr @ std::net::IpAddr::V4(_) => addr,
+        };
+
+        match (self.addr, addr) {
+            (std::net::IpAddr::V4(range), std::net::IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(range) & mask == u32::from(addr) & mask
+            },
+
+            (std::net::IpAddr::V6(range), std::net::IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(range) & mask == u128::from(addr) & mask
+            },
+
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(pos) => (&s[..pos], Some(&s[(pos + 1)..])),
+            None => (s, None),
+        };
+
+        let addr: std::net::IpAddr = addr.parse().map_err(|err| crate::Error::config(format!("invalid address in {:?}: {}", s, err)))?;
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|err| crate::Error::config(format!("invalid prefix length in {:?}: {}", s, err)))?,
+            None => match addr {
+                std::net::IpAddr::V4(_) => 32,
+                std::net::IpAddr::V6(_) => 128,
+            },
+        };
+
+        Cidr::new(addr, prefix_len)
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn cidr(s: &str) -> super::Cidr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> std::net::IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefix_len() {
+        assert!(super::Cidr::new(addr("10.0.0.0"), 0).is_ok());
+        assert!(super::Cidr::new(addr("10.0.0.0"), 32).is_ok());
+        assert!(super::Cidr::new(addr("10.0.0.0"), 33).is_err());
+        assert!(super::Cidr::new(addr("fd00::"), 128).is_ok());
+        assert!(super::Cidr::new(addr("fd00::"), 129).is_err());
+
+        // A single address is a range of one.
+        assert_eq!(cidr("10.0.0.1"), super::Cidr::new(addr("10.0.0.1"), 32).unwrap());
+        assert_eq!(cidr("fd00::1"), super::Cidr::new(addr("fd00::1"), 128).unwrap());
+        assert!("10.0.0.0/x".parse::<super::Cidr>().is_err());
+        assert!("10.0.0/8".parse::<super::Cidr>().is_err());
+    }
+
+    #[test]
+    fn contains_v4() {
+        let range = cidr("10.1.0.0/16");
+        assert!(range.contains(addr("10.1.0.0")));
+        assert!(range.contains(addr("10.1.255.255")));
+        assert!(!range.contains(addr("10.2.0.0")));
+        assert!(!range.contains(addr("fd00::")));
+
+        assert!(cidr("10.0.0.1/32").contains(addr("10.0.0.1")));
+        assert!(!cidr("10.0.0.1/32").contains(addr("10.0.0.2")));
+
+        assert!(cidr("0.0.0.0/0").contains(addr("255.255.255.255")));
+        assert!(!cidr("0.0.0.0/0").contains(addr("::1")));
+    }
+
+    #[test]
+    fn contains_v6() {
+        let range = cidr("fd00::/8");
+        assert!(range.contains(addr("fd12:3456::1")));
+        assert!(!range.contains(addr("fe80::1")));
+        assert!(!range.contains(addr("10.0.0.1")));
+
+        assert!(cidr("fd00::1/128").contains(addr("fd00::1")));
+        assert!(!cidr("fd00::1/128").contains(addr("fd00::2")));
+
+        assert!(cidr("::/0").contains(addr("fe80::1")));
+    }
+
+    #[test]
+    fn contains_ipv4_mapped() {
+        // Peers of a listener bound to an IPv6 address are compared as IPv4 addresses if they're IPv4-mapped.
+        assert!(cidr("10.0.0.0/8").contains(addr("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(addr("::ffff:11.1.2.3")));
+        assert!(!cidr("::ffff:0:0/96").contains(addr("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn reload() {
+        let access_list = super::AccessList::new(vec![], vec![cidr("10.0.0.0/8")]);
+        let handle = access_list.clone();
+        assert!(!access_list.check(addr("10.0.0.1")));
+        assert!(access_list.check(addr("192.168.0.1")));
+
+        handle.reload(vec![cidr("10.0.0.0/8")], vec![cidr("10.0.0.2")]);
+        assert!(access_list.check(addr("10.0.0.1")));
+        assert!(!access_list.check(addr("10.0.0.2")));
+        assert!(!access_list.check(addr("192.168.0.1")));
+        assert_eq!(handle.rejected(), 3);
+    }
+}
@@ -0,0 +1,480 @@
+// The most admin connections that are served at once, ie the number of slots in the listener's `Service`.
+pub(crate) const MAX_CONNECTIONS: usize = 4;
+
+// The largest request that is read.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+// How long a connection can go without sending a request before it's closed.
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+// A Unix socket for inspecting and controlling the broker, served by the `Runtime` on the same event loop as the broker.
+//
+// Requests and responses are JSON objects, one per line. Each request has a "command", and each response has "ok", along with
+// either the command's results or an "error":
+//
+//     {"command":"list_clients"}
+//     {"command":"disconnect","client_id":"sensor-1"}
+//     {"command":"list_retained"}
+//     {"command":"clear_retained","topic_filter":"sensors/#"}
+//     {"command":"publish","topic":"sensors/reset","payload":"now","qos":1,"retain":false}
+//     {"command":"buffer_pool"}
+//     {"command":"access_list"}
+//     {"command":"set_access_list","allow":["10.0.0.0/8"],"deny":["10.0.0.1"]}
+//
+// See the mqtt-async-ctl binary for a client.
+pub struct AdminListener {
+    inner: std::os::unix::net::UnixListener,
+    path: std::path::PathBuf,
+}
+
+pub(crate) struct AdminConnection {
+    inner: std::os::unix::net::UnixStream,
+    peer: Peer,
+
+    // The requests read so far that haven't been handled yet.
+    requests: Vec<u8>,
+
+    // The responses waiting to be written, and the number of bytes of them that have been written.
+    responses: Vec<u8>,
+    responses_written: usize,
+
+    // Set once the peer has shut down its write half, or sent a request that was too large. The connection is closed once
+    // the remaining responses have been written.
+    read_closed: bool,
+
+    // When the connection is closed if it hasn't sent another request.
+    deadline: std::time::Instant,
+}
+
+// The process on the other end of an admin connection, from its credentials, since a Unix socket's peer has no address.
+pub(crate) struct Peer(Option<nix::sys::socket::UnixCredentials>);
+
+impl AdminListener {
+    // The socket is only accessible by the user the broker runs as.
+    pub fn bind(path: impl AsRef<std::path::Path>) -> Result<Self, crate::Error> {
+        let path = path.as_ref().to_owned();
+
+        // A socket left behind by a broker that didn't exit cleanly would make bind fail, so it's removed. A socket that
+        // a running broker is still listening on, or anything else at the path, is left alone.
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if std::os::unix::fs::FileTypeExt::is_socket(&metadata.file_type()) =>
+                match std::os::unix::net::UnixStream::connect(&path) {
+                    Ok(_) => return Err(crate::Error::config(format!("{} is in use by another process", path.display()))),
+                    Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => std::fs::remove_file(&path)?,
+                    Err(err) => return Err(err.into()),
+                },
+            Ok(_) => return Err(crate::Error::config(format!("{} exists and is not a socket", path.display()))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        // The socket is created with the umask's permissions, so the umask is restricted around bind rather than
+        // the permissions being changed afterwards, when another user could already have connected.
+        let umask = nix::sys::stat::umask(nix::sys::stat::Mode::from_bits_truncate(0o177));
+        let inner = std::os::unix::net::UnixListener::bind(&path);
+        let _ = nix::sys::stat::umask(umask);
+        let inner = inner?;
+        inner.set_nonblocking(true)?;
+
+        Ok(AdminListener {
+            inner,
+            path,
+        })
+    }
+}
+
+impl crate::runtime::Listener for AdminListener {
+    type Connection = AdminConnection;
+
+    const NAME: &'static str = "admin";
+
+    fn accept(&self) -> Result<Option<AdminConnection>, crate::Error> {
+        let stream = match self.inner.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        stream.set_nonblocking(true)?;
+
+        // Only used for logging, so a connection whose credentials can't be read is still served.
+        let peer = Peer(nix::sys::socket::getsockopt(std::os::unix::io::AsRawFd::as_raw_fd(&stream), nix::sys::socket::sockopt::PeerCredentials).ok());
+
+        Ok(Some(AdminConnection {
+            inner: stream,
+            peer,
+            requests: vec![],
+            responses: vec![],
+            responses_written: 0,
+            read_closed: false,
+            deadline: std::time::Instant::now() + TIMEOUT,
+        }))
+    }
+}
+
+impl Drop for AdminListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl std::os::unix::io::AsRawFd for AdminListener {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AdminConnection {
+    // Handles requests and writes their responses. Returns `Ready(Ok(()))` once the peer has closed its write half and
+    // every response has been written, at which point the connection should be closed.
+    pub(crate) fn poll(&mut self, session: &crate::Session, access_list: &crate::AccessList) -> std::task::Poll<Result<(), crate::Error>> {
+        loop {
+            while self.responses_written < self.responses.len() {
+                match std::io::Write::write(&mut self.inner, &self.responses[self.responses_written..]) {
+                    Ok(0) => return std::task::Poll::Ready(Err(crate::Error::Transport(std::io::ErrorKind::WriteZero.into()))),
+                    Ok(written) => self.responses_written += written,
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return std::task::Poll::Pending,
+                    Err(err) => return std::task::Poll::Ready(Err(err.into())),
+                }
+            }
+            self.responses.clear();
+            self.responses_written = 0;
+
+            if let Some(end) = self.requests.iter().position(|&b| b == b'\n') {
+                let request: Vec<u8> = self.requests.drain(..=end).collect();
+                self.respond(handle(session, access_list, &request[..end]));
+                self.deadline = std::time::Instant::now() + TIMEOUT;
+                continue;
+            }
+
+            if self.read_closed {
+                return std::task::Poll::Ready(Ok(()));
+            }
+
+            if self.requests.len() > MAX_REQUEST_LEN {
+                self.respond(Err("request is too large".to_owned()));
+                self.requests = vec![];
+                self.read_closed = true;
+                continue;
+            }
+
+            let mut buf = [0_u8; 4096];
+            match std::io::Read::read(&mut self.inner, &mut buf) {
+                Ok(0) => self.read_closed = true,
+                Ok(read) => self.requests.extend_from_slice(&buf[..read]),
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return std::task::Poll::Pending,
+                Err(err) => return std::task::Poll::Ready(Err(err.into())),
+            }
+        }
+    }
+
+    fn respond(&mut self, result: Result<serde_json::Map<String, serde_json::Value>, String>) {
+        let response = match result {
+            Ok(mut response) => {
+                response.insert("ok".to_owned(), true.into());
+                response
+            },
+
+            Err(err) => {
+                let mut response = serde_json::Map::new();
+                response.insert("ok".to_owned(), false.into());
+                response.insert("error".to_owned(), err.into());
+                response
+            },
+        };
+
+        serde_json::to_writer(&mut self.responses, &response).expect("serializing a JSON value to a Vec never fails");
+        self.responses.push(b'\n');
+    }
+}
+
+impl crate::runtime::ListenerConnection for AdminConnection {
+    type Peer = Peer;
+
+    fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    fn deadline(&self) -> std::time::Instant {
+        self.deadline
+    }
+}
+
+impl std::os::unix::io::AsRawFd for AdminConnection {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(credentials) => write!(f, "pid {} uid {}", credentials.pid(), credentials.uid()),
+            None => f.write_str("unknown"),
+        }
+    }
+}
+
+// Handles a single request, and returns the fields of its response besides "ok".
+fn handle(session: &crate::Session, access_list: &crate::AccessList, request: &[u8]) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    let request: serde_json::Value = serde_json::from_slice(request).map_err(|err| format!("malformed request: {}", err))?;
+    let command = request.get("command").and_then(serde_json::Value::as_str).ok_or("request has no command")?;
+
+    let mut response = serde_json::Map::new();
+
+    match command {
+        "list_clients" => {
+            let clients = session.clients().into_iter().map(|client| serde_json::json!({
+                "connection_id": client.id.to_string(),
+                "client_id": client.client_id,
+                "peer_addr": client.peer_addr.to_string(),
+                "keep_alive": client.keep_alive.map(|keep_alive| keep_alive.as_secs()),
+                "queue_len": client.queue_len,
+                "queued_bytes": client.queued_bytes,
+                "dropped_publishes": client.dropped_publishes,
+                "subscriptions": client.subscriptions.into_iter().map(|(topic_filter, qos)| serde_json::json!({
+                    "topic_filter": topic_filter,
+                    "qos": qos_to_u8(qos),
+                })).collect::<Vec<_>>(),
+            })).collect::<Vec<_>>();
+            response.insert("clients".to_owned(), clients.into());
+        },
+
+        "disconnect" => {
+            let client_id = str_argument(&request, "client_id")?.ok_or("missing client_id")?;
+            if session.kick(client_id) == 0 {
+                return Err(format!("no client has client ID {:?}", client_id));
+            }
+        },
+
+        "list_retained" => {
+            let retained = session.retained().into_iter().map(|(topic_name, qos, payload)| serde_json::json!({
+                "topic": topic_name,
+                "qos": qos_to_u8(qos),
+                "payload_len": payload.len(),
+
+                // Payloads that aren't UTF-8 are left out.
+                "payload": std::str::from_utf8(&payload).ok(),
+            })).collect::<Vec<_>>();
+            response.insert("retained".to_owned(), retained.into());
+        },
+
+        "clear_retained" => {
+            let topic_filter = str_argument(&request, "topic_filter")?.unwrap_or("#");
+            let cleared = session.clear_retained(topic_filter);
+            response.insert("cleared".to_owned(), cleared.into());
+        },
+
+        "publish" => {
+            let topic_name = str_argument(&request, "topic")?.ok_or("missing topic")?;
+            let payload = str_argument(&request, "payload")?.unwrap_or_default();
+
+            let qos = match request.get("qos") {
+                None => mqtt3::proto::QoS::AtMostOnce,
+                Some(qos) => match qos.as_u64() {
+                    Some(0) => mqtt3::proto::QoS::AtMostOnce,
+                    Some(1) => mqtt3::proto::QoS::AtLeastOnce,
+                    Some(2) => mqtt3::proto::QoS::ExactlyOnce,
+                    _ => return Err("qos must be 0, 1 or 2".to_owned()),
+                },
+            };
+
+            let retain = match request.get("retain") {
+                None => false,
+                Some(retain) => retain.as_bool().ok_or("retain must be a boolean")?,
+            };
+
+            // The packet identifier is never sent anywhere, since each subscriber's delivery gets a packet identifier of its own.
+            let packet_identifier = mqtt3::proto::PacketIdentifier::new(1).expect("1 is a valid packet identifier");
+            let packet_identifier_dup_qos = match qos {
+                mqtt3::proto::QoS::AtMostOnce => mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce,
+                mqtt3::proto::QoS::AtLeastOnce => mqtt3::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, false),
+                mqtt3::proto::QoS::ExactlyOnce => mqtt3::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, false),
+            };
+
+            session.inject(mqtt3::proto::Publish {
+                packet_identifier_dup_qos,
+                retain,
+                topic_name: topic_name.to_owned(),
+                payload: bytes::Bytes::copy_from_slice(payload.as_bytes()),
+            }).map_err(|err| err.to_string())?;
+        },
+
+        "buffer_pool" => {
+            let stats = session.gauges().buffer_pool;
+            response.insert("free".to_owned(), stats.free.into());
+            response.insert("reclaiming".to_owned(), stats.reclaiming.into());
+            response.insert("waiting".to_owned(), stats.waiting.into());
+        },
+
+        "access_list" => {
+            let (allow, deny) = access_list.get();
+            response.insert("allow".to_owned(), allow.iter().map(ToString::to_string).collect::<Vec<_>>().into());
+            response.insert("deny".to_owned(), deny.iter().map(ToString::to_string).collect::<Vec<_>>().into());
+            response.insert("rejected".to_owned(), access_list.rejected().into());
+        },
+
+        // Replaces the whole list, so a missing "allow" or "deny" is empty. Connected clients are not affected.
+        "set_access_list" => {
+            let allow = cidrs_argument(&request, "allow")?;
+            let deny = cidrs_argument(&request, "deny")?;
+            info!(allow = allow.len(), deny = deny.len(); "replacing the access list through the admin API");
+            access_list.reload(allow, deny);
+        },
+
+        command => return Err(format!("unknown command {:?}", command)),
+    }
+
+    Ok(response)
+}
+
+// Returns the given string argument of the request, if it has it.
+fn str_argument<'a>(request: &'a serde_json::Value, name: &str) -> Result<Option<&'a str>, String> {
+    match request.get(name) {
+        Some(value) => value.as_str().map(Some).ok_or_else(|| format!("{} must be a string", name)),
+        None => Ok(None),
+    }
+}
+
+// Returns the given argument of the request as a list of address ranges, or an empty list if it doesn't have it.
+fn cidrs_argument(request: &serde_json::Value, name: &str) -> Result<Vec<crate::Cidr>, String> {
+    let values = match request.get(name) {
+        Some(values) => values.as_array().ok_or_else(|| format!("{} must be an array", name))?,
+        None => return Ok(vec![]),
+    };
+
+    values.iter()
+        .map(|value| {
+            let value = value.as_str().ok_or_else(|| format!("{} must be an array of strings", name))?;
+            value.parse().map_err(|err: crate::Error| err.to_string())
+        })
+        .collect()
+}
+
+fn qos_to_u8(qos: mqtt3::proto::QoS) -> u8 {
+    match qos {
+        mqtt3::proto::QoS::AtMostOnce => 0,
+        mqtt3::proto::QoS::AtLeastOnce => 1,
+        mqtt3::proto::QoS::ExactlyOnce => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn handle(session: &crate::Session, access_list: &crate::AccessList, request: &str) -> serde_json::Value {
+        super::handle(session, access_list, request.as_bytes()).map_or_else(serde_json::Value::from, serde_json::Value::from)
+    }
+
+    #[test]
+    fn set_access_list() {
+        let session = crate::test_util::session();
+        let access_list: crate::AccessList = Default::default();
+
+        let response = handle(&session, &access_list, r#"{"command":"set_access_list","allow":["10.0.0.0/8"],"deny":["10.0.0.1"]}"#);
+        assert_eq!(response, serde_json::json!({}));
+        assert!(access_list.check("10.0.0.2".parse().unwrap()));
+        assert!(!access_list.check("10.0.0.1".parse().unwrap()));
+        assert!(!access_list.check("192.168.0.1".parse().unwrap()));
+
+        let response = handle(&session, &access_list, r#"{"command":"access_list"}"#);
+        assert_eq!(response, serde_json::json!({ "allow": ["10.0.0.0/8"], "deny": ["10.0.0.1/32"], "rejected": 2 }));
+
+        // An invalid range leaves the list as it was.
+        let response = handle(&session, &access_list, r#"{"command":"set_access_list","allow":["10.0.0.0/33"]}"#);
+        assert!(response.is_string());
+        assert!(!access_list.check("192.168.0.1".parse().unwrap()));
+
+        // Leaving out both replaces the list with one that allows everything.
+        handle(&session, &access_list, r#"{"command":"set_access_list"}"#);
+        assert!(access_list.check("192.168.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn disconnect() {
+        let session = crate::test_util::session();
+        let access_list: crate::AccessList = Default::default();
+
+        let mut client = crate::test_util::TestClient::new(&session);
+        client.send(&crate::test_util::connect(b"a"));
+        client.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        let response = handle(&session, &access_list, r#"{"command":"disconnect","client_id":"b"}"#);
+        assert_eq!(response, serde_json::json!(r#"no client has client ID "b""#));
+
+        let response = handle(&session, &access_list, r#"{"command":"disconnect","client_id":"a"}"#);
+        assert_eq!(response, serde_json::json!({}));
+
+        // The client is disconnected the next time it's written to.
+        let waker = crate::test_util::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        assert!(matches!(session.poll_write(&mut cx, client.reader.id()), std::task::Poll::Ready(Err(crate::Error::Policy(_)))));
+    }
+
+    #[test]
+    fn publish() {
+        let session = crate::test_util::session();
+        let access_list: crate::AccessList = Default::default();
+
+        let mut subscriber = crate::test_util::TestClient::new(&session);
+        subscriber.send(&crate::test_util::connect(b"s"));
+        subscriber.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        subscriber.send(&[0x82, 8, 0, 1, 0, 3, b't', b'/', b'x', 1]);
+        subscriber.expect(&session, &[0x90, 3, 0, 1, 1]);
+
+        let response = handle(&session, &access_list, r#"{"command":"publish","topic":"t/x","payload":"hi","qos":1}"#);
+        assert_eq!(response, serde_json::json!({}));
+        subscriber.expect(&session, &[0x32, 9, 0, 3, b't', b'/', b'x', 0, 1, b'h', b'i']);
+
+        let response = handle(&session, &access_list, r#"{"command":"publish","topic":"t/x","payload":5}"#);
+        assert_eq!(response, serde_json::json!("payload must be a string"));
+
+        let response = handle(&session, &access_list, r#"{"command":"publish","topic":"t/+","payload":"hi"}"#);
+        assert!(response.is_string());
+    }
+
+    #[test]
+    fn publish_retained_is_limited() {
+        let config = crate::SessionConfig {
+            max_retained_messages: 1,
+            sys_interval: None,
+            ..Default::default()
+        };
+        let session = crate::Session::new(crate::BufferPool::new(Default::default()).unwrap(), config).unwrap();
+        let access_list: crate::AccessList = Default::default();
+
+        let response = handle(&session, &access_list, r#"{"command":"publish","topic":"t/a","payload":"1","retain":true}"#);
+        assert_eq!(response, serde_json::json!({}));
+
+        let response = handle(&session, &access_list, r#"{"command":"publish","topic":"t/b","payload":"2","retain":true}"#);
+        assert_eq!(response, serde_json::json!("resource exhausted: too many retained messages"));
+
+        // Replacing the retained message of a topic doesn't add to them.
+        let response = handle(&session, &access_list, r#"{"command":"publish","topic":"t/a","payload":"3","retain":true}"#);
+        assert_eq!(response, serde_json::json!({}));
+
+        let response = handle(&session, &access_list, r#"{"command":"list_retained"}"#);
+        assert_eq!(response, serde_json::json!({ "retained": [{ "topic": "t/a", "qos": 0, "payload_len": 1, "payload": "3" }] }));
+    }
+
+    #[test]
+    fn clear_retained() {
+        let session = crate::test_util::session();
+        let access_list: crate::AccessList = Default::default();
+
+        for topic in ["t/a", "t/b", "u/c"] {
+            let request = serde_json::json!({ "command": "publish", "topic": topic, "payload": "p", "retain": true });
+            let response = handle(&session, &access_list, &request.to_string());
+            assert_eq!(response, serde_json::json!({}));
+        }
+
+        let response = handle(&session, &access_list, r#"{"command":"clear_retained","topic_filter":"t/#"}"#);
+        assert_eq!(response, serde_json::json!({ "cleared": 2 }));
+
+        let response = handle(&session, &access_list, r#"{"command":"list_retained"}"#);
+        assert_eq!(response, serde_json::json!({ "retained": [{ "topic": "u/c", "qos": 0, "payload_len": 1, "payload": "p" }] }));
+
+        // Without a topic filter, everything is cleared.
+        let response = handle(&session, &access_list, r#"{"command":"clear_retained"}"#);
+        assert_eq!(response, serde_json::json!({ "cleared": 1 }));
+    }
+}
@@ -0,0 +1,143 @@
+#![deny(rust_2018_idioms, warnings)]
+#![deny(clippy::all, clippy::pedantic)]
+#![allow(
+    clippy::uninlined_format_args,
+)]
+
+// A client for the broker's admin API. See `mqtt_async::AdminListener`.
+
+const USAGE: &str = "\
+usage: mqtt-async-ctl [--socket <path>] <command>
+
+commands:
+    clients                                      list the connected clients
+    disconnect <client-id>                       disconnect the clients with the client ID
+    retained                                     list the retained messages
+    clear-retained [<topic-filter>]              remove the retained messages matching the topic filter, or all of them
+    publish [--qos <qos>] [--retain] <topic> <payload>
+                                                 publish a message as if a client had
+    buffer-pool                                  show the state of the buffer pool
+    access-list                                  show the access list
+    set-access-list [--allow <cidr>]... [--deny <cidr>]...
+                                                 replace the access list
+
+The socket defaults to $MQTT_ASYNC_ADMIN_SOCKET.";
+
+fn main() {
+    let mut args = std::env::args().skip(1).peekable();
+
+    let socket_path = match args.peek().map(String::as_str) {
+        Some("--socket") => {
+            let _ = args.next();
+            args.next().map(std::path::PathBuf::from)
+        },
+        _ => std::env::var_os("MQTT_ASYNC_ADMIN_SOCKET").map(std::path::PathBuf::from),
+    };
+    let socket_path = socket_path.unwrap_or_else(|| usage());
+
+    let request = match parse_command(args) {
+        Some(request) => request,
+        None => usage(),
+    };
+
+    let response = match send(&socket_path, &request) {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!("mqtt-async-ctl: {}: {}", socket_path.display(), err);
+            std::process::exit(1);
+        },
+    };
+
+    println!("{}", serde_json::to_string_pretty(&response).expect("serializing a JSON value to a String never fails"));
+
+    if response.get("ok").and_then(serde_json::Value::as_bool) != Some(true) {
+        std::process::exit(1);
+    }
+}
+
+// Builds the request for the command in the given arguments, or returns None if they aren't a valid command.
+fn parse_command(mut args: impl Iterator<Item = String>) -> Option<serde_json::Value> {
+    let command = args.next()?;
+    let mut args: Vec<String> = args.collect();
+
+    let request = match (command.as_str(), &mut args[..]) {
+        ("clients", []) => serde_json::json!({ "command": "list_clients" }),
+
+        ("disconnect", [client_id]) => serde_json::json!({ "command": "disconnect", "client_id": client_id }),
+
+        ("retained", []) => serde_json::json!({ "command": "list_retained" }),
+
+        ("clear-retained", []) => serde_json::json!({ "command": "clear_retained" }),
+        ("clear-retained", [topic_filter]) => serde_json::json!({ "command": "clear_retained", "topic_filter": topic_filter }),
+
+        ("publish", _) => {
+            let mut qos = 0;
+            let mut retain = false;
+            let mut positional = vec![];
+
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--qos" => qos = args.next()?.parse().ok().filter(|&qos: &u8| qos <= 2)?,
+                    "--retain" => retain = true,
+                    _ => positional.push(arg),
+                }
+            }
+
+            match &positional[..] {
+                [topic, payload] => serde_json::json!({
+                    "command": "publish",
+                    "topic": topic,
+                    "payload": payload,
+                    "qos": qos,
+                    "retain": retain,
+                }),
+                _ => return None,
+            }
+        },
+
+        ("buffer-pool", []) => serde_json::json!({ "command": "buffer_pool" }),
+
+        ("access-list", []) => serde_json::json!({ "command": "access_list" }),
+
+        ("set-access-list", _) => {
+            let mut allow = vec![];
+            let mut deny = vec![];
+
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--allow" => allow.push(args.next()?),
+                    "--deny" => deny.push(args.next()?),
+                    _ => return None,
+                }
+            }
+
+            serde_json::json!({ "command": "set_access_list", "allow": allow, "deny": deny })
+        },
+
+        _ => return None,
+    };
+
+    Some(request)
+}
+
+// Sends the request to the broker and returns its response.
+fn send(socket_path: &std::path::Path, request: &serde_json::Value) -> std::io::Result<serde_json::Value> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket_path)?;
+
+    let mut request = serde_json::to_vec(request)?;
+    request.push(b'\n');
+    std::io::Write::write_all(&mut stream, &request)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut response = String::new();
+    std::io::BufRead::read_line(&mut std::io::BufReader::new(stream), &mut response)?;
+    let response = serde_json::from_str(&response)?;
+    Ok(response)
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
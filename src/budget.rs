@@ -0,0 +1,45 @@
+// Bounds the amount of work a single source does each time it's polled, so that one busy connection can't starve the others.
+//
+// Once the budget is exhausted, the source's waker is woken so that it gets requeued behind every other ready source,
+// and `poll_consume` returns `Pending` so that the source yields.
+pub(crate) struct Budget {
+    remaining: usize,
+}
+
+impl Budget {
+    pub(crate) fn new(remaining: usize) -> Self {
+        Budget {
+            remaining,
+        }
+    }
+
+    pub(crate) fn poll_consume(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<()> {
+        if self.remaining == 0 {
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        }
+        else {
+            self.remaining -= 1;
+            std::task::Poll::Ready(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn exhausted_budget_requeues_once() {
+        let wake_counter = std::sync::Arc::new(crate::test_util::WakeCounter(std::sync::atomic::AtomicUsize::new(0)));
+        let waker = std::task::Waker::from(wake_counter.clone());
+        let mut cx = std::task::Context::from_waker(&waker);
+
+        let mut budget = super::Budget::new(2);
+        assert!(budget.poll_consume(&mut cx).is_ready());
+        assert!(budget.poll_consume(&mut cx).is_ready());
+        assert_eq!(wake_counter.0.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+        // The source yields as soon as it's Pending, having been woken exactly once to be polled again.
+        assert!(budget.poll_consume(&mut cx).is_pending());
+        assert_eq!(wake_counter.0.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+}
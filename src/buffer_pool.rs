@@ -2,59 +2,224 @@ pub struct BufferPool {
     inner: std::cell::RefCell<BufferPoolInner>,
 }
 
+pub struct BufferPoolConfig {
+    // The number of buffers in the pool.
+    pub capacity: usize,
+
+    // The initial capacity of each buffer. Must be at least the smallest amount that a Reader reads into a buffer at once.
+    pub buffer_capacity: usize,
+
+    // The capacity that a pooled buffer is allowed to grow to. Buffers that grew past this are replaced with new ones
+    // when they're put back into the pool.
+    pub max_buffer_capacity: usize,
+}
+
+// A snapshot of the state of a BufferPool.
+pub(crate) struct BufferPoolStats {
+    // The number of buffers in the pool.
+    pub(crate) free: usize,
+
+    // The number of buffers that were put back but are still shared.
+    pub(crate) reclaiming: usize,
+
+    // The number of connections waiting for a buffer.
+    pub(crate) waiting: usize,
+}
+
 struct BufferPoolInner {
+    config: BufferPoolConfig,
+
     pool: std::collections::VecDeque<bytes::BytesMut>,
-    wakers: std::collections::VecDeque<std::task::Waker>,
+
+    // Buffers that were put back while their allocation is still shared with `Bytes` split off of them,
+    // such as the payloads of decoded packets. Each such `Bytes` holds a reference to the allocation, so the buffer can only be
+    // reused once the last of them has been dropped.
+    reclaiming: std::collections::VecDeque<bytes::BytesMut>,
+
+    // The connections waiting for a buffer, in the order they started waiting. Each connection is queued at most once,
+    // so that every buffer that's put back wakes a different connection.
+    wakers: std::collections::VecDeque<(crate::ConnectionId, std::task::Waker)>,
+    waiting: std::collections::HashSet<crate::ConnectionId>,
+}
+
+impl Default for BufferPoolConfig {
+    fn default() -> Self {
+        BufferPoolConfig {
+            capacity: 128,
+            buffer_capacity: 8192,
+            max_buffer_capacity: 65536,
+        }
+    }
 }
 
-const CAPACITY: usize = 128;
+impl BufferPoolConfig {
+    fn validate(&self) -> Result<(), crate::Error> {
+        if self.capacity == 0 {
+            return Err(crate::Error::config("capacity must not be zero"));
+        }
+
+        if self.buffer_capacity < crate::reader::MIN_READ_LEN {
+            return Err(crate::Error::config(format!("buffer_capacity must be at least {}", crate::reader::MIN_READ_LEN)));
+        }
+
+        if self.max_buffer_capacity < self.buffer_capacity {
+            return Err(crate::Error::config("max_buffer_capacity must not be less than buffer_capacity"));
+        }
+
+        Ok(())
+    }
+}
 
 impl BufferPool {
-    pub fn new() -> std::rc::Rc<Self> {
-        std::rc::Rc::new(BufferPool {
+    pub fn new(config: BufferPoolConfig) -> Result<std::rc::Rc<Self>, crate::Error> {
+        config.validate()?;
+
+        Ok(std::rc::Rc::new(BufferPool {
             inner: std::cell::RefCell::new(BufferPoolInner {
                 pool: {
-                    let mut pool = std::collections::VecDeque::with_capacity(CAPACITY);
-                    for _ in 0..CAPACITY {
-                        pool.push_back(bytes::BytesMut::with_capacity(8192));
+                    let mut pool = std::collections::VecDeque::with_capacity(config.capacity);
+                    for _ in 0..config.capacity {
+                        pool.push_back(bytes::BytesMut::with_capacity(config.buffer_capacity));
                     }
                     pool
                 },
+                config,
+                reclaiming: Default::default(),
                 wakers: Default::default(),
+                waiting: Default::default(),
             }),
-        })
+        }))
     }
 
-    pub(crate) fn poll_take(&self, cx: &mut std::task::Context<'_>) -> std::task::Poll<bytes::BytesMut> {
+    pub(crate) fn poll_take(&self, cx: &mut std::task::Context<'_>, id: crate::ConnectionId) -> std::task::Poll<bytes::BytesMut> {
         let mut inner = self.inner.borrow_mut();
-        inner.poll_take(cx)
+        inner.poll_take(cx, id)
+    }
+
+    // Like `poll_take`, but doesn't register to be woken up if the pool is empty.
+    pub(crate) fn try_take(&self) -> Option<bytes::BytesMut> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.pool.is_empty() {
+            inner.reclaim();
+        }
+        inner.pool.pop_front()
     }
 
     pub(crate) fn put_back(&self, buf: bytes::BytesMut) {
         let mut inner = self.inner.borrow_mut();
-        inner.put_back(buf)
+        inner.put_back(buf);
+    }
+
+    // Stops waking the given connection when a buffer becomes available, eg because it's been disconnected.
+    pub(crate) fn forget(&self, id: crate::ConnectionId) {
+        let mut inner = self.inner.borrow_mut();
+        inner.forget(id);
+    }
+
+    pub(crate) fn buffer_capacity(&self) -> usize {
+        let inner = self.inner.borrow();
+        inner.config.buffer_capacity
+    }
+
+    pub(crate) fn max_buffer_capacity(&self) -> usize {
+        let inner = self.inner.borrow();
+        inner.config.max_buffer_capacity
+    }
+
+    pub(crate) fn stats(&self) -> BufferPoolStats {
+        let inner = self.inner.borrow();
+        BufferPoolStats {
+            free: inner.pool.len(),
+            reclaiming: inner.reclaiming.len(),
+            waiting: inner.wakers.len(),
+        }
+    }
+
+    // Moves buffers whose allocations are no longer shared back into the pool.
+    //
+    // This should be called after dropping packets that were decoded from pooled buffers.
+    pub(crate) fn reclaim(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.reclaim();
     }
 }
 
 impl BufferPoolInner {
-    fn poll_take(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<bytes::BytesMut> {
+    fn poll_take(&mut self, cx: &mut std::task::Context<'_>, id: crate::ConnectionId) -> std::task::Poll<bytes::BytesMut> {
+        if self.pool.is_empty() {
+            self.reclaim();
+        }
+
         if let Some(buf) = self.pool.pop_front() {
-            eprintln!("BufferPool::poll_take Ok");
+            trace!("BufferPool::poll_take Ok");
+            self.forget(id);
             std::task::Poll::Ready(buf)
         }
         else {
-            eprintln!("BufferPool::poll_take Pending");
-            self.wakers.push_back(cx.waker().clone());
+            trace!("BufferPool::poll_take Pending");
+            if self.waiting.insert(id) {
+                self.wakers.push_back((id, cx.waker().clone()));
+            }
             std::task::Poll::Pending
         }
     }
 
+    fn forget(&mut self, id: crate::ConnectionId) {
+        if self.waiting.remove(&id) {
+            self.wakers.retain(|(waiting_id, _)| *waiting_id != id);
+        }
+    }
+
     fn put_back(&mut self, mut buf: bytes::BytesMut) {
-        eprintln!("BufferPool::put_back");
+        trace!("BufferPool::put_back");
         buf.clear();
+        if buf.try_reclaim(self.config.buffer_capacity) {
+            self.push(buf);
+        }
+        else {
+            self.reclaiming.push_back(buf);
+        }
+    }
+
+    fn reclaim(&mut self) {
+        let mut i = 0;
+        while i < self.reclaiming.len() {
+            if self.reclaiming[i].try_reclaim(self.config.buffer_capacity) {
+                let buf = self.reclaiming.swap_remove_back(i).expect("index is in bounds");
+                trace!("BufferPool::reclaim");
+                self.push(buf);
+            }
+            else {
+                i += 1;
+            }
+        }
+    }
+
+    fn push(&mut self, mut buf: bytes::BytesMut) {
+        if buf.capacity() > self.config.max_buffer_capacity {
+            buf = bytes::BytesMut::with_capacity(self.config.buffer_capacity);
+        }
+
         self.pool.push_back(buf);
-        if let Some(waker) = self.wakers.pop_front() {
+        if let Some((id, waker)) = self.wakers.pop_front() {
+            self.waiting.remove(&id);
             waker.wake();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn invalid_config() {
+        for config in [
+            super::BufferPoolConfig { capacity: 0, ..Default::default() },
+            super::BufferPoolConfig { buffer_capacity: crate::reader::MIN_READ_LEN - 1, ..Default::default() },
+            super::BufferPoolConfig { buffer_capacity: 1024, max_buffer_capacity: 512, ..Default::default() },
+        ] {
+            assert!(matches!(super::BufferPool::new(config), Err(crate::Error::Config(_))));
+        }
+
+        assert!(super::BufferPool::new(super::BufferPoolConfig { buffer_capacity: crate::reader::MIN_READ_LEN, ..Default::default() }).is_ok());
+    }
+}
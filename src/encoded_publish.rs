@@ -0,0 +1,226 @@
+// A PUBLISH packet that is encoded once and then shared by the queues of every client it's routed to.
+//
+// The fixed header and packet identifier depend on the QoS, DUP and RETAIN flags and the packet identifier of each delivery,
+// so they're encoded separately per client as a small `Header`. The topic name and payload are identical for every delivery,
+// so they're encoded once and shared.
+pub(crate) struct EncodedPublish {
+    // The topic name, including its two-byte length prefix.
+    topic_name: bytes::Bytes,
+    payload: bytes::Bytes,
+}
+
+// The per-delivery parts of an `EncodedPublish`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Header {
+    // The packet type and flags byte followed by the remaining length.
+    fixed_header: [u8; 5],
+    fixed_header_len: usize,
+
+    packet_identifier: [u8; 2],
+    packet_identifier_len: usize,
+}
+
+// The largest remaining length that can be encoded in the fixed header.
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+impl EncodedPublish {
+    pub(crate) fn new(topic_name: &str, payload: bytes::Bytes) -> Result<Self, crate::Error> {
+        let topic_name_len: u16 = std::convert::TryInto::try_into(topic_name.len())
+            .map_err(|_| crate::Error::protocol("topic name is too long"))?;
+
+        let mut encoded_topic_name = bytes::BytesMut::with_capacity(std::mem::size_of::<u16>() + topic_name.len());
+        bytes::BufMut::put_u16(&mut encoded_topic_name, topic_name_len);
+        bytes::BufMut::put_slice(&mut encoded_topic_name, topic_name.as_bytes());
+
+        let result = EncodedPublish {
+            topic_name: encoded_topic_name.freeze(),
+            payload,
+        };
+
+        // The QoS 1 and 2 variants are the largest since they include the packet identifier.
+        if result.remaining_length(mqtt3::proto::QoS::ExactlyOnce) > MAX_REMAINING_LENGTH {
+            return Err(crate::Error::protocol("publish is too large"));
+        }
+
+        Ok(result)
+    }
+
+    pub(crate) fn header(&self, packet_identifier_dup_qos: mqtt3::proto::PacketIdentifierDupQoS, retain: bool) -> Header {
+        let (qos, packet_identifier, dup) = match packet_identifier_dup_qos {
+            mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce => (mqtt3::proto::QoS::AtMostOnce, None, false),
+            mqtt3::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, dup) => (mqtt3::proto::QoS::AtLeastOnce, Some(packet_identifier), dup),
+            mqtt3::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, dup) => (mqtt3::proto::QoS::ExactlyOnce, Some(packet_identifier), dup),
+        };
+
+        let mut fixed_header = [0_u8; 5];
+
+        fixed_header[0] = 0x30;
+        if dup {
+            fixed_header[0] |= 0x08;
+        }
+        fixed_header[0] |= match qos {
+            mqtt3::proto::QoS::AtMostOnce => 0x00,
+            mqtt3::proto::QoS::AtLeastOnce => 0x02,
+            mqtt3::proto::QoS::ExactlyOnce => 0x04,
+        };
+        if retain {
+            fixed_header[0] |= 0x01;
+        }
+
+        let mut fixed_header_len = 1;
+        let mut remaining_length = self.remaining_length(qos);
+        loop {
+            #[allow(clippy::cast_possible_truncation)]
+            let mut digit = (remaining_length % 0x80) as u8;
+            remaining_length /= 0x80;
+            if remaining_length > 0 {
+                digit |= 0x80;
+            }
+            fixed_header[fixed_header_len] = digit;
+            fixed_header_len += 1;
+            if remaining_length == 0 {
+                break;
+            }
+        }
+
+        let (packet_identifier, packet_identifier_len) = match packet_identifier {
+            Some(packet_identifier) => (packet_identifier.get().to_be_bytes(), 2),
+            None => ([0; 2], 0),
+        };
+
+        Header {
+            fixed_header,
+            fixed_header_len,
+            packet_identifier,
+            packet_identifier_len,
+        }
+    }
+
+    // The encoded packet, as the chunks to be written one after the other.
+    pub(crate) fn chunks<'a>(&'a self, header: &'a Header) -> [&'a [u8]; 4] {
+        [
+            &header.fixed_header[..header.fixed_header_len],
+            &self.topic_name,
+            &header.packet_identifier[..header.packet_identifier_len],
+            &self.payload,
+        ]
+    }
+
+    pub(crate) fn payload(&self) -> &bytes::Bytes {
+        &self.payload
+    }
+
+    // The length of the encoded packet.
+    pub(crate) fn len(&self, header: &Header) -> usize {
+        header.fixed_header_len + self.topic_name.len() + header.packet_identifier_len + self.payload.len()
+    }
+
+    fn remaining_length(&self, qos: mqtt3::proto::QoS) -> usize {
+        let packet_identifier_len = match qos {
+            mqtt3::proto::QoS::AtMostOnce => 0,
+            mqtt3::proto::QoS::AtLeastOnce | mqtt3::proto::QoS::ExactlyOnce => std::mem::size_of::<u16>(),
+        };
+        self.topic_name.len() + packet_identifier_len + self.payload.len()
+    }
+}
+
+impl Header {
+    pub(crate) fn qos(&self) -> mqtt3::proto::QoS {
+        match self.fixed_header[0] & 0x06 {
+            0x00 => mqtt3::proto::QoS::AtMostOnce,
+            0x02 => mqtt3::proto::QoS::AtLeastOnce,
+            _ => mqtt3::proto::QoS::ExactlyOnce,
+        }
+    }
+}
+
+impl std::fmt::Debug for EncodedPublish {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncodedPublish")
+            .field("topic_name", &String::from_utf8_lossy(&self.topic_name[std::mem::size_of::<u16>()..]))
+            .field("payload_len", &self.payload.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn encode(encoded_publish: &super::EncodedPublish, header: &super::Header) -> Vec<u8> {
+        encoded_publish.chunks(header).concat()
+    }
+
+    fn packet_identifier(packet_identifier: u16) -> mqtt3::proto::PacketIdentifier {
+        mqtt3::proto::PacketIdentifier::new(packet_identifier).unwrap()
+    }
+
+    #[test]
+    fn at_most_once() {
+        let encoded_publish = super::EncodedPublish::new("a/b", bytes::Bytes::from_static(b"hi")).unwrap();
+        let header = encoded_publish.header(mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce, false);
+
+        let expected = [0x30, 7, 0, 3, b'a', b'/', b'b', b'h', b'i'];
+        assert_eq!(encode(&encoded_publish, &header), expected);
+        assert_eq!(encoded_publish.len(&header), expected.len());
+        assert_eq!(header.qos(), mqtt3::proto::QoS::AtMostOnce);
+    }
+
+    #[test]
+    fn at_least_once_dup() {
+        let encoded_publish = super::EncodedPublish::new("a/b", bytes::Bytes::from_static(b"hi")).unwrap();
+        let header = encoded_publish.header(mqtt3::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier(0x0102), true), false);
+
+        let expected = [0x3a, 9, 0, 3, b'a', b'/', b'b', 0x01, 0x02, b'h', b'i'];
+        assert_eq!(encode(&encoded_publish, &header), expected);
+        assert_eq!(encoded_publish.len(&header), expected.len());
+        assert_eq!(header.qos(), mqtt3::proto::QoS::AtLeastOnce);
+    }
+
+    #[test]
+    fn exactly_once_retain() {
+        let encoded_publish = super::EncodedPublish::new("t", bytes::Bytes::new()).unwrap();
+        let header = encoded_publish.header(mqtt3::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier(7), false), true);
+
+        let expected = [0x35, 5, 0, 1, b't', 0, 7];
+        assert_eq!(encode(&encoded_publish, &header), expected);
+        assert_eq!(encoded_publish.len(&header), expected.len());
+        assert_eq!(header.qos(), mqtt3::proto::QoS::ExactlyOnce);
+    }
+
+    #[test]
+    fn headers_share_encoding() {
+        // One encoding serves deliveries with different QoS and packet identifiers.
+        let encoded_publish = super::EncodedPublish::new("t", bytes::Bytes::from_static(b"p")).unwrap();
+        let header1 = encoded_publish.header(mqtt3::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier(1), false), false);
+        let header2 = encoded_publish.header(mqtt3::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier(2), false), false);
+        let header0 = encoded_publish.header(mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce, false);
+
+        assert_eq!(encode(&encoded_publish, &header1), [0x32, 6, 0, 1, b't', 0, 1, b'p']);
+        assert_eq!(encode(&encoded_publish, &header2), [0x32, 6, 0, 1, b't', 0, 2, b'p']);
+        assert_eq!(encode(&encoded_publish, &header0), [0x30, 4, 0, 1, b't', b'p']);
+    }
+
+    #[test]
+    fn multi_byte_remaining_length() {
+        let payload = bytes::Bytes::from(vec![b'x'; 200]);
+        let encoded_publish = super::EncodedPublish::new("a", payload).unwrap();
+
+        // 3 + 200 = 203 = 0x4b + 0x01 * 128
+        let header = encoded_publish.header(mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce, false);
+        let encoded = encode(&encoded_publish, &header);
+        assert_eq!(&encoded[..5], [0x30, 0xcb, 0x01, 0, 1]);
+        assert_eq!(encoded.len(), 3 + 203);
+        assert_eq!(encoded_publish.len(&header), encoded.len());
+
+        // 3 + 2 + 200 = 205
+        let header = encoded_publish.header(mqtt3::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier(1), false), false);
+        let encoded = encode(&encoded_publish, &header);
+        assert_eq!(&encoded[..3], [0x34, 0xcd, 0x01]);
+        assert_eq!(encoded_publish.len(&header), encoded.len());
+    }
+
+    #[test]
+    fn too_long_topic_name() {
+        let topic_name = "a".repeat(usize::from(u16::MAX) + 1);
+        assert!(super::EncodedPublish::new(&topic_name, bytes::Bytes::new()).is_err());
+    }
+}
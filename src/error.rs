@@ -0,0 +1,83 @@
+// The errors of the broker and of its connections.
+//
+// An error from a connection only ever closes that connection. The runtime only stops for transport errors of its own,
+// such as failing to wait on epoll.
+#[derive(Debug)]
+pub enum Error {
+    // An I/O error on a socket or other file descriptor.
+    Transport(std::io::Error),
+
+    // A client broke the MQTT protocol, such as by sending a malformed packet or packets in the wrong order.
+    Protocol(String),
+
+    // The broker ran out of room for a client, such as when the client's queue overflowed.
+    ResourceExhausted(String),
+
+    // A client was disconnected or refused by one of the broker's policies, such as a rate limit or a timeout.
+    Policy(String),
+
+    // The broker was configured with an invalid value.
+    Config(String),
+
+    // The broker failed at something that should never fail, such as encoding a packet it built itself.
+    Internal(String),
+}
+
+impl Error {
+    pub(crate) fn protocol(message: impl Into<String>) -> Self {
+        Error::Protocol(message.into())
+    }
+
+    pub(crate) fn resource_exhausted(message: impl Into<String>) -> Self {
+        Error::ResourceExhausted(message.into())
+    }
+
+    pub(crate) fn policy(message: impl Into<String>) -> Self {
+        Error::Policy(message.into())
+    }
+
+    pub(crate) fn config(message: impl Into<String>) -> Self {
+        Error::Config(message.into())
+    }
+
+    pub(crate) fn internal(message: impl Into<String>) -> Self {
+        Error::Internal(message.into())
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Transport(err) => write!(f, "transport error: {}", err),
+            Error::Protocol(message) => write!(f, "protocol error: {}", message),
+            Error::ResourceExhausted(message) => write!(f, "resource exhausted: {}", message),
+            Error::Policy(message) => write!(f, "policy violation: {}", message),
+            Error::Config(message) => write!(f, "invalid config: {}", message),
+            Error::Internal(message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(err) => Some(err),
+            Error::Protocol(_) | Error::ResourceExhausted(_) | Error::Policy(_) | Error::Config(_) | Error::Internal(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Transport(err)
+    }
+}
+
+impl From<nix::Error> for Error {
+    fn from(err: nix::Error) -> Self {
+        match err {
+            nix::Error::Sys(errno) => Error::Transport(std::io::Error::from_raw_os_error(errno as i32)),
+            err => Error::Transport(std::io::Error::new(std::io::ErrorKind::Other, err)),
+        }
+    }
+}
@@ -0,0 +1,162 @@
+// How long the runtime can go without receiving its own heartbeat before the broker is considered unhealthy. Heartbeats are
+// sent through pending_wake_fd every second, so this allows for a few to be late.
+const HEARTBEAT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// How long the buffer pool can be exhausted, with connections waiting for a buffer, before the broker is considered unhealthy.
+// Buffers are only held while packets are being read and by the queued payloads of large publishes, which are bounded by the
+// queue watermarks, so a pool that stays exhausted this long is most likely leaking them.
+const BUFFER_POOL_EXHAUSTED_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// How long the acceptor can be paused before the broker is considered not ready. The acceptor pauses whenever the session
+// is briefly congested, and reporting each of those would flap the broker in and out of service.
+const ACCEPTOR_PAUSED_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// The state of the broker that the `HttpListener`'s health and readiness checks are computed from, maintained by the `Runtime`.
+pub(crate) struct Health {
+    started_at: std::time::Instant,
+
+    // When the runtime last received the heartbeat it sends itself, if it has yet.
+    last_heartbeat: Option<std::time::Instant>,
+
+    // Since when the buffer pool has had no free buffers and connections waiting for one, if it had none when last updated.
+    buffer_pool_exhausted_since: Option<std::time::Instant>,
+
+    // Since when the acceptor has been paused, because there were too many connections or the session was congested,
+    // if it was paused when last updated.
+    acceptor_paused_since: Option<std::time::Instant>,
+}
+
+impl Health {
+    pub(crate) fn new(started_at: std::time::Instant) -> Self {
+        Health {
+            started_at,
+            last_heartbeat: None,
+            buffer_pool_exhausted_since: None,
+            acceptor_paused_since: None,
+        }
+    }
+
+    pub(crate) fn heartbeat(&mut self, now: std::time::Instant) {
+        self.last_heartbeat = Some(now);
+    }
+
+    // Called periodically by the runtime.
+    pub(crate) fn update(&mut self, now: std::time::Instant, buffer_pool: &crate::buffer_pool::BufferPoolStats, acceptor_paused: bool) {
+        if buffer_pool_exhausted(buffer_pool) {
+            self.buffer_pool_exhausted_since.get_or_insert(now);
+        }
+        else {
+            self.buffer_pool_exhausted_since = None;
+        }
+
+        if acceptor_paused {
+            self.acceptor_paused_since.get_or_insert(now);
+        }
+        else {
+            self.acceptor_paused_since = None;
+        }
+    }
+
+    // Whether the broker is working at all, ie its event loop is turning and connections aren't stuck waiting for buffers,
+    // along with the details of each check.
+    pub(crate) fn live(&self, now: std::time::Instant, session: &crate::Session) -> (bool, serde_json::Map<String, serde_json::Value>) {
+        let mut checks = serde_json::Map::new();
+
+        // Before the first heartbeat, the runtime is given as long from when it started as it would have after a heartbeat.
+        let heartbeat_age = now.saturating_duration_since(self.last_heartbeat.unwrap_or(self.started_at));
+        let heartbeat_ok = heartbeat_age < HEARTBEAT_TIMEOUT;
+        checks.insert("heartbeat".to_owned(), serde_json::json!({
+            "ok": heartbeat_ok,
+            "seconds_since_last": self.last_heartbeat.map(|last_heartbeat| now.saturating_duration_since(last_heartbeat).as_secs_f64()),
+        }));
+
+        // The exhaustion is only tracked as often as the runtime updates it, so a pool that has since recovered counts as recovered.
+        let buffer_pool = session.buffer_pool_stats();
+        let exhausted_for =
+            if buffer_pool_exhausted(&buffer_pool) {
+                self.buffer_pool_exhausted_since.map(|since| now.saturating_duration_since(since))
+            }
+            else {
+                None
+            };
+        let buffer_pool_ok = exhausted_for.map_or(true, |exhausted_for| exhausted_for < BUFFER_POOL_EXHAUSTED_TIMEOUT);
+        checks.insert("buffer_pool".to_owned(), serde_json::json!({
+            "ok": buffer_pool_ok,
+            "free": buffer_pool.free,
+            "waiting": buffer_pool.waiting,
+            "seconds_exhausted": exhausted_for.map(|exhausted_for| exhausted_for.as_secs_f64()),
+        }));
+
+        (heartbeat_ok && buffer_pool_ok, checks)
+    }
+
+    // Whether the broker should be sent new clients, ie it's live, its event loop has started, and it hasn't stopped
+    // accepting connections for long, along with the details of each check.
+    pub(crate) fn ready(&self, now: std::time::Instant, session: &crate::Session) -> (bool, serde_json::Map<String, serde_json::Value>) {
+        let (live, mut checks) = self.live(now, session);
+
+        let started = self.last_heartbeat.is_some();
+        checks.insert("started".to_owned(), serde_json::json!({
+            "ok": started,
+        }));
+
+        let paused_for = self.acceptor_paused_since.map(|since| now.saturating_duration_since(since));
+        let acceptor_ok = paused_for.map_or(true, |paused_for| paused_for < ACCEPTOR_PAUSED_TIMEOUT);
+        checks.insert("acceptor".to_owned(), serde_json::json!({
+            "ok": acceptor_ok,
+            "seconds_paused": paused_for.map(|paused_for| paused_for.as_secs_f64()),
+        }));
+
+        (live && started && acceptor_ok, checks)
+    }
+}
+
+fn buffer_pool_exhausted(buffer_pool: &crate::buffer_pool::BufferPoolStats) -> bool {
+    buffer_pool.free == 0 && buffer_pool.waiting > 0
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn heartbeat() {
+        let session = crate::test_util::session();
+        let started_at = std::time::Instant::now();
+        let mut health = super::Health::new(started_at);
+
+        // Not ready until the first heartbeat, but live for as long as one could still be on its way.
+        assert!(health.live(started_at, &session).0);
+        assert!(!health.ready(started_at, &session).0);
+        assert!(!health.live(started_at + super::HEARTBEAT_TIMEOUT, &session).0);
+
+        health.heartbeat(started_at + std::time::Duration::from_secs(1));
+        assert!(health.ready(started_at + std::time::Duration::from_secs(2), &session).0);
+        assert!(!health.live(started_at + std::time::Duration::from_secs(1) + super::HEARTBEAT_TIMEOUT, &session).0);
+    }
+
+    #[test]
+    fn acceptor_paused() {
+        let session = crate::test_util::session();
+        let now = std::time::Instant::now();
+        let mut health = super::Health::new(now);
+        health.heartbeat(now);
+
+        let buffer_pool = session.buffer_pool_stats();
+        let second = std::time::Duration::from_secs(1);
+
+        // A brief pause doesn't make the broker not ready.
+        health.update(now, &buffer_pool, true);
+        health.heartbeat(now + second);
+        assert!(health.ready(now + second, &session).0);
+
+        health.update(now + 2 * second, &buffer_pool, false);
+        health.update(now + 3 * second, &buffer_pool, true);
+        health.heartbeat(now + 3 * second + super::ACCEPTOR_PAUSED_TIMEOUT);
+        assert!(health.ready(now + 2 * second + super::ACCEPTOR_PAUSED_TIMEOUT, &session).0);
+
+        // Once the pause has gone on long enough it does, but the broker is still live.
+        let (ready, checks) = health.ready(now + 3 * second + super::ACCEPTOR_PAUSED_TIMEOUT, &session);
+        assert!(!ready);
+        assert_eq!(checks["acceptor"]["ok"], false);
+        assert!(health.live(now + 3 * second + super::ACCEPTOR_PAUSED_TIMEOUT, &session).0);
+    }
+}
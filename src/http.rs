@@ -0,0 +1,234 @@
+// The most HTTP connections that are served at once, ie the number of slots in the listener's `Service`.
+pub(crate) const MAX_CONNECTIONS: usize = 16;
+
+// The largest request that is read. Requests are only ever a request line and a few headers, so anything larger is refused.
+const MAX_REQUEST_LEN: usize = 8192;
+
+// How long a connection has to send its request and read the response before it's closed.
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// A listener for HTTP requests for the broker's metrics and health, served by the `Runtime` on the same event loop as the broker.
+//
+//     /metrics    the broker's metrics in the Prometheus text format
+//     /healthz    200 if the broker is working, ie its event loop is turning and the buffer pool isn't stuck exhausted,
+//                 otherwise 503
+//     /readyz     200 if the broker is working and hasn't stopped accepting new clients for long, otherwise 503
+//
+// The health and readiness checks respond with a JSON object with the status and the details of each check.
+//
+// Each connection serves a single request, and is closed once the response has been written.
+pub struct HttpListener {
+    inner: std::net::TcpListener,
+}
+
+pub(crate) struct HttpConnection {
+    inner: std::net::TcpStream,
+    peer_addr: std::net::SocketAddr,
+
+    // The request read so far, until the response has been built.
+    request: Vec<u8>,
+
+    // The response, and the number of bytes of it that have been written, once the whole request has been read.
+    response: Option<(Vec<u8>, usize)>,
+
+    // When the connection is closed if it's still open.
+    deadline: std::time::Instant,
+}
+
+impl HttpListener {
+    pub fn bind(addr: impl std::net::ToSocketAddrs) -> Result<Self, crate::Error> {
+        let inner = std::net::TcpListener::bind(addr)?;
+        inner.set_nonblocking(true)?;
+
+        Ok(HttpListener {
+            inner,
+        })
+    }
+}
+
+impl crate::runtime::Listener for HttpListener {
+    type Connection = HttpConnection;
+
+    const NAME: &'static str = "HTTP";
+
+    fn accept(&self) -> Result<Option<HttpConnection>, crate::Error> {
+        let (stream, peer_addr) = match self.inner.accept() {
+            Ok((stream, peer_addr)) => (stream, peer_addr),
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        stream.set_nonblocking(true)?;
+
+        Ok(Some(HttpConnection {
+            inner: stream,
+            peer_addr,
+            request: vec![],
+            response: None,
+            deadline: std::time::Instant::now() + TIMEOUT,
+        }))
+    }
+}
+
+impl std::os::unix::io::AsRawFd for HttpListener {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl HttpConnection {
+    // Reads the request and writes its response. Returns `Ready(Ok(()))` once the response has been completely written,
+    // at which point the connection should be closed.
+    pub(crate) fn poll(&mut self, session: &crate::Session, health: &crate::health::Health) -> std::task::Poll<Result<(), crate::Error>> {
+        while self.response.is_none() {
+            let mut buf = [0_u8; 1024];
+            let read = match std::io::Read::read(&mut self.inner, &mut buf) {
+                Ok(read) => read,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return std::task::Poll::Pending,
+                Err(err) => return std::task::Poll::Ready(Err(err.into())),
+            };
+            if read == 0 {
+                return std::task::Poll::Ready(Err(crate::Error::Transport(std::io::ErrorKind::UnexpectedEof.into())));
+            }
+
+            self.request.extend_from_slice(&buf[..read]);
+
+            let response =
+                if self.request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    respond(session, health, &self.request)
+                }
+                else if self.request.len() > MAX_REQUEST_LEN {
+                    Response::text("431 Request Header Fields Too Large", "request is too large\n")
+                }
+                else {
+                    continue;
+                };
+            self.request = vec![];
+            self.response = Some((response.encode(), 0));
+        }
+
+        let (response, written) = self.response.as_mut().expect("response was just set");
+        while *written < response.len() {
+            match std::io::Write::write(&mut self.inner, &response[*written..]) {
+                Ok(0) => return std::task::Poll::Ready(Err(crate::Error::Transport(std::io::ErrorKind::WriteZero.into()))),
+                Ok(n) => *written += n,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return std::task::Poll::Pending,
+                Err(err) => return std::task::Poll::Ready(Err(err.into())),
+            }
+        }
+
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+impl crate::runtime::ListenerConnection for HttpConnection {
+    type Peer = std::net::SocketAddr;
+
+    fn peer(&self) -> &std::net::SocketAddr {
+        &self.peer_addr
+    }
+
+    fn deadline(&self) -> std::time::Instant {
+        self.deadline
+    }
+}
+
+impl std::os::unix::io::AsRawFd for HttpConnection {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+
+    // Whether only the headers are sent, for a HEAD request.
+    head: bool,
+}
+
+impl Response {
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+            head: false,
+        }
+    }
+
+    // A response with the status and checks of a health or readiness check.
+    fn health(ok: bool, checks: &serde_json::Map<String, serde_json::Value>) -> Self {
+        let body = serde_json::json!({
+            "status": if ok { "ok" } else { "degraded" },
+            "checks": checks,
+        });
+
+        Response {
+            status: if ok { "200 OK" } else { "503 Service Unavailable" },
+            content_type: "application/json",
+            body: format!("{}\n", body),
+            head: false,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len(),
+        );
+        if !self.head {
+            response.push_str(&self.body);
+        }
+        response.into_bytes()
+    }
+}
+
+// Routes the request, which has been read up to the end of its headers.
+fn respond(session: &crate::Session, health: &crate::health::Health, request: &[u8]) -> Response {
+    let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let request_line = match std::str::from_utf8(request_line) {
+        Ok(request_line) => request_line,
+        Err(_) => return Response::text("400 Bad Request", "malformed request line\n"),
+    };
+
+    let mut parts = request_line.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => (method, target),
+        _ => return Response::text("400 Bad Request", "malformed request line\n"),
+    };
+
+    let head = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return Response::text("405 Method Not Allowed", "only GET and HEAD are supported\n"),
+    };
+
+    let path = target.split('?').next().unwrap_or_default();
+
+    let mut response = match path {
+        "/metrics" => Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body: session.metrics().encode(&session.gauges()),
+            head: false,
+        },
+
+        "/healthz" => {
+            let (ok, checks) = health.live(std::time::Instant::now(), session);
+            Response::health(ok, &checks)
+        },
+
+        "/readyz" => {
+            let (ok, checks) = health.ready(std::time::Instant::now(), session);
+            Response::health(ok, &checks)
+        },
+
+        _ => Response::text("404 Not Found", "not found\n"),
+    };
+    response.head = head;
+    response
+}
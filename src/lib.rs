@@ -1,13 +1,47 @@
 // #![deny(rust_2018_idioms, warnings)]
 #![deny(clippy::all, clippy::pedantic)]
 #![allow(
+    clippy::default_trait_access,
+    clippy::format_push_string,
+    clippy::missing_errors_doc,
+    clippy::must_use_candidate,
+    clippy::struct_field_names,
+    clippy::too_many_lines,
+    clippy::uninlined_format_args,
 )]
 
+// Declared first so that its macros can be used by all the modules after it.
+#[macro_use]
+mod logging;
+pub use logging::{set_logger, set_max_level, HumanLogger, JsonLogger, Level};
+
 mod acceptor;
-pub use acceptor::Acceptor;
+pub use acceptor::{Acceptor, AcceptorConfig};
+
+mod admin;
+pub use admin::AdminListener;
+
+mod access_list;
+pub use access_list::{AccessList, Cidr};
+
+mod budget;
+use budget::Budget;
 
 mod buffer_pool;
-pub use buffer_pool::BufferPool;
+pub use buffer_pool::{BufferPool, BufferPoolConfig};
+
+mod encoded_publish;
+use encoded_publish::EncodedPublish;
+
+mod error;
+pub use error::Error;
+
+mod health;
+
+mod http;
+pub use http::HttpListener;
+
+mod metrics;
 
 mod reader;
 use reader::Reader;
@@ -16,7 +50,20 @@ mod runtime;
 pub use runtime::Runtime;
 
 mod session;
-pub use session::Session;
+pub use session::{Session, SessionConfig, SlowConsumerPolicy};
+
+mod slab;
+use slab::ConnectionId;
+
+mod sys;
+
+#[cfg(test)]
+mod test_util;
+
+mod token_bucket;
+use token_bucket::TokenBucket;
+
+mod topic;
 
 mod writer;
 use writer::Writer;
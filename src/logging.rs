@@ -0,0 +1,158 @@
+// Leveled, structured logging, on top of the `log` crate and its key-value support.
+//
+// Events are logged with the error!, warn!, info!, debug! and trace! macros, optionally with fields before the message:
+//
+//     debug!(connection_id = id, packet_type = "PUBLISH"; "received packet");
+//
+// Field values are captured by their Display impls. Each event is passed to the `log::Log` that was installed, such as
+// a `HumanLogger` or `JsonLogger` installed with `set_logger`, and discarded if none was. Events above the maximum level
+// are discarded without formatting anything, so that disabled events cost no more than a comparison.
+
+pub use log::Level;
+
+// Logs events as single lines of text to stderr, like:
+//
+//     1792300000.123 INFO  mqtt_async::session: accepting client connection_id=0.0 peer_addr=[::1]:50000
+pub struct HumanLogger;
+
+// Logs events as single-line JSON objects to stderr, like:
+//
+//     {"time":1792300000.123,"level":"INFO","target":"mqtt_async::session","message":"accepting client","connection_id":"0.0"}
+//
+// Field values are always strings.
+pub struct JsonLogger;
+
+// Installs the logger that all events are passed to, and logs events up to `Level::Info`. This can only be done once,
+// and should be done before the broker starts, since events logged before then are discarded.
+pub fn set_logger(logger: Box<dyn log::Log>) -> Result<(), crate::Error> {
+    log::set_boxed_logger(logger).map_err(|_| crate::Error::config("logger was already set"))?;
+    log::set_max_level(log::LevelFilter::Info);
+    Ok(())
+}
+
+// Sets the most verbose level of events that are logged.
+pub fn set_max_level(level: Level) {
+    log::set_max_level(level.to_level_filter());
+}
+
+macro_rules! event {
+    ($level:expr, $($field:ident = $value:expr),+ ; $($arg:tt)+) => {
+        log::log!($level, $($field:% = $value),+ ; $($arg)+)
+    };
+
+    ($level:expr, $($arg:tt)+) => {
+        log::log!($level, $($arg)+)
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { event!(log::Level::Error, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { event!(log::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { event!(log::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { event!(log::Level::Debug, $($arg)+) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { event!(log::Level::Trace, $($arg)+) };
+}
+
+impl log::Log for HumanLogger {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        let mut line = format!("{} {:<5} {}: {}", Timestamp, record.level(), record.target(), record.args());
+        let _ = record.key_values().visit(&mut HumanFields(&mut line));
+
+        // A single write, so that lines from concurrent writers aren't interleaved.
+        line.push('\n');
+        let _ = std::io::Write::write_all(&mut std::io::stderr(), line.as_bytes());
+    }
+
+    fn flush(&self) {
+    }
+}
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        let mut line = format!(
+            r#"{{"time":{},"level":"{}","target":{},"message":{}"#,
+            Timestamp,
+            record.level(),
+            JsonString(record.target()),
+            JsonString(&record.args().to_string()),
+        );
+        let _ = record.key_values().visit(&mut JsonFields(&mut line));
+
+        line.push_str("}\n");
+        let _ = std::io::Write::write_all(&mut std::io::stderr(), line.as_bytes());
+    }
+
+    fn flush(&self) {
+    }
+}
+
+// Appends each field of a record to a `HumanLogger` line, as " name=value".
+struct HumanFields<'a>(&'a mut String);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for HumanFields<'_> {
+    fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.push_str(&format!(" {}={}", key, value));
+        Ok(())
+    }
+}
+
+// Appends each field of a record to a `JsonLogger` line, as another member of its object.
+struct JsonFields<'a>(&'a mut String);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.push_str(&format!(",{}:{}", JsonString(key.as_str()), JsonString(&value.to_string())));
+        Ok(())
+    }
+}
+
+// The current time as seconds since the Unix epoch, with millisecond precision.
+struct Timestamp;
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        write!(f, "{}.{:03}", now.as_secs(), now.subsec_millis())
+    }
+}
+
+// Formats a string as a quoted and escaped JSON string.
+struct JsonString<'a>(&'a str);
+
+impl std::fmt::Display for JsonString<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if c.is_control() => write!(f, "\\u{:04x}", u32::from(c))?,
+                c => std::fmt::Write::write_char(f, c)?,
+            }
+        }
+        f.write_str("\"")
+    }
+}
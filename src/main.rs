@@ -1,12 +1,31 @@
 #![deny(rust_2018_idioms, warnings)]
 #![deny(clippy::all, clippy::pedantic)]
 #![allow(
+    clippy::default_trait_access,
 )]
 
 fn main() {
-    let buffer_pool = mqtt_async::BufferPool::new();
-    let session = mqtt_async::Session::new(buffer_pool);
-    let acceptor = mqtt_async::Acceptor::bind(("::", 1883), session.clone()).unwrap();
-    let runtime = mqtt_async::Runtime::new(acceptor, session).unwrap();
+    // MQTT_ASYNC_LOG_FORMAT is "human" (the default) or "json". MQTT_ASYNC_LOG_LEVEL is one of "error", "warn", "info"
+    // (the default), "debug" or "trace".
+    match std::env::var("MQTT_ASYNC_LOG_FORMAT").as_deref() {
+        Ok("json") => mqtt_async::set_logger(Box::new(mqtt_async::JsonLogger)).unwrap(),
+        Ok("human") | Err(std::env::VarError::NotPresent) => mqtt_async::set_logger(Box::new(mqtt_async::HumanLogger)).unwrap(),
+        Ok(format) => panic!("invalid MQTT_ASYNC_LOG_FORMAT {:?}", format),
+        Err(err) => panic!("invalid MQTT_ASYNC_LOG_FORMAT: {}", err),
+    }
+    if let Ok(level) = std::env::var("MQTT_ASYNC_LOG_LEVEL") {
+        mqtt_async::set_max_level(level.parse().unwrap());
+    }
+
+    let buffer_pool = mqtt_async::BufferPool::new(Default::default()).unwrap();
+    let session = mqtt_async::Session::new(buffer_pool, Default::default()).unwrap();
+    let acceptor = mqtt_async::Acceptor::bind(("::", 1883), session.clone(), Default::default()).unwrap();
+    // Metrics and the health and readiness checks are served over HTTP on MQTT_ASYNC_HTTP_ADDR, if it's set.
+    let http_listener = std::env::var("MQTT_ASYNC_HTTP_ADDR").ok().map(|addr| mqtt_async::HttpListener::bind(addr).unwrap());
+
+    // The admin API is served on the Unix socket at MQTT_ASYNC_ADMIN_SOCKET, if it's set.
+    let admin_listener = std::env::var_os("MQTT_ASYNC_ADMIN_SOCKET").map(|path| mqtt_async::AdminListener::bind(path).unwrap());
+
+    let runtime = mqtt_async::Runtime::new(acceptor, session, http_listener, admin_listener).unwrap();
     let () = runtime.run().unwrap();
 }
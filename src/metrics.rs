@@ -0,0 +1,342 @@
+// Counters of the broker's activity, exposed in the Prometheus text format by the runtime's `HttpListener`.
+//
+// Every counter is a Cell, so that it can be updated through a shared reference from anywhere, including while the session's
+// inner state is borrowed.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) connections_accepted: Counter,
+
+    // Indexed by `PacketType as usize`.
+    pub(crate) packets_received: [Counter; PacketType::ALL.len()],
+    pub(crate) packets_sent: [Counter; PacketType::ALL.len()],
+
+    pub(crate) bytes_received: Counter,
+    pub(crate) bytes_sent: Counter,
+
+    // The number of times a publish was queued for a subscriber, and the number of times one was dropped because
+    // the subscriber's queue was full.
+    pub(crate) publishes_routed: Counter,
+    pub(crate) publishes_dropped: Counter,
+
+    pub(crate) epoll_wakeups: Counter,
+
+    // How long each iteration of the runtime's loop took to handle the events it woke up for.
+    pub(crate) loop_latency: Histogram,
+}
+
+// The current state of the broker, computed when the metrics are scraped.
+pub(crate) struct Gauges {
+    pub(crate) connections: usize,
+    pub(crate) subscriptions: usize,
+    pub(crate) queued_bytes: usize,
+    pub(crate) buffer_pool: crate::buffer_pool::BufferPoolStats,
+}
+
+#[derive(Default)]
+pub(crate) struct Counter(std::cell::Cell<u64>);
+
+// The bounds of the buckets of the loop latency histogram, in seconds.
+const LOOP_LATENCY_BUCKETS: [f64; 10] = [0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025, 0.05, 0.1];
+
+#[derive(Default)]
+pub(crate) struct Histogram {
+    // The number of observations in each bucket, not including those in lower buckets. Observations above the last bound
+    // are only counted in count.
+    buckets: [Counter; LOOP_LATENCY_BUCKETS.len()],
+    count: Counter,
+    sum: std::cell::Cell<f64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PacketType {
+    ConnAck,
+    Connect,
+    Disconnect,
+    PingReq,
+    PingResp,
+    PubAck,
+    PubComp,
+    Publish,
+    PubRec,
+    PubRel,
+    SubAck,
+    Subscribe,
+    UnsubAck,
+    Unsubscribe,
+}
+
+impl Metrics {
+    // Encodes these metrics along with the given gauges in the Prometheus text format.
+    pub(crate) fn encode(&self, gauges: &Gauges) -> String {
+        let mut encoder = Encoder(String::new());
+
+        encoder.metric("mqtt_async_connections", "gauge", "The number of connected clients.");
+        encoder.sample("mqtt_async_connections", None, gauges.connections);
+
+        encoder.metric("mqtt_async_connections_total", "counter", "The number of client connections that have been accepted.");
+        encoder.sample("mqtt_async_connections_total", None, self.connections_accepted.get());
+
+        encoder.metric("mqtt_async_packets_received_total", "counter", "The number of packets received from clients, by type.");
+        for (&packet_type, counter) in PacketType::ALL.iter().zip(&self.packets_received) {
+            encoder.sample("mqtt_async_packets_received_total", Some(("type", packet_type.name())), counter.get());
+        }
+
+        encoder.metric("mqtt_async_packets_sent_total", "counter", "The number of packets sent to clients, by type.");
+        for (&packet_type, counter) in PacketType::ALL.iter().zip(&self.packets_sent) {
+            encoder.sample("mqtt_async_packets_sent_total", Some(("type", packet_type.name())), counter.get());
+        }
+
+        encoder.metric("mqtt_async_bytes_received_total", "counter", "The number of bytes read from clients.");
+        encoder.sample("mqtt_async_bytes_received_total", None, self.bytes_received.get());
+
+        encoder.metric("mqtt_async_bytes_sent_total", "counter", "The number of bytes written to clients.");
+        encoder.sample("mqtt_async_bytes_sent_total", None, self.bytes_sent.get());
+
+        encoder.metric("mqtt_async_publishes_routed_total", "counter", "The number of times a publish was queued for a subscriber.");
+        encoder.sample("mqtt_async_publishes_routed_total", None, self.publishes_routed.get());
+
+        encoder.metric("mqtt_async_publishes_dropped_total", "counter", "The number of publishes dropped because a subscriber's queue was full.");
+        encoder.sample("mqtt_async_publishes_dropped_total", None, self.publishes_dropped.get());
+
+        encoder.metric("mqtt_async_subscriptions", "gauge", "The number of subscriptions of all connected clients.");
+        encoder.sample("mqtt_async_subscriptions", None, gauges.subscriptions);
+
+        encoder.metric("mqtt_async_queued_bytes", "gauge", "The total size of the publishes queued for all clients.");
+        encoder.sample("mqtt_async_queued_bytes", None, gauges.queued_bytes);
+
+        encoder.metric("mqtt_async_buffer_pool_free", "gauge", "The number of buffers in the buffer pool.");
+        encoder.sample("mqtt_async_buffer_pool_free", None, gauges.buffer_pool.free);
+
+        encoder.metric("mqtt_async_buffer_pool_reclaiming", "gauge", "The number of buffers put back into the buffer pool that are still shared.");
+        encoder.sample("mqtt_async_buffer_pool_reclaiming", None, gauges.buffer_pool.reclaiming);
+
+        encoder.metric("mqtt_async_buffer_pool_waiting", "gauge", "The number of connections waiting for a buffer.");
+        encoder.sample("mqtt_async_buffer_pool_waiting", None, gauges.buffer_pool.waiting);
+
+        encoder.metric("mqtt_async_epoll_wakeups_total", "counter", "The number of times the runtime woke up from waiting on epoll.");
+        encoder.sample("mqtt_async_epoll_wakeups_total", None, self.epoll_wakeups.get());
+
+        encoder.metric(
+            "mqtt_async_loop_latency_seconds",
+            "histogram",
+            "How long each iteration of the runtime's loop took to handle the events it woke up for.",
+        );
+        let mut cumulative = 0;
+        for (&bound, bucket) in LOOP_LATENCY_BUCKETS.iter().zip(&self.loop_latency.buckets) {
+            cumulative += bucket.get();
+            encoder.sample("mqtt_async_loop_latency_seconds_bucket", Some(("le", &bound.to_string())), cumulative);
+        }
+        encoder.sample("mqtt_async_loop_latency_seconds_bucket", Some(("le", "+Inf")), self.loop_latency.count.get());
+        encoder.sample("mqtt_async_loop_latency_seconds_sum", None, self.loop_latency.sum.get());
+        encoder.sample("mqtt_async_loop_latency_seconds_count", None, self.loop_latency.count.get());
+
+        encoder.0
+    }
+}
+
+impl Counter {
+    pub(crate) fn inc(&self) {
+        self.add(1);
+    }
+
+    pub(crate) fn add(&self, n: u64) {
+        self.0.set(self.0.get().wrapping_add(n));
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+impl Histogram {
+    pub(crate) fn observe(&self, value: std::time::Duration) {
+        let value = value.as_secs_f64();
+
+        if let Some(i) = LOOP_LATENCY_BUCKETS.iter().position(|&bound| value <= bound) {
+            self.buckets[i].inc();
+        }
+        self.count.inc();
+        self.sum.set(self.sum.get() + value);
+    }
+}
+
+impl PacketType {
+    const ALL: [PacketType; 14] = [
+        PacketType::ConnAck,
+        PacketType::Connect,
+        PacketType::Disconnect,
+        PacketType::PingReq,
+        PacketType::PingResp,
+        PacketType::PubAck,
+        PacketType::PubComp,
+        PacketType::Publish,
+        PacketType::PubRec,
+        PacketType::PubRel,
+        PacketType::SubAck,
+        PacketType::Subscribe,
+        PacketType::UnsubAck,
+        PacketType::Unsubscribe,
+    ];
+
+    pub(crate) fn of(packet: &mqtt3::proto::Packet) -> Self {
+        match packet {
+            mqtt3::proto::Packet::ConnAck(_) => PacketType::ConnAck,
+            mqtt3::proto::Packet::Connect(_) => PacketType::Connect,
+            mqtt3::proto::Packet::Disconnect(_) => PacketType::Disconnect,
+            mqtt3::proto::Packet::PingReq(_) => PacketType::PingReq,
+            mqtt3::proto::Packet::PingResp(_) => PacketType::PingResp,
+            mqtt3::proto::Packet::PubAck(_) => PacketType::PubAck,
+            mqtt3::proto::Packet::PubComp(_) => PacketType::PubComp,
+            mqtt3::proto::Packet::Publish(_) => PacketType::Publish,
+            mqtt3::proto::Packet::PubRec(_) => PacketType::PubRec,
+            mqtt3::proto::Packet::PubRel(_) => PacketType::PubRel,
+            mqtt3::proto::Packet::SubAck(_) => PacketType::SubAck,
+            mqtt3::proto::Packet::Subscribe(_) => PacketType::Subscribe,
+            mqtt3::proto::Packet::UnsubAck(_) => PacketType::UnsubAck,
+            mqtt3::proto::Packet::Unsubscribe(_) => PacketType::Unsubscribe,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            PacketType::ConnAck => "CONNACK",
+            PacketType::Connect => "CONNECT",
+            PacketType::Disconnect => "DISCONNECT",
+            PacketType::PingReq => "PINGREQ",
+            PacketType::PingResp => "PINGRESP",
+            PacketType::PubAck => "PUBACK",
+            PacketType::PubComp => "PUBCOMP",
+            PacketType::Publish => "PUBLISH",
+            PacketType::PubRec => "PUBREC",
+            PacketType::PubRel => "PUBREL",
+            PacketType::SubAck => "SUBACK",
+            PacketType::Subscribe => "SUBSCRIBE",
+            PacketType::UnsubAck => "UNSUBACK",
+            PacketType::Unsubscribe => "UNSUBSCRIBE",
+        }
+    }
+}
+
+impl std::fmt::Display for PacketType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+struct Encoder(String);
+
+impl Encoder {
+    fn metric(&mut self, name: &str, metric_type: &str, help: &str) {
+        self.0.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, metric_type));
+    }
+
+    fn sample(&mut self, name: &str, label: Option<(&str, &str)>, value: impl std::fmt::Display) {
+        match label {
+            Some((label_name, label_value)) => self.0.push_str(&format!("{}{{{}=\"{}\"}} {}\n", name, label_name, label_value, value)),
+            None => self.0.push_str(&format!("{} {}\n", name, value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn gauges() -> super::Gauges {
+        super::Gauges {
+            connections: 3,
+            subscriptions: 5,
+            queued_bytes: 100,
+            buffer_pool: crate::buffer_pool::BufferPoolStats {
+                free: 7,
+                reclaiming: 1,
+                waiting: 0,
+            },
+        }
+    }
+
+    // Returns the lines of the exposition of the given metric, from its HELP line up to the next metric's.
+    fn metric<'a>(encoded: &'a str, name: &str) -> Vec<&'a str> {
+        let help = format!("# HELP {} ", name);
+        encoded.lines()
+            .skip_while(|line| !line.starts_with(&help))
+            .enumerate()
+            .take_while(|&(i, line)| i == 0 || !line.starts_with("# HELP "))
+            .map(|(_, line)| line)
+            .collect()
+    }
+
+    #[test]
+    fn histogram() {
+        let metrics: super::Metrics = Default::default();
+        for &micros in &[50, 100, 300, 300, 2_000, 1_000_000] {
+            metrics.loop_latency.observe(std::time::Duration::from_micros(micros));
+        }
+
+        let encoded = metrics.encode(&gauges());
+        assert_eq!(metric(&encoded, "mqtt_async_loop_latency_seconds"), [
+            "# HELP mqtt_async_loop_latency_seconds How long each iteration of the runtime's loop took to handle the events it woke up for.",
+            "# TYPE mqtt_async_loop_latency_seconds histogram",
+            // Each bucket counts the observations at or below its bound, so they're cumulative, and an observation
+            // on a bound is in that bound's bucket.
+            "mqtt_async_loop_latency_seconds_bucket{le=\"0.0001\"} 2",
+            "mqtt_async_loop_latency_seconds_bucket{le=\"0.00025\"} 2",
+            "mqtt_async_loop_latency_seconds_bucket{le=\"0.0005\"} 4",
+            "mqtt_async_loop_latency_seconds_bucket{le=\"0.001\"} 4",
+            "mqtt_async_loop_latency_seconds_bucket{le=\"0.0025\"} 5",
+            "mqtt_async_loop_latency_seconds_bucket{le=\"0.005\"} 5",
+            "mqtt_async_loop_latency_seconds_bucket{le=\"0.01\"} 5",
+            "mqtt_async_loop_latency_seconds_bucket{le=\"0.025\"} 5",
+            "mqtt_async_loop_latency_seconds_bucket{le=\"0.05\"} 5",
+            "mqtt_async_loop_latency_seconds_bucket{le=\"0.1\"} 5",
+            // The observation above the last bound is only in +Inf, which is the count.
+            "mqtt_async_loop_latency_seconds_bucket{le=\"+Inf\"} 6",
+            "mqtt_async_loop_latency_seconds_sum 1.00275",
+            "mqtt_async_loop_latency_seconds_count 6",
+        ]);
+    }
+
+    #[test]
+    fn counters_and_gauges() {
+        let metrics: super::Metrics = Default::default();
+        metrics.connections_accepted.add(4);
+        metrics.packets_received[super::PacketType::Publish as usize].inc();
+        metrics.packets_received[super::PacketType::Publish as usize].inc();
+        metrics.packets_received[super::PacketType::Connect as usize].inc();
+
+        let encoded = metrics.encode(&gauges());
+        assert!(encoded.ends_with('\n'));
+
+        assert_eq!(metric(&encoded, "mqtt_async_connections"), [
+            "# HELP mqtt_async_connections The number of connected clients.",
+            "# TYPE mqtt_async_connections gauge",
+            "mqtt_async_connections 3",
+        ]);
+
+        assert_eq!(metric(&encoded, "mqtt_async_connections_total"), [
+            "# HELP mqtt_async_connections_total The number of client connections that have been accepted.",
+            "# TYPE mqtt_async_connections_total counter",
+            "mqtt_async_connections_total 4",
+        ]);
+
+        let packets_received = metric(&encoded, "mqtt_async_packets_received_total");
+        assert_eq!(packets_received[1], "# TYPE mqtt_async_packets_received_total counter");
+        assert_eq!(packets_received.len(), 2 + super::PacketType::ALL.len());
+        assert!(packets_received.contains(&"mqtt_async_packets_received_total{type=\"PUBLISH\"} 2"));
+        assert!(packets_received.contains(&"mqtt_async_packets_received_total{type=\"CONNECT\"} 1"));
+        assert!(packets_received.contains(&"mqtt_async_packets_received_total{type=\"SUBSCRIBE\"} 0"));
+
+        // Every metric has exactly one HELP and one TYPE line, right before its samples.
+        let mut names = vec![];
+        let mut lines = encoded.lines().peekable();
+        while let Some(line) = lines.next() {
+            let name = line.strip_prefix("# HELP ").unwrap().split(' ').next().unwrap();
+            let type_line = lines.next().unwrap();
+            assert!(type_line.starts_with(&format!("# TYPE {} ", name)), "{}", type_line);
+            assert!(lines.peek().map_or(false, |line| line.starts_with(name)));
+            while lines.peek().map_or(false, |line| !line.starts_with('#')) {
+                assert!(lines.next().unwrap().starts_with(name));
+            }
+            assert!(!names.contains(&name), "{} is exposed twice", name);
+            names.push(name);
+        }
+    }
+}
@@ -1,8 +1,14 @@
-use std::io::Read;
+// The number of reads and received packets a Reader processes each time it's polled.
+const BUDGET: usize = 64;
 
-use bytes::BufMut;
+// The largest partial packet a Reader copies out of its pooled read buffer so that it can return the buffer to the pool.
+const STASH_CAPACITY: usize = 64;
+
+// The smallest amount of spare capacity in a read buffer that is worth reading into.
+pub(crate) const MIN_READ_LEN: usize = 512;
 
 pub(crate) struct Reader {
+    id: crate::ConnectionId,
     inner: std::rc::Rc<std::net::TcpStream>,
     buffer_pool: std::rc::Rc<crate::BufferPool>,
     session: std::rc::Rc<crate::Session>,
@@ -10,15 +16,46 @@ pub(crate) struct Reader {
 
     pending_packet: Option<mqtt3::proto::Packet>,
     pending_read: Option<bytes::BytesMut>,
+
+    // Whether pending_read came from the BufferPool, as opposed to being allocated for a single packet that is too large
+    // for a pooled buffer.
+    pending_read_pooled: bool,
+
+    // Bytes read past the end of the packet in pending_read.
+    pending_read_next: Option<bytes::BytesMut>,
+
+    // The number of bytes of the packet at the front of pending_read that have not been read yet,
+    // or None if its fixed header hasn't been completely read yet.
+    packet_unread: Option<usize>,
+
+    // The largest packet the client is allowed to send.
+    max_packet_size: usize,
+
+    stash: Vec<u8>,
+    read_closed: bool,
+
+    // The client's rate limits, and the number of times reading from it has been paused for exceeding them.
+    packet_rate: Option<crate::TokenBucket>,
+    byte_rate: Option<crate::TokenBucket>,
+    rate_limit_violations: u32,
+
+    // While reading is paused for exceeding the rate limits, when the timer to resume it was registered for.
+    // Polls during the same pause neither count another violation nor register another timer.
+    rate_limited_until: Option<std::time::Instant>,
+    max_rate_limit_violations: Option<u32>,
 }
 
 impl Reader {
     pub(crate) fn new(
+        id: crate::ConnectionId,
         inner: std::rc::Rc<std::net::TcpStream>,
         buffer_pool: std::rc::Rc<crate::BufferPool>,
         session: std::rc::Rc<crate::Session>,
+        config: &crate::SessionConfig,
+        max_packet_size: usize,
     ) -> Self {
         Reader {
+            id,
             inner,
             buffer_pool,
             session,
@@ -26,54 +63,309 @@ impl Reader {
 
             pending_packet: None,
             pending_read: None,
+            pending_read_pooled: false,
+            pending_read_next: None,
+            packet_unread: None,
+            max_packet_size,
+            stash: Vec::with_capacity(STASH_CAPACITY),
+            read_closed: false,
+
+            packet_rate: config.max_client_packet_rate.map(crate::TokenBucket::new),
+            byte_rate: config.max_client_byte_rate.map(crate::TokenBucket::new),
+            rate_limit_violations: 0,
+            rate_limited_until: None,
+            max_rate_limit_violations: config.max_client_rate_limit_violations,
         }
     }
 
-    pub(crate) fn poll(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+    pub(crate) fn id(&self) -> crate::ConnectionId {
+        self.id
+    }
+
+    pub(crate) fn read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    // Returns `Ready(Ok(()))` once the peer has shut down its write half and every packet it sent has been received.
+    pub(crate) fn poll(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), crate::Error>> {
+        let result = self.poll_inner(cx);
+
+        // Don't hold on to a pooled buffer while waiting for the socket to become readable again,
+        // otherwise every idle connection pins a buffer and the pool runs dry.
+        if let std::task::Poll::Pending | std::task::Poll::Ready(Ok(())) = result {
+            self.release_read_buf();
+        }
+
+        result
+    }
+
+    fn poll_inner(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), crate::Error>> {
         let fd = std::os::unix::io::AsRawFd::as_raw_fd(self);
 
-        let buf =
-            if let Some(buf) = &mut self.pending_read {
-                buf
-            }
-            else {
-                let buf = match self.buffer_pool.poll_take(cx) {
-                    std::task::Poll::Ready(buf) => buf,
-                    std::task::Poll::Pending => return std::task::Poll::Pending,
-                };
-                self.pending_read.get_or_insert(buf)
-            };
+        if self.read_closed {
+            return std::task::Poll::Ready(Ok(()));
+        }
+
+        let mut budget = crate::Budget::new(BUDGET);
 
         loop {
-            match self.session.poll_recv_ready(cx, fd) {
+            match budget.poll_consume(cx) {
                 std::task::Poll::Ready(()) => (),
                 std::task::Poll::Pending => return std::task::Poll::Pending,
             }
 
+            match self.session.poll_recv_ready(cx, self.id) {
+                std::task::Poll::Ready(Ok(())) => (),
+                std::task::Poll::Ready(Err(err)) => return std::task::Poll::Ready(Err(err)),
+                std::task::Poll::Pending => return std::task::Poll::Pending,
+            }
+
+            match self.poll_rate_limits(cx) {
+                std::task::Poll::Ready(Ok(())) => (),
+                std::task::Poll::Ready(Err(err)) => return std::task::Poll::Ready(Err(err)),
+                std::task::Poll::Pending => return std::task::Poll::Pending,
+            }
+
             if let Some(pending_packet) = self.pending_packet.take() {
-                self.session.recv(cx, fd, pending_packet)?;
+                self.session.recv(cx, self.id, pending_packet)?;
             }
 
-            match mqtt3::proto::decode(&mut self.decoder, buf).map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))? {
-                Some(packet) => self.pending_packet = Some(packet),
-                None => {
-                    let mut read_buf = as_read_buf(buf);
+            if self.pending_read.is_none() {
+                let mut buf = match self.buffer_pool.poll_take(cx, self.id) {
+                    std::task::Poll::Ready(buf) => buf,
+                    std::task::Poll::Pending => return std::task::Poll::Pending,
+                };
+                buf.extend_from_slice(&self.stash);
+                self.stash.clear();
+                self.pending_read = Some(buf);
+                self.pending_read_pooled = true;
+            }
+
+            if self.packet_unread.is_none() {
+                self.advance_pending_read();
+
+                let buf = self.pending_read.as_ref().expect("pending_read was just set");
+                let packet_len = packet_len(buf)?;
+
+                // Checked before any more of the packet is read, so that a client can't make the broker buffer
+                // an arbitrarily large packet just by announcing it.
+                if let Some(packet_len) = packet_len {
+                    if packet_len > self.max_packet_size {
+                        return std::task::Poll::Ready(Err(crate::Error::policy(
+                            format!("packet of {} bytes exceeds the maximum packet size of {} bytes", packet_len, self.max_packet_size),
+                        )));
+                    }
+                }
+
+                self.packet_unread = packet_len.map(|packet_len| packet_len.saturating_sub(buf.len()));
+            }
+
+            // The decoder is only given the packet once its fixed header has been read, so that its length is known
+            // before the decoder consumes any of it.
+            if self.packet_unread.is_some() {
+                let buf = self.pending_read.as_mut().expect("pending_read was just set");
+                let packet =
+                    mqtt3::proto::decode(&mut self.decoder, buf)
+                    .map_err(|err| crate::Error::protocol(format!("could not decode packet: {}", err)))?;
+                if let Some(packet) = packet {
+                    self.pending_packet = Some(packet);
+                    self.packet_unread = None;
+                    if let Some(packet_rate) = &mut self.packet_rate {
+                        packet_rate.consume(1);
+                    }
+                    continue;
+                }
+            }
+
+            let packet_unread = self.packet_unread.unwrap_or(0);
+
+            let buf = self.pending_read.as_mut().expect("pending_read was just set");
+
+            if packet_unread == 0 && buf.capacity() - buf.len() < MIN_READ_LEN {
+                // Too little room left to be worth reading into, and there is at most a partial fixed header in it,
+                // so stash that and start over with a new buffer.
+                self.release_read_buf();
+                continue;
+            }
+
+            if buf.capacity() - buf.len() < packet_unread {
+                let required = buf.len() + packet_unread;
+                if self.pending_read_pooled && required <= self.buffer_pool.max_buffer_capacity() {
+                    buf.reserve(packet_unread);
+                }
+                else {
+                    // The packet is too large for a pooled buffer, so it gets a buffer of its own. That's still never larger
+                    // than the largest packet the client is allowed to send, whatever the client announced.
+                    if required > self.max_packet_size {
+                        return std::task::Poll::Ready(Err(crate::Error::policy(
+                            format!("packet of {} bytes exceeds the maximum packet size of {} bytes", required, self.max_packet_size),
+                        )));
+                    }
+
+                    let mut large_buf = bytes::BytesMut::with_capacity(required);
+                    large_buf.extend_from_slice(buf);
+                    let buf = std::mem::replace(buf, large_buf);
+                    if self.pending_read_pooled {
+                        self.buffer_pool.put_back(buf);
+                    }
+                    self.pending_read_pooled = false;
+                }
+            }
 
-                    let read = match (&*self.inner).read(&mut read_buf) {
-                        Ok(read) => read,
-                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return std::task::Poll::Pending,
-                        Err(err) => return std::task::Poll::Ready(Err(err)),
-                    };
+            // When the rest of the current packet is being read, read whatever follows it into a second buffer in the same syscall,
+            // so that the current packet's buffer doesn't need to grow past the end of the packet.
+            let mut next_buf = if packet_unread > 0 { self.buffer_pool.try_take() } else { None };
 
-                    if read == 0 {
-                        return std::task::Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+            let buf = self.pending_read.as_mut().expect("pending_read was just set");
+            let read = match read_vectored(fd, buf, if packet_unread > 0 { packet_unread } else { usize::MAX }, next_buf.as_mut()) {
+                Ok(read) => read,
+                Err(err) => {
+                    if let Some(next_buf) = next_buf {
+                        self.buffer_pool.put_back(next_buf);
                     }
 
-                    unsafe { buf.advance_mut(read); }
+                    if err.kind() == std::io::ErrorKind::WouldBlock {
+                        return std::task::Poll::Pending;
+                    }
 
-                    eprintln!("fd {}: read {} bytes", fd, read);
+                    return std::task::Poll::Ready(Err(err.into()));
                 },
+            };
+
+            if let Some(packet_unread) = &mut self.packet_unread {
+                *packet_unread -= std::cmp::min(read, *packet_unread);
             }
+
+            if let Some(byte_rate) = &mut self.byte_rate {
+                byte_rate.consume(read);
+            }
+
+            self.session.metrics().bytes_received.add(read as u64);
+
+            match next_buf {
+                Some(next_buf) if !next_buf.is_empty() => self.pending_read_next = Some(next_buf),
+                Some(next_buf) => self.buffer_pool.put_back(next_buf),
+                None => (),
+            }
+
+            if read == 0 {
+                if !buf.is_empty() || self.packet_unread.is_some() {
+                    // Peer closed its write half in the middle of a packet.
+                    return std::task::Poll::Ready(Err(crate::Error::Transport(std::io::ErrorKind::UnexpectedEof.into())));
+                }
+
+                self.read_closed = true;
+                return std::task::Poll::Ready(Ok(()));
+            }
+
+            trace!(connection_id = self.id; "read {} bytes", read);
+        }
+    }
+
+    // Pauses reading from the client until it's back within its rate limits.
+    fn poll_rate_limits(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), crate::Error>> {
+        let ready_at = std::cmp::max(
+            self.packet_rate.as_mut().and_then(crate::TokenBucket::ready_at),
+            self.byte_rate.as_mut().and_then(crate::TokenBucket::ready_at),
+        );
+        let ready_at =
+            if let Some(ready_at) = ready_at {
+                ready_at
+            }
+            else {
+                self.rate_limited_until = None;
+                return std::task::Poll::Ready(Ok(()));
+            };
+
+        match self.rate_limited_until {
+            None => {
+                self.rate_limit_violations += 1;
+                if let Some(max_rate_limit_violations) = self.max_rate_limit_violations {
+                    if self.rate_limit_violations > max_rate_limit_violations {
+                        return std::task::Poll::Ready(Err(crate::Error::policy("client exceeded its rate limits too many times")));
+                    }
+                }
+            },
+
+            // Woken by something else before the timer expired, eg a buffer becoming available. The timer still wakes it.
+            Some(rate_limited_until) if std::time::Instant::now() < rate_limited_until => return std::task::Poll::Pending,
+
+            // The timer expired but the bucket is still just short of being out of debt, so the timer is registered again.
+            Some(_) => (),
+        }
+
+        self.rate_limited_until = Some(ready_at);
+        self.session.register_timer(cx, ready_at);
+        std::task::Poll::Pending
+    }
+
+    // Called at a packet boundary. If the current read buffer has been completely decoded and bytes were read past it
+    // into pending_read_next, then that becomes the current read buffer.
+    fn advance_pending_read(&mut self) {
+        let next_buf = match self.pending_read_next.take() {
+            Some(next_buf) => next_buf,
+            None => return,
+        };
+
+        let buf = self.pending_read.as_mut().expect("pending_read_next is only set when pending_read is");
+        if buf.is_empty() {
+            let buf = std::mem::replace(buf, next_buf);
+            if self.pending_read_pooled {
+                self.buffer_pool.put_back(buf);
+            }
+            self.pending_read_pooled = true;
+        }
+        else {
+            buf.extend_from_slice(&next_buf);
+            self.buffer_pool.put_back(next_buf);
+        }
+    }
+
+    // Returns the read buffer to the pool. A partial packet that is too large to stash is moved into a buffer of its own,
+    // so that a client that stops sending in the middle of a packet doesn't hold on to a pooled buffer.
+    fn release_read_buf(&mut self) {
+        if self.pending_read_next.is_some() {
+            // There are complete packets still to be decoded.
+            return;
+        }
+
+        let buf = match self.pending_read.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+
+        if buf.len() > STASH_CAPACITY {
+            if !self.pending_read_pooled {
+                self.pending_read = Some(buf);
+                return;
+            }
+
+            // Sized for the rest of the packet, so that it doesn't need to grow while that's read.
+            let mut own_buf = bytes::BytesMut::with_capacity(buf.len() + self.packet_unread.unwrap_or(0));
+            own_buf.extend_from_slice(&buf);
+            self.buffer_pool.put_back(buf);
+            self.pending_read = Some(own_buf);
+            self.pending_read_pooled = false;
+            return;
+        }
+
+        self.stash.extend_from_slice(&buf);
+        if self.pending_read_pooled {
+            self.buffer_pool.put_back(buf);
+        }
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        if let Some(buf) = self.pending_read.take() {
+            if self.pending_read_pooled {
+                self.buffer_pool.put_back(buf);
+            }
+        }
+
+        if let Some(buf) = self.pending_read_next.take() {
+            self.buffer_pool.put_back(buf);
         }
     }
 }
@@ -81,9 +373,14 @@ impl Reader {
 impl std::fmt::Debug for Reader {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Reader")
+            .field("id", &self.id)
             .field("inner", &std::os::unix::io::AsRawFd::as_raw_fd(self))
             .field("pending_read", &self.pending_read)
-            .finish()
+            .field("pending_read_next", &self.pending_read_next)
+            .field("packet_unread", &self.packet_unread)
+            .field("stash", &self.stash)
+            .field("read_closed", &self.read_closed)
+            .finish_non_exhaustive()
     }
 }
 
@@ -93,26 +390,165 @@ impl std::os::unix::io::AsRawFd for Reader {
     }
 }
 
-fn as_read_buf(buf: &'_ mut bytes::BytesMut) -> &'_ mut [u8] {
-    if !buf.has_remaining_mut() {
-        buf.reserve(buf.capacity());
+// Returns the length of the packet at the start of the given buffer, if its fixed header is complete.
+fn packet_len(buf: &[u8]) -> Result<Option<usize>, crate::Error> {
+    let mut remaining_length = 0;
+
+    for (i, &digit) in buf.iter().skip(1).take(4).enumerate() {
+        remaining_length |= usize::from(digit & 0x7F) << (7 * i);
+        if digit & 0x80 == 0 {
+            let fixed_header_len = 1 + i + 1;
+            return Ok(Some(fixed_header_len + remaining_length));
+        }
+    }
+
+    if buf.len() > 4 {
+        return Err(crate::Error::protocol("malformed remaining length"));
     }
 
-    let chunk = buf.chunk_mut();
+    Ok(None)
+}
+
+// Reads into the spare capacity of buf, up to at most limit bytes, and then into the spare capacity of next_buf.
+//
+// The spare capacity is uninitialized, so this reads with readv(2) directly rather than going through std::io::Read,
+// which would require initializing it first.
+fn read_vectored(
+    fd: std::os::unix::io::RawFd,
+    buf: &mut bytes::BytesMut,
+    limit: usize,
+    next_buf: Option<&mut bytes::BytesMut>,
+) -> std::io::Result<usize> {
+    let spare = buf.spare_capacity_mut();
+    let len = std::cmp::min(spare.len(), limit);
+    let mut iov = [
+        nix::libc::iovec { iov_base: spare.as_mut_ptr().cast(), iov_len: len },
+        nix::libc::iovec { iov_base: std::ptr::null_mut(), iov_len: 0 },
+    ];
+    let mut iov_len = 1;
+
+    let mut next_buf = next_buf;
+    if let Some(next_buf) = &mut next_buf {
+        let next_spare = next_buf.spare_capacity_mut();
+        iov[1] = nix::libc::iovec { iov_base: next_spare.as_mut_ptr().cast(), iov_len: next_spare.len() };
+        iov_len = 2;
+    }
 
-    // tokio converts the UninitSlice directly to [MaybeUninit<u8>] because UninitSlice is repr(transparent) over that type.
-    // However this is actually an undocumented implementation detail of UninitSlice, let's not rely on it.
-    // Instead we construct it manually from its as_mut_ptr() and len().
+    let read = unsafe { nix::libc::readv(fd, iov.as_ptr(), iov_len) };
+    let read = match std::convert::TryInto::<usize>::try_into(read) {
+        Ok(read) => read,
+        Err(_) => return Err(std::io::Error::last_os_error()),
+    };
 
-    let ptr: *mut std::mem::MaybeUninit<u8> = chunk.as_mut_ptr().cast();
-    let len = chunk.len();
+    // readv fills the iovecs in order, so anything past the first one went into next_buf.
     unsafe {
-        let read_buf = std::slice::from_raw_parts_mut(ptr, len);
-        read_buf.fill(std::mem::MaybeUninit::new(0));
+        buf.set_len(buf.len() + std::cmp::min(read, len));
+        if let Some(next_buf) = next_buf {
+            next_buf.set_len(next_buf.len() + read.saturating_sub(len));
+        }
+    }
+
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn packet_len_partial_header() {
+        assert_eq!(super::packet_len(&[]).unwrap(), None);
+        assert_eq!(super::packet_len(&[0x30]).unwrap(), None);
+        assert_eq!(super::packet_len(&[0x30, 0x80]).unwrap(), None);
+        assert_eq!(super::packet_len(&[0x30, 0x80, 0x80, 0x80]).unwrap(), None);
+    }
+
+    #[test]
+    fn packet_len_complete_header() {
+        assert_eq!(super::packet_len(&[0xc0, 0x00]).unwrap(), Some(2));
+        assert_eq!(super::packet_len(&[0x30, 0x7f]).unwrap(), Some(2 + 127));
+
+        // The rest of the packet doesn't need to have been read.
+        assert_eq!(super::packet_len(&[0x30, 0x80, 0x01]).unwrap(), Some(3 + 128));
+        assert_eq!(super::packet_len(&[0x30, 0x80, 0x01, 0x00, 0x03]).unwrap(), Some(3 + 128));
+
+        // The largest remaining length that can be encoded.
+        assert_eq!(super::packet_len(&[0x30, 0xff, 0xff, 0xff, 0x7f]).unwrap(), Some(5 + 268_435_455));
+    }
+
+    #[test]
+    fn packet_len_malformed() {
+        assert!(super::packet_len(&[0x30, 0x80, 0x80, 0x80, 0x80]).is_err());
+        assert!(super::packet_len(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01]).is_err());
+    }
+
+    #[test]
+    fn read_vectored_splits_at_limit() {
+        let (mut writer, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::io::Write::write_all(&mut writer, b"0123456789").unwrap();
+
+        let mut buf = bytes::BytesMut::with_capacity(16);
+        buf.extend_from_slice(b"ab");
+        let mut next_buf = bytes::BytesMut::with_capacity(16);
+        let read = super::read_vectored(std::os::unix::io::AsRawFd::as_raw_fd(&reader), &mut buf, 4, Some(&mut next_buf)).unwrap();
+
+        assert_eq!(read, 10);
+        assert_eq!(&buf[..], b"ab0123");
+        assert_eq!(&next_buf[..], b"456789");
+    }
+
+    #[test]
+    fn read_vectored_short_read() {
+        let (mut writer, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::io::Write::write_all(&mut writer, b"012").unwrap();
+
+        let mut buf = bytes::BytesMut::with_capacity(16);
+        let mut next_buf = bytes::BytesMut::with_capacity(16);
+        let read = super::read_vectored(std::os::unix::io::AsRawFd::as_raw_fd(&reader), &mut buf, 4, Some(&mut next_buf)).unwrap();
+
+        assert_eq!(read, 3);
+        assert_eq!(&buf[..], b"012");
+        assert!(next_buf.is_empty());
+    }
+
+    #[test]
+    fn read_vectored_without_next_buf() {
+        let (mut writer, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::io::Write::write_all(&mut writer, b"0123456789").unwrap();
+
+        // Without a limit, the read is only bounded by the buffer's spare capacity.
+        let mut buf = bytes::BytesMut::with_capacity(8);
+        let read = super::read_vectored(std::os::unix::io::AsRawFd::as_raw_fd(&reader), &mut buf, usize::MAX, None).unwrap();
+        assert_eq!(read, buf.capacity());
+        assert_eq!(&buf[..], &b"0123456789"[..read]);
+
+        reader.set_nonblocking(true).unwrap();
+        let mut buf = bytes::BytesMut::with_capacity(64);
+        let _ = super::read_vectored(std::os::unix::io::AsRawFd::as_raw_fd(&reader), &mut buf, usize::MAX, None).unwrap();
+        let err = super::read_vectored(std::os::unix::io::AsRawFd::as_raw_fd(&reader), &mut buf, usize::MAX, None).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn exhausted_budget_yields_once() {
+        let session = crate::test_util::session();
+        let mut client = crate::test_util::TestClient::new(&session);
+        client.send(&crate::test_util::connect(b"a"));
+        client.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        // More PINGREQs than the reader's budget allows it to receive in one poll.
+        std::io::Write::write_all(&mut client.stream, &[0xc0, 0].repeat(super::BUDGET + super::BUDGET / 2)).unwrap();
+
+        let wake_counter = std::sync::Arc::new(crate::test_util::WakeCounter(std::sync::atomic::AtomicUsize::new(0)));
+        let waker = std::task::Waker::from(wake_counter.clone());
+        let mut cx = std::task::Context::from_waker(&waker);
+
+        // The reader yields once its budget is exhausted, and wakes itself to be polled again.
+        assert!(client.reader.poll(&mut cx).is_pending());
+        assert_eq!(wake_counter.0.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        // The next poll receives the rest, and waits for the socket rather than waking itself again.
+        assert!(client.reader.poll(&mut cx).is_pending());
+        assert_eq!(wake_counter.0.load(std::sync::atomic::Ordering::Relaxed), 1);
 
-        // TODO: Use std::mem::MaybeUninit::slice_assume_init_mut when that is stabilized.
-        let ptr: *mut u8 = read_buf.as_mut_ptr().cast();
-        let read_buf = std::slice::from_raw_parts_mut(ptr, len);
-        read_buf
+        client.expect(&session, &[0xd0, 0].repeat(super::BUDGET + super::BUDGET / 2));
     }
 }
@@ -1,16 +1,84 @@
+// The number of connections accepted each time the acceptor is polled.
+const ACCEPT_BUDGET: usize = 32;
+
+// The epoll data of the acceptor, pending_wake_fd and the HTTP and admin listeners. The connections of each of those
+// listeners use the tokens just below the listener's, see `Service`. Every other event's data is the `ConnectionId` of
+// a connection, which never has these values.
+const ACCEPTOR_TOKEN: u64 = u64::MAX - 1;
+const PENDING_WAKE_TOKEN: u64 = u64::MAX;
+const HTTP_LISTENER_TOKEN: u64 = u64::MAX - 2;
+const ADMIN_LISTENER_TOKEN: u64 = HTTP_LISTENER_TOKEN - 1 - crate::http::MAX_CONNECTIONS as u64;
+
+// The token of the heartbeat the runtime wakes itself with every `TIMEOUT_CHECK_INTERVAL`. It only ever appears in
+// pending_wakes, never as epoll data.
+const HEARTBEAT_TOKEN: u64 = ADMIN_LISTENER_TOKEN - 1 - crate::admin::MAX_CONNECTIONS as u64;
+
+// How often connections are checked for having timed out, such as by stalling writes for longer than
+// `SessionConfig::write_stall_timeout`.
+const TIMEOUT_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 pub struct Runtime {
     acceptor: crate::Acceptor,
-    acceptor_fd: std::os::unix::io::RawFd,
     session: std::rc::Rc<crate::Session>,
-    readers: std::collections::BTreeMap<std::os::unix::io::RawFd, crate::Reader>,
+    readers: crate::slab::SecondaryMap<Connection>,
 
     epoll_fd: std::os::unix::io::RawFd,
     pending_wake_fd: std::os::unix::io::RawFd,
-    pending_wakes: std::rc::Rc<std::cell::RefCell<std::collections::BTreeSet<std::os::unix::io::RawFd>>>,
+    pending_wakes: std::rc::Rc<std::cell::RefCell<Vec<u64>>>,
+
+    acceptor_waker: (std::rc::Rc<Waker>, std::task::Waker),
+
+    // Whether the acceptor's listener is being watched. It isn't while the acceptor is paused.
+    acceptor_watched: bool,
+
+    http: Option<Service<crate::HttpListener>>,
+    admin: Option<Service<crate::AdminListener>>,
+
+    // Sent through pending_wake_fd like any other wake, so that receiving it shows that wakes are being delivered
+    // and handled, for the `HttpListener`'s health checks.
+    heartbeat_waker: std::rc::Rc<Waker>,
+    health: crate::health::Health,
+}
+
+// A listener that the runtime serves besides the acceptor, such as the `HttpListener`.
+pub(crate) trait Listener: std::os::unix::io::AsRawFd {
+    type Connection: ListenerConnection;
+
+    // The name of the listener, for logging.
+    const NAME: &'static str;
+
+    // Returns `Ok(None)` once there are no more connections to accept.
+    fn accept(&self) -> Result<Option<Self::Connection>, crate::Error>;
+}
+
+pub(crate) trait ListenerConnection: std::os::unix::io::AsRawFd {
+    type Peer: std::fmt::Display;
+
+    // The peer of the connection, for logging.
+    fn peer(&self) -> &Self::Peer;
+
+    // When the connection is closed if it's still open.
+    fn deadline(&self) -> std::time::Instant;
+}
+
+// A `Listener` and its connections. Each connection has one of a fixed number of slots, and its token is the one that many
+// below the listener's token. Once every slot is taken, further connections wait in the listener's backlog until one is freed.
+struct Service<L: Listener> {
+    listener: L,
+    token: u64,
+    connections: Vec<Option<L::Connection>>,
+
+    // Set when the listener may have connections to accept, either because it became ready or because a slot was freed.
+    ready: bool,
 }
 
 impl Runtime {
-    pub fn new(acceptor: crate::Acceptor, session: std::rc::Rc<crate::Session>) -> nix::Result<Self> {
+    pub fn new(
+        acceptor: crate::Acceptor,
+        session: std::rc::Rc<crate::Session>,
+        http_listener: Option<crate::HttpListener>,
+        admin_listener: Option<crate::AdminListener>,
+    ) -> Result<Self, crate::Error> {
         let acceptor_fd = std::os::unix::io::AsRawFd::as_raw_fd(&acceptor);
 
         let epoll_fd = nix::sys::epoll::epoll_create1(nix::sys::epoll::EpollCreateFlags::EPOLL_CLOEXEC)?;
@@ -22,210 +90,623 @@ impl Runtime {
             acceptor_fd,
             Some(&mut nix::sys::epoll::EpollEvent::new(
                 nix::sys::epoll::EpollFlags::EPOLLIN | nix::sys::epoll::EpollFlags::EPOLLET,
-                acceptor_fd as _,
+                ACCEPTOR_TOKEN,
+            )),
+        )?;
+
+        // Wakers write to pending_wake_fd, so it must be watched too for sources that were woken (or that yielded after
+        // exhausting their budget) to be polled again.
+        let () = nix::sys::epoll::epoll_ctl(
+            epoll_fd,
+            nix::sys::epoll::EpollOp::EpollCtlAdd,
+            pending_wake_fd,
+            Some(&mut nix::sys::epoll::EpollEvent::new(
+                nix::sys::epoll::EpollFlags::EPOLLIN | nix::sys::epoll::EpollFlags::EPOLLET,
+                PENDING_WAKE_TOKEN,
             )),
         )?;
 
+        let http = http_listener.map(|http_listener| Service::new(epoll_fd, http_listener, HTTP_LISTENER_TOKEN, crate::http::MAX_CONNECTIONS)).transpose()?;
+        let admin = admin_listener.map(|admin_listener| Service::new(epoll_fd, admin_listener, ADMIN_LISTENER_TOKEN, crate::admin::MAX_CONNECTIONS)).transpose()?;
+
+        let pending_wakes: std::rc::Rc<std::cell::RefCell<Vec<u64>>> = Default::default();
+        let acceptor_waker = new_waker(ACCEPTOR_TOKEN, pending_wakes.clone(), pending_wake_fd);
+        let (heartbeat_waker, _) = new_waker(HEARTBEAT_TOKEN, pending_wakes.clone(), pending_wake_fd);
+
+        let health = crate::health::Health::new(std::time::Instant::now());
+
         Ok(Runtime {
             acceptor,
-            acceptor_fd,
             session,
             readers: Default::default(),
 
             epoll_fd,
             pending_wake_fd,
-            pending_wakes: Default::default(),
+            pending_wakes,
+
+            acceptor_waker,
+            acceptor_watched: true,
+
+            http,
+            admin,
+
+            heartbeat_waker,
+            health,
         })
     }
 
-    pub fn run(mut self) -> nix::Result<()> {
-        let mut ready: std::collections::BTreeMap<_, _> = Default::default();
+    // Only returns if the runtime itself fails. Errors of individual connections just close those connections.
+    pub fn run(mut self) -> Result<(), crate::Error> {
+        // The tokens of the acceptor and connections that have become ready. Their readiness flags are accumulated
+        // in acceptor_ready and in their Connection, so that each is only polled once per iteration.
+        let mut ready: Vec<u64> = vec![];
+        let mut acceptor_ready = nix::sys::epoll::EpollFlags::empty();
+
+        let mut next_timeout_check = std::time::Instant::now() + TIMEOUT_CHECK_INTERVAL;
+
+        self.heartbeat_waker.wake_by_ref();
 
         loop {
+            let now = std::time::Instant::now();
+            let next_timer = self.session.poll_timers(now);
+            let next_deadline = next_timer.map_or(next_timeout_check, |next_timer| std::cmp::min(next_timer, next_timeout_check));
+
+            // Rounded up so that the wait doesn't end just short of the deadline and spin until it's due.
+            let timeout = next_deadline.saturating_duration_since(now);
+            let timeout = std::convert::TryInto::try_into((timeout.as_micros() + 999) / 1000).unwrap_or(isize::MAX);
+
             let mut events = [nix::sys::epoll::EpollEvent::empty(); 1024];
-            let num_events = nix::sys::epoll::epoll_wait(self.epoll_fd, &mut events, -1)?;
+            let num_events = nix::sys::epoll::epoll_wait(self.epoll_fd, &mut events, timeout)?;
             let events = &mut events[..num_events];
 
+            let woken_at = std::time::Instant::now();
+            self.session.metrics().epoll_wakeups.inc();
+
+            let readers = &mut self.readers;
+            let mut mark_ready = |token: u64, flags: nix::sys::epoll::EpollFlags| {
+                let ready_flags =
+                    if token == ACCEPTOR_TOKEN {
+                        &mut acceptor_ready
+                    }
+                    else if let Some(connection) = readers.get_mut(crate::ConnectionId::from_u64(token)) {
+                        &mut connection.ready
+                    }
+                    else {
+                        // The connection was unregistered after this event was queued, eg a wake for a connection that has since
+                        // been disconnected, or an event for a connection whose fd has since been reused.
+                        debug!(connection_id = crate::ConnectionId::from_u64(token); "runtime received stale event");
+                        return;
+                    };
+
+                if ready_flags.is_empty() {
+                    ready.push(token);
+                }
+                *ready_flags |= flags;
+            };
+
             for event in events {
-                let fd = event.data() as std::os::unix::io::RawFd;
+                let token = event.data();
 
-                if fd == self.pending_wake_fd {
-                    let mut pending_wakes = self.pending_wakes.try_borrow_mut().expect("runtime could not lock pending_wakes mutex");
-                    for &fd in &*pending_wakes {
-                        *ready.entry(fd).or_insert_with(nix::sys::epoll::EpollFlags::empty) |=
-                            nix::sys::epoll::EpollFlags::EPOLLIN | nix::sys::epoll::EpollFlags::EPOLLOUT;
+                if token == PENDING_WAKE_TOKEN {
+                    // Reset the eventfd counter. EAGAIN just means another event already drained it.
+                    let mut counter = [0_u8; 8];
+                    match nix::unistd::read(self.pending_wake_fd, &mut counter) {
+                        Ok(_) | Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => (),
+                        Err(err) => return Err(err.into()),
                     }
-                    pending_wakes.clear();
+
+                    let mut pending_wakes =
+                        self.pending_wakes.try_borrow_mut()
+                        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("runtime could not lock pending_wakes mutex: {}", err)))?;
+                    for token in pending_wakes.drain(..) {
+                        if token == HEARTBEAT_TOKEN {
+                            self.heartbeat_waker.pending.set(false);
+                            self.health.heartbeat(woken_at);
+                        }
+                        else {
+                            mark_ready(token, nix::sys::epoll::EpollFlags::EPOLLIN | nix::sys::epoll::EpollFlags::EPOLLOUT);
+                        }
+                    }
+                }
+                else if let Some(http) = self.http.as_mut().filter(|http| http.owns(token)) {
+                    let session = &self.session;
+                    let health = &self.health;
+                    http.poll(token, |connection| connection.poll(session, health));
+                }
+                else if let Some(admin) = self.admin.as_mut().filter(|admin| admin.owns(token)) {
+                    let session = &self.session;
+                    let access_list = self.acceptor.access_list();
+                    admin.poll(token, |connection| connection.poll(session, access_list));
                 }
                 else {
-                    *ready.entry(fd).or_insert_with(nix::sys::epoll::EpollFlags::empty) |= event.events();
+                    mark_ready(token, event.events());
                 }
             }
 
-            for (&fd, &flags) in &ready {
-                let waker = new_waker(fd, self.pending_wakes.clone(), self.pending_wake_fd);
-                let mut cx = std::task::Context::from_waker(&waker);
+            if let Some(http) = &mut self.http {
+                http.accept(self.epoll_fd);
+            }
+            if let Some(admin) = &mut self.admin {
+                admin.accept(self.epoll_fd);
+            }
 
-                if fd == self.acceptor_fd {
-                    assert_eq!(flags, nix::sys::epoll::EpollFlags::EPOLLIN);
-                    match self.acceptor.poll(&mut cx) {
-                        std::task::Poll::Ready(Ok(reader)) => {
-                            register_reader(self.epoll_fd, &mut self.readers, reader)?;
-                        },
-                        std::task::Poll::Ready(Err(err)) => {
-                            eprintln!("Acceptor fd {} had err {}", fd, err);
-                        },
-                        std::task::Poll::Pending => (),
+            for token in ready.drain(..) {
+                if token == ACCEPTOR_TOKEN {
+                    let (acceptor_waker, acceptor_task_waker) = &self.acceptor_waker;
+                    acceptor_waker.pending.set(false);
+                    let mut cx = std::task::Context::from_waker(acceptor_task_waker);
+
+                    let flags = std::mem::replace(&mut acceptor_ready, nix::sys::epoll::EpollFlags::empty());
+                    if !flags.contains(nix::sys::epoll::EpollFlags::EPOLLIN) {
+                        warn!("Acceptor became ready but for flags {:?}", flags);
                     }
-                }
-                else if let Some(reader) = self.readers.get_mut(&fd) {
-                    if flags.contains(nix::sys::epoll::EpollFlags::EPOLLIN) {
-                        match reader.poll(&mut cx) {
-                            std::task::Poll::Ready(Ok(())) => (),
-                            std::task::Poll::Ready(Err(err)) => {
-                                eprintln!("Reader fd {} had err {}", fd, err);
-                                unregister_reader(self.epoll_fd, &mut self.readers, fd)?;
+
+                    let mut budget = crate::Budget::new(ACCEPT_BUDGET);
+
+                    // The acceptor is edge-triggered, so drain the backlog rather than accepting a single connection per notification.
+                    while let std::task::Poll::Ready(()) = budget.poll_consume(&mut cx) {
+                        match self.acceptor.poll(&mut cx) {
+                            std::task::Poll::Ready(Ok(reader)) => {
+                                let id = reader.id();
+                                if let Err(err) = register_reader(self.epoll_fd, &mut self.readers, &self.pending_wakes, self.pending_wake_fd, reader) {
+                                    error!(connection_id = id; "Reader could not be registered: {}", err);
+                                    self.session.disconnect(id);
+                                }
                             },
-                            std::task::Poll::Pending => (),
-                        }
-                    }
-                    else if flags.contains(nix::sys::epoll::EpollFlags::EPOLLOUT) {
-                        match self.session.poll_write(&mut cx, fd) {
-                            std::task::Poll::Ready(Ok(())) => (),
                             std::task::Poll::Ready(Err(err)) => {
-                                eprintln!("Writer fd {} had err {}", fd, err);
-                                unregister_reader(self.epoll_fd, &mut self.readers, fd)?;
+                                error!("Acceptor had err {}", err);
+                                break;
                             },
-                            std::task::Poll::Pending => (),
+                            std::task::Poll::Pending => break,
                         }
                     }
-                    else {
-                        panic!("Reader fd {} became ready but for flags {:?}", fd, flags);
+
+                    // Stop watching the listener while the acceptor is paused, since it's woken when it can accept again anyway.
+                    // Watching it again re-arms it, so connections that arrived while paused are still reported.
+                    if self.acceptor.paused() == self.acceptor_watched {
+                        self.acceptor_watched = !self.acceptor.paused();
+                        let events =
+                            if self.acceptor_watched {
+                                nix::sys::epoll::EpollFlags::EPOLLIN | nix::sys::epoll::EpollFlags::EPOLLET
+                            }
+                            else {
+                                nix::sys::epoll::EpollFlags::empty()
+                            };
+                        let () = nix::sys::epoll::epoll_ctl(
+                            self.epoll_fd,
+                            nix::sys::epoll::EpollOp::EpollCtlMod,
+                            std::os::unix::io::AsRawFd::as_raw_fd(&self.acceptor),
+                            Some(&mut nix::sys::epoll::EpollEvent::new(events, ACCEPTOR_TOKEN)),
+                        )?;
                     }
                 }
                 else {
-                    panic!("runtime received event for fd {} that isn't the acceptor or any reader", fd);
+                    let id = crate::ConnectionId::from_u64(token);
+
+                    // The connection may have been unregistered by an earlier event in this same iteration.
+                    let connection = match self.readers.get_mut(id) {
+                        Some(connection) => connection,
+                        None => continue,
+                    };
+
+                    let flags = std::mem::replace(&mut connection.ready, nix::sys::epoll::EpollFlags::empty());
+
+                    connection.waker.pending.set(false);
+                    let mut cx = std::task::Context::from_waker(&connection.task_waker);
+
+                    match poll_reader(&self.session, &mut connection.reader, flags, &mut cx) {
+                        Ok(std::task::Poll::Ready(())) => {
+                            debug!(connection_id = id; "Reader closed");
+                            unregister_reader(self.epoll_fd, &self.session, &mut self.readers, id);
+                        },
+                        Ok(std::task::Poll::Pending) => (),
+                        Err(err) => {
+                            info!(connection_id = id; "Reader had err {}", err);
+                            unregister_reader(self.epoll_fd, &self.session, &mut self.readers, id);
+                        },
+                    }
                 }
             }
 
-            ready.clear();
+            let now = std::time::Instant::now();
+            if now >= next_timeout_check {
+                for (id, err) in self.session.timed_out_clients(now) {
+                    info!(connection_id = id; "Reader had err {}", err);
+                    unregister_reader(self.epoll_fd, &self.session, &mut self.readers, id);
+                }
+
+                if let Some(http) = &mut self.http {
+                    http.close_timed_out(now);
+                    http.accept(self.epoll_fd);
+                }
+                if let Some(admin) = &mut self.admin {
+                    admin.close_timed_out(now);
+                    admin.accept(self.epoll_fd);
+                }
+
+                self.health.update(now, &self.session.buffer_pool_stats(), self.acceptor.paused());
+                self.heartbeat_waker.wake_by_ref();
+
+                next_timeout_check = now + TIMEOUT_CHECK_INTERVAL;
+            }
+
+            self.session.metrics().loop_latency.observe(woken_at.elapsed());
         }
     }
 }
 
-#[derive(Clone)]
-struct Handle {
+struct Connection {
+    reader: crate::Reader,
+    waker: std::rc::Rc<Waker>,
+    task_waker: std::task::Waker,
+
+    // The readiness flags accumulated for this connection since it was last polled.
+    ready: nix::sys::epoll::EpollFlags,
+}
+
+// Each readiness bit is handled independently, since under edge-triggering a bit that is ignored now will not be reported again.
+//
+// Returns `Ok(Ready(()))` once the connection is finished and should be unregistered.
+fn poll_reader(
+    session: &crate::Session,
+    reader: &mut crate::Reader,
+    flags: nix::sys::epoll::EpollFlags,
+    cx: &mut std::task::Context<'_>,
+) -> Result<std::task::Poll<()>, crate::Error> {
+    let fd = std::os::unix::io::AsRawFd::as_raw_fd(reader);
+
+    if flags.contains(nix::sys::epoll::EpollFlags::EPOLLERR) {
+        let err = nix::sys::socket::getsockopt(fd, nix::sys::socket::sockopt::SocketError)?;
+        let err =
+            if err == 0 {
+                std::io::ErrorKind::ConnectionReset.into()
+            }
+            else {
+                std::io::Error::from_raw_os_error(err)
+            };
+        return Err(err.into());
+    }
+
+    if flags.intersects(nix::sys::epoll::EpollFlags::EPOLLIN | nix::sys::epoll::EpollFlags::EPOLLRDHUP | nix::sys::epoll::EpollFlags::EPOLLHUP) {
+        match reader.poll(cx) {
+            std::task::Poll::Ready(Ok(())) | std::task::Poll::Pending => (),
+            std::task::Poll::Ready(Err(err)) => return Err(err),
+        }
+    }
+
+    if flags.contains(nix::sys::epoll::EpollFlags::EPOLLHUP) {
+        // Both halves are shut down, so there's no way to flush any pending writes.
+        return Ok(std::task::Poll::Ready(()));
+    }
+
+    if flags.contains(nix::sys::epoll::EpollFlags::EPOLLOUT) || reader.read_closed() {
+        match session.poll_write(cx, reader.id()) {
+            std::task::Poll::Ready(Ok(())) =>
+                // Once the peer has half-closed the connection, it's done as soon as all pending writes have been flushed.
+                if reader.read_closed() {
+                    return Ok(std::task::Poll::Ready(()));
+                },
+            std::task::Poll::Ready(Err(err)) => return Err(err),
+            std::task::Poll::Pending => (),
+        }
+    }
+
+    Ok(std::task::Poll::Pending)
 }
 
 fn register_reader(
     epoll_fd: std::os::unix::io::RawFd,
-    readers: &mut std::collections::BTreeMap<std::os::unix::io::RawFd, crate::Reader>,
+    readers: &mut crate::slab::SecondaryMap<Connection>,
+    pending_wakes: &std::rc::Rc<std::cell::RefCell<Vec<u64>>>,
+    pending_wake_fd: std::os::unix::io::RawFd,
     reader: crate::Reader,
-) -> nix::Result<()> {
+) -> Result<(), crate::Error> {
+    let id = reader.id();
     let reader_fd = std::os::unix::io::AsRawFd::as_raw_fd(&reader);
     let () = nix::sys::epoll::epoll_ctl(
         epoll_fd,
         nix::sys::epoll::EpollOp::EpollCtlAdd,
         reader_fd,
         Some(&mut nix::sys::epoll::EpollEvent::new(
-            nix::sys::epoll::EpollFlags::EPOLLIN | nix::sys::epoll::EpollFlags::EPOLLOUT | nix::sys::epoll::EpollFlags::EPOLLET,
-            reader_fd as _,
+            nix::sys::epoll::EpollFlags::EPOLLIN | nix::sys::epoll::EpollFlags::EPOLLOUT | nix::sys::epoll::EpollFlags::EPOLLRDHUP | nix::sys::epoll::EpollFlags::EPOLLET,
+            id.to_u64(),
         )),
     )?;
-    readers.insert(reader_fd, reader);
+    let (waker, task_waker) = new_waker(id.to_u64(), pending_wakes.clone(), pending_wake_fd);
+    readers.insert(id, Connection {
+        reader,
+        waker,
+        task_waker,
+        ready: nix::sys::epoll::EpollFlags::empty(),
+    });
     Ok(())
 }
 
 fn unregister_reader(
     epoll_fd: std::os::unix::io::RawFd,
-    readers: &mut std::collections::BTreeMap<std::os::unix::io::RawFd, crate::Reader>,
-    reader_fd: std::os::unix::io::RawFd,
-) -> nix::Result<()> {
-    let () = nix::sys::epoll::epoll_ctl(
+    session: &crate::Session,
+    readers: &mut crate::slab::SecondaryMap<Connection>,
+    id: crate::ConnectionId,
+) {
+    let connection = match readers.remove(id) {
+        Some(connection) => connection,
+        None => return,
+    };
+    let reader_fd = std::os::unix::io::AsRawFd::as_raw_fd(&connection.reader);
+
+    // Closing the socket removes it from the epoll set anyway, so failing to remove it first is harmless.
+    if let Err(err) = nix::sys::epoll::epoll_ctl(
         epoll_fd,
         nix::sys::epoll::EpollOp::EpollCtlDel,
         reader_fd,
         None,
-    )?;
-    readers.remove(&reader_fd);
-    Ok(())
+    ) {
+        warn!(connection_id = id; "Reader could not be unregistered: {}", err);
+    }
+    drop(connection);
+    session.disconnect(id);
 }
 
-fn new_waker(
-    fd: std::os::unix::io::RawFd,
-    pending_wakes: std::rc::Rc<std::cell::RefCell<std::collections::BTreeSet<std::os::unix::io::RawFd>>>,
-    pending_wake_fd: std::os::unix::io::RawFd,
-) -> std::task::Waker {
-    struct Waker {
-        fd: std::os::unix::io::RawFd,
-        pending_wakes: std::rc::Rc<std::cell::RefCell<std::collections::BTreeSet<std::os::unix::io::RawFd>>>,
-        pending_wake_fd: std::os::unix::io::RawFd,
+impl<L: Listener> Service<L> {
+    fn new(epoll_fd: std::os::unix::io::RawFd, listener: L, token: u64, max_connections: usize) -> Result<Self, crate::Error> {
+        let () = nix::sys::epoll::epoll_ctl(
+            epoll_fd,
+            nix::sys::epoll::EpollOp::EpollCtlAdd,
+            std::os::unix::io::AsRawFd::as_raw_fd(&listener),
+            Some(&mut nix::sys::epoll::EpollEvent::new(
+                nix::sys::epoll::EpollFlags::EPOLLIN | nix::sys::epoll::EpollFlags::EPOLLET,
+                token,
+            )),
+        )?;
+
+        Ok(Service {
+            listener,
+            token,
+            connections: std::iter::repeat_with(|| None).take(max_connections).collect(),
+            ready: false,
+        })
     }
 
-    impl Waker {
-        fn into_raw_waker(self: std::rc::Rc<Self>) -> std::task::RawWaker {
-            std::task::RawWaker::new(std::rc::Rc::into_raw(self).cast(), &RAW_WAKER_VTABLE)
-        }
+    // Whether the token is that of the listener or one of its connections.
+    fn owns(&self, token: u64) -> bool {
+        token <= self.token && self.token - token <= self.connections.len() as u64
+    }
 
-        fn wake(self: std::rc::Rc<Self>) {
-            self.wake_by_ref();
+    // Handles an event for the given token, which this service owns. Connections are polled with the given function,
+    // and closed once it returns `Ready`.
+    fn poll(&mut self, token: u64, poll: impl FnOnce(&mut L::Connection) -> std::task::Poll<Result<(), crate::Error>>) {
+        if token == self.token {
+            self.ready = true;
+            return;
         }
 
-        fn wake_by_ref(self: &std::rc::Rc<Self>) {
-            let mut pending_wakes =
-                self.pending_wakes.try_borrow_mut()
-                .map_err(|err| format!("waker {} could not lock pending_wakes mutex: {}", self.fd, err))
-                .unwrap();
-            pending_wakes.insert(self.fd);
-            let written =
-                nix::unistd::write(self.pending_wake_fd, &(1_u64.to_ne_bytes()))
-                .map_err(|err| format!("waker {} could not write to pending_wake_fd: {}", self.fd, err))
-                .unwrap();
-            assert_eq!(written, 8, "waker {} could not write to pending_wake_fd: short write of {} bytes", self.fd, written);
+        #[allow(clippy::cast_possible_truncation)]
+        let slot = &mut self.connections[(self.token - token - 1) as usize];
+
+        // The slot may have been freed, or even reused, since this event was queued. Polling a connection that isn't
+        // actually ready is harmless.
+        if let Some(connection) = slot {
+            match poll(connection) {
+                std::task::Poll::Ready(Ok(())) => (),
+                std::task::Poll::Ready(Err(err)) => debug!(peer_addr = connection.peer(); "{} connection had err {}", L::NAME, err),
+                std::task::Poll::Pending => return,
+            }
+
+            // Closing the socket removes it from the epoll set.
+            *slot = None;
+            self.ready = true;
         }
     }
 
-    const RAW_WAKER_VTABLE: std::task::RawWakerVTable = std::task::RawWakerVTable::new(
-        raw_waker_clone,
-        raw_waker_wake,
-        raw_waker_wake_by_ref,
-        raw_waker_drop,
-    );
+    // Accepts connections into the free slots, until either there are no more connections to accept or no more free slots.
+    // Connections left in the listener's backlog are accepted once a slot is freed.
+    fn accept(&mut self, epoll_fd: std::os::unix::io::RawFd) {
+        if !std::mem::replace(&mut self.ready, false) {
+            return;
+        }
+
+        for (i, slot) in self.connections.iter_mut().enumerate() {
+            if slot.is_some() {
+                continue;
+            }
+
+            let connection = match self.listener.accept() {
+                Ok(Some(connection)) => connection,
+                Ok(None) => break,
+                Err(err) => {
+                    error!("{} listener had err {}", L::NAME, err);
+                    break;
+                },
+            };
+
+            // A newly registered socket that is already readable is reported as such, so the connection is polled as soon as
+            // its first event arrives.
+            if let Err(err) = nix::sys::epoll::epoll_ctl(
+                epoll_fd,
+                nix::sys::epoll::EpollOp::EpollCtlAdd,
+                std::os::unix::io::AsRawFd::as_raw_fd(&connection),
+                Some(&mut nix::sys::epoll::EpollEvent::new(
+                    nix::sys::epoll::EpollFlags::EPOLLIN | nix::sys::epoll::EpollFlags::EPOLLOUT | nix::sys::epoll::EpollFlags::EPOLLRDHUP | nix::sys::epoll::EpollFlags::EPOLLET,
+                    self.token - 1 - i as u64,
+                )),
+            ) {
+                error!("{} connection could not be registered: {}", L::NAME, err);
+                continue;
+            }
+
+            *slot = Some(connection);
+        }
+    }
 
-    unsafe fn raw_waker_clone(data: *const ()) -> std::task::RawWaker {
-        let waker: *const Waker = data.cast();
-        // Wrap in ManuallyDrop so that it isn't dropped.
-        // We don't want to drop it because raw_waker_clone receives &Self, not Self.
-        let waker = std::mem::ManuallyDrop::new(std::rc::Rc::from_raw(waker));
-        let result = std::rc::Rc::clone(&*waker);
-        result.into_raw_waker()
+    fn close_timed_out(&mut self, now: std::time::Instant) {
+        for slot in &mut self.connections {
+            if let Some(connection) = slot.as_ref().filter(|connection| connection.deadline() <= now) {
+                debug!(peer_addr = connection.peer(); "{} connection timed out", L::NAME);
+                *slot = None;
+                self.ready = true;
+            }
+        }
     }
+}
+
+// A waker for the acceptor or a connection. Each is created once and reused every time its source is polled.
+struct Waker {
+    token: u64,
 
-    unsafe fn raw_waker_wake(data: *const ()) {
-        let waker: *const Waker = data.cast();
-        let waker = std::rc::Rc::from_raw(waker);
-        waker.wake()
+    // Whether the token has been pushed to pending_wakes and the source hasn't been polled since,
+    // so that waking it again doesn't push it again.
+    pending: std::cell::Cell<bool>,
+
+    pending_wakes: std::rc::Rc<std::cell::RefCell<Vec<u64>>>,
+    pending_wake_fd: std::os::unix::io::RawFd,
+}
+
+impl Waker {
+    fn into_raw_waker(self: std::rc::Rc<Self>) -> std::task::RawWaker {
+        std::task::RawWaker::new(std::rc::Rc::into_raw(self).cast(), &RAW_WAKER_VTABLE)
     }
 
-    unsafe fn raw_waker_wake_by_ref(data: *const ()) {
-        let waker: *const Waker = data.cast();
-        // Wrap in ManuallyDrop so that it isn't dropped.
-        // We don't want to drop it because raw_waker_wake_by_ref receives &Self, not Self.
-        let waker = std::mem::ManuallyDrop::new(std::rc::Rc::from_raw(waker));
-        waker.wake_by_ref()
+    fn wake(self: std::rc::Rc<Self>) {
+        self.wake_by_ref();
     }
 
-    unsafe fn raw_waker_drop(data: *const ()) {
-        let waker: *const Waker = data.cast();
-        let waker = std::rc::Rc::from_raw(waker);
-        drop(waker)
+    fn wake_by_ref(self: &std::rc::Rc<Self>) {
+        if self.pending.replace(true) {
+            return;
+        }
+
+        let mut pending_wakes = match self.pending_wakes.try_borrow_mut() {
+            Ok(pending_wakes) => pending_wakes,
+            Err(err) => {
+                error!("waker {} could not lock pending_wakes mutex: {}", self.token, err);
+                self.pending.set(false);
+                return;
+            },
+        };
+        pending_wakes.push(self.token);
+
+        // The token is already in pending_wakes, so even if this fails it's still picked up the next time the runtime
+        // drains pending_wakes. EAGAIN in particular just means the counter is saturated, ie pending_wake_fd is already readable.
+        match nix::unistd::write(self.pending_wake_fd, &(1_u64.to_ne_bytes())) {
+            Ok(8) | Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => (),
+            Ok(written) => error!("waker {} could not write to pending_wake_fd: short write of {} bytes", self.token, written),
+            Err(err) => error!("waker {} could not write to pending_wake_fd: {}", self.token, err),
+        }
     }
+}
+
+const RAW_WAKER_VTABLE: std::task::RawWakerVTable = std::task::RawWakerVTable::new(
+    raw_waker_clone,
+    raw_waker_wake,
+    raw_waker_wake_by_ref,
+    raw_waker_drop,
+);
+
+unsafe fn raw_waker_clone(data: *const ()) -> std::task::RawWaker {
+    let waker: *const Waker = data.cast();
+    // Wrap in ManuallyDrop so that it isn't dropped.
+    // We don't want to drop it because raw_waker_clone receives &Self, not Self.
+    let waker = std::mem::ManuallyDrop::new(std::rc::Rc::from_raw(waker));
+    let result = std::rc::Rc::clone(&*waker);
+    result.into_raw_waker()
+}
+
+unsafe fn raw_waker_wake(data: *const ()) {
+    let waker: *const Waker = data.cast();
+    let waker = std::rc::Rc::from_raw(waker);
+    waker.wake();
+}
+
+unsafe fn raw_waker_wake_by_ref(data: *const ()) {
+    let waker: *const Waker = data.cast();
+    // Wrap in ManuallyDrop so that it isn't dropped.
+    // We don't want to drop it because raw_waker_wake_by_ref receives &Self, not Self.
+    let waker = std::mem::ManuallyDrop::new(std::rc::Rc::from_raw(waker));
+    waker.wake_by_ref();
+}
+
+unsafe fn raw_waker_drop(data: *const ()) {
+    let waker: *const Waker = data.cast();
+    let waker = std::rc::Rc::from_raw(waker);
+    drop(waker);
+}
 
+fn new_waker(
+    token: u64,
+    pending_wakes: std::rc::Rc<std::cell::RefCell<Vec<u64>>>,
+    pending_wake_fd: std::os::unix::io::RawFd,
+) -> (std::rc::Rc<Waker>, std::task::Waker) {
     let waker = std::rc::Rc::new(Waker {
-        fd,
+        token,
+        pending: std::cell::Cell::new(false),
         pending_wakes,
         pending_wake_fd,
     });
-    let raw_waker = waker.into_raw_waker();
-    unsafe { std::task::Waker::from_raw(raw_waker) }
+    let raw_waker = waker.clone().into_raw_waker();
+    let task_waker = unsafe { std::task::Waker::from_raw(raw_waker) };
+    (waker, task_waker)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn rdhup_delivers_buffered_data() {
+        let session = crate::test_util::session();
+        let mut client = crate::test_util::TestClient::new(&session);
+
+        // The peer sends its CONNECT and shuts down its write half, so the connection is only reported as read-closed.
+        std::io::Write::write_all(&mut client.stream, &crate::test_util::connect(b"a")).unwrap();
+        client.stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let waker = crate::test_util::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        let result = super::poll_reader(&session, &mut client.reader, nix::sys::epoll::EpollFlags::EPOLLRDHUP, &mut cx);
+        assert!(matches!(result, Ok(std::task::Poll::Ready(()))));
+
+        // The CONNECT was still received, and its CONNACK flushed before the connection was finished.
+        let mut received = [0; 4];
+        std::io::Read::read_exact(&mut client.stream, &mut received).unwrap();
+        assert_eq!(received, crate::test_util::CONNACK_ACCEPTED);
+    }
+
+    #[test]
+    fn hup_delivers_buffered_data() {
+        let session = crate::test_util::session();
+        let mut client = crate::test_util::TestClient::new(&session);
+
+        std::io::Write::write_all(&mut client.stream, &crate::test_util::connect(b"a")).unwrap();
+        client.stream.shutdown(std::net::Shutdown::Both).unwrap();
+
+        let waker = crate::test_util::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        let result = super::poll_reader(&session, &mut client.reader, nix::sys::epoll::EpollFlags::EPOLLHUP, &mut cx);
+        assert!(matches!(result, Ok(std::task::Poll::Ready(()))));
+
+        // The CONNECT was still received, even though nothing can be written back.
+        let client_ids: Vec<_> = session.clients().into_iter().filter_map(|client| client.client_id).collect();
+        assert_eq!(client_ids, ["a"]);
+    }
+
+    #[test]
+    fn repeated_wakes_push_token_once() {
+        let pending_wake_fd = nix::sys::eventfd::eventfd(0, nix::sys::eventfd::EfdFlags::EFD_CLOEXEC | nix::sys::eventfd::EfdFlags::EFD_NONBLOCK).unwrap();
+        let pending_wakes: std::rc::Rc<std::cell::RefCell<Vec<u64>>> = Default::default();
+        let (waker, task_waker) = super::new_waker(5, pending_wakes.clone(), pending_wake_fd);
+
+        task_waker.wake_by_ref();
+        // Waking by value goes through a different function of the waker than waking by reference.
+        #[allow(clippy::waker_clone_wake)]
+        task_waker.clone().wake();
+        waker.wake_by_ref();
+        assert_eq!(*pending_wakes.borrow(), [5]);
+
+        // Once the runtime has taken the token and is about to poll the source, it can be woken again.
+        pending_wakes.borrow_mut().clear();
+        waker.pending.set(false);
+        task_waker.wake_by_ref();
+        task_waker.wake_by_ref();
+        assert_eq!(*pending_wakes.borrow(), [5]);
+
+        nix::unistd::close(pending_wake_fd).unwrap();
+    }
 }
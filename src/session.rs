@@ -1,132 +1,1948 @@
+// The number of writes made to a client each time its writes are polled.
+const WRITE_BUDGET: usize = 64;
+
+// The number of encoded packets gathered into a single vectored write.
+const MAX_GATHERED_WRITES: usize = 64;
+
+// The number of chunks that make up a single encoded packet. See `PendingWrite::chunks`
+const MAX_CHUNKS: usize = 4;
+
+// Once a pooled buffer holds this many bytes of encoded packets, further packets are encoded into a new buffer.
+const COALESCE_LIMIT: usize = 4096;
+
 pub struct Session {
     inner: std::cell::RefCell<SessionInner>,
+    metrics: crate::metrics::Metrics,
+}
+
+pub struct SessionConfig {
+    // Once the publishes queued for a single client add up to more than this many bytes, the broker stops reading from the
+    // clients that publish to it, until its queue drains to client_queue_low_watermark bytes.
+    pub client_queue_high_watermark: usize,
+    pub client_queue_low_watermark: usize,
+
+    // Once the publishes queued for all clients add up to more than this many bytes, the broker stops accepting connections
+    // and stops reading from all clients that have published anything, until the queues drain to queue_low_watermark bytes.
+    pub queue_high_watermark: usize,
+    pub queue_low_watermark: usize,
+
+    // The most publishes that can be waiting to be encoded for a single client, and the most bytes of publishes that can be
+    // queued for it, before slow_consumer_policy is applied to it.
+    pub max_client_queue_len: usize,
+    pub max_client_queue_bytes: usize,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+
+    // A client is disconnected once its writes have been blocked for this long without any bytes being written.
+    // None to never disconnect clients for this.
+    pub write_stall_timeout: Option<std::time::Duration>,
+
+    // The most packets and bytes per second that are read from a single client. Reading from a client that exceeds either
+    // is paused until it's back within the limit.
+    pub max_client_packet_rate: Option<u32>,
+    pub max_client_byte_rate: Option<u32>,
+
+    // A client is disconnected once reading from it has been paused for exceeding its rate limits this many times.
+    // None to never disconnect clients for this.
+    pub max_client_rate_limit_violations: Option<u32>,
+
+    // The largest packet a client can send, including its fixed header. A client that announces a larger packet is
+    // disconnected before any more of it is read. `AcceptorConfig::max_packet_size` overrides it for every client the
+    // acceptor accepts.
+    pub max_packet_size: usize,
+
+    // A client is disconnected if it hasn't sent CONNECT this long after connecting. None to wait indefinitely.
+    pub connect_timeout: Option<std::time::Duration>,
+
+    // The most bytes and levels in a topic name or filter. A client that publishes to a longer topic name is disconnected,
+    // and a subscription to a longer topic filter fails.
+    pub max_topic_len: usize,
+    pub max_topic_levels: usize,
+
+    // Clients that connect with an empty client ID and a clean session are given a unique client ID starting with this.
+    pub generated_client_id_prefix: String,
+
+    // How often the broker's status is published to the $SYS topics. None to not publish them at all.
+    pub sys_interval: Option<std::time::Duration>,
+
+    // The most retained messages, and the most bytes of their topic names and payloads, that are stored at once.
+    // A retained publish from a client that would exceed either is still routed, but not retained.
+    pub max_retained_messages: usize,
+    pub max_retained_bytes: usize,
+}
+
+// What to do with a publish routed to a client whose queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    // Drop the oldest QoS 0 publishes that haven't been encoded yet to make room for it. If there aren't enough of them,
+    // drop the new publish instead.
+    DropOldestAtMostOnce,
+
+    // Drop the new publish.
+    DropNewest,
+
+    // Drop the new publish and disconnect the client.
+    Disconnect,
 }
 
 struct SessionInner {
+    config: SessionConfig,
     buffer_pool: std::rc::Rc<crate::BufferPool>,
-    clients: std::collections::BTreeMap<std::os::unix::io::RawFd, Client>,
+    clients: crate::slab::Slab<Client>,
+
+    // The total size of the publishes queued for all clients.
+    queued_bytes: usize,
+    congested: bool,
+
+    // The wakers of the acceptor and publishers that are blocked until queued_bytes drops to the low watermark,
+    // keyed by the publisher's connection, or None for the acceptor.
+    congestion_wakers: std::collections::HashMap<Option<crate::ConnectionId>, std::task::Waker>,
+
+    // The number of clients connected from each address.
+    connections_per_ip: std::collections::HashMap<std::net::IpAddr, usize>,
+
+    // The waker of the acceptor while it's blocked on the number of connections, to be woken when a client disconnects.
+    accept_waker: Option<std::task::Waker>,
+
+    // The counter that client IDs are generated from, so that each one is unique.
+    generated_client_ids: u64,
+
+    // Wakers to be woken at the given times. The u64 is a sequence number that distinguishes timers for the same time.
+    timers: std::collections::BTreeMap<(std::time::Instant, u64), std::task::Waker>,
+    next_timer: u64,
+
+    // The last retained message of each topic name, to be sent to clients that subscribe to it, and the total size
+    // of their topic names and payloads.
+    retained: std::collections::BTreeMap<String, Retained>,
+    retained_bytes: usize,
+
+    sys_topics: crate::sys::SysTopics,
+}
+
+struct Retained {
+    encoded_publish: std::rc::Rc<crate::EncodedPublish>,
+    qos: mqtt3::proto::QoS,
+}
+
+// Where a routed publish came from.
+#[derive(Clone, Copy)]
+enum Origin {
+    Client(crate::ConnectionId),
+
+    // The $SYS topics, published by the broker itself.
+    Sys,
+
+    // The admin API's publish command.
+    Admin,
+}
+
+// A snapshot of a client, for the admin API.
+pub(crate) struct ClientInfo {
+    pub(crate) id: crate::ConnectionId,
+    pub(crate) client_id: Option<String>,
+    pub(crate) peer_addr: std::net::SocketAddr,
+    pub(crate) keep_alive: Option<std::time::Duration>,
+
+    // The number of packets waiting to be encoded for the client, and the total size of the publishes queued for it.
+    pub(crate) queue_len: usize,
+    pub(crate) queued_bytes: usize,
+
+    pub(crate) dropped_publishes: u64,
+    pub(crate) subscriptions: Vec<(String, mqtt3::proto::QoS)>,
 }
 
+#[allow(clippy::struct_excessive_bools)]
 struct Client {
+    peer_addr: std::net::SocketAddr,
+
+    // Whether the client has sent CONNECT, and if it hasn't, when it will be disconnected for not having done so.
+    connected: bool,
+    connect_deadline: Option<std::time::Instant>,
+
+    // The client ID from the client's CONNECT, or the one it was given if it didn't have one.
+    client_id: Option<String>,
+
+    // Set when the client's CONNECT was refused. It's disconnected as soon as the CONNACK saying so has been written.
+    refused: bool,
+
+    // The keep alive from the client's CONNECT.
+    keep_alive: Option<std::time::Duration>,
+
     writer: crate::Writer,
-    pending_packets: std::collections::VecDeque<mqtt3::proto::Packet>,
-    pending_write: Option<bytes::BytesMut>,
+    pending_packets: PendingPackets,
+
+    // Encoded packets waiting to be written, and the number of bytes of them that have already been written.
+    pending_writes: std::collections::VecDeque<PendingWrite>,
+    pending_write_offset: usize,
+
+    // The waker of the last poll_write, to be woken when more packets are queued for this client.
+    writer_waker: Option<std::task::Waker>,
+
+    subscriptions: std::collections::BTreeMap<String, mqtt3::proto::QoS>,
+    next_packet_identifier: u16,
+
+    // The total size of the publishes queued for this client.
+    queued_bytes: usize,
+    congested: bool,
+
+    // The wakers of publishers that are blocked until this client's queued_bytes drops to the low watermark, keyed by
+    // the publisher's connection.
+    congestion_wakers: std::collections::HashMap<crate::ConnectionId, std::task::Waker>,
+
+    // Whether this client has ever published anything, and so is subject to being blocked when all queues are congested.
+    is_publisher: bool,
+
+    // A congested client that this client's publishes were last routed to.
+    blocked_by: Option<crate::ConnectionId>,
+
+    // The number of publishes routed to this client that were dropped because its queue was full.
+    dropped_publishes: u64,
+
+    // Set when this client's queue overflowed under `SlowConsumerPolicy::Disconnect`. Its next poll_write fails.
+    overflowed: bool,
+
+    // Set when this client was disconnected through the admin API. Its next poll_write fails.
+    kicked: bool,
+
+    // When this client will be disconnected if its blocked writes still haven't made progress.
+    write_deadline: Option<std::time::Instant>,
+}
+
+enum Outgoing {
+    Packet(mqtt3::proto::Packet),
+
+    // A publish routed to this client, shared with every other client it was routed to.
+    Publish(std::rc::Rc<crate::EncodedPublish>, crate::encoded_publish::Header),
+}
+
+// The packets waiting to be encoded for a client, in the order they were queued.
+//
+// QoS 0 publishes are kept apart from every other packet, so that `SlowConsumerPolicy::DropOldestAtMostOnce` can drop the oldest
+// of them without searching the queue for it. Each packet is numbered as it's queued, so that the two are still interleaved
+// in order when they're taken off the front.
+#[derive(Default)]
+struct PendingPackets {
+    at_most_once: std::collections::VecDeque<(u64, std::rc::Rc<crate::EncodedPublish>, crate::encoded_publish::Header)>,
+    others: std::collections::VecDeque<(u64, Outgoing)>,
+    next: u64,
+}
+
+enum PendingWrite {
+    // A pooled buffer containing one or more encoded packets.
+    Buf(bytes::BytesMut),
+
+    Publish(std::rc::Rc<crate::EncodedPublish>, crate::encoded_publish::Header),
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            client_queue_high_watermark: 1024 * 1024,
+            client_queue_low_watermark: 256 * 1024,
+
+            queue_high_watermark: 256 * 1024 * 1024,
+            queue_low_watermark: 192 * 1024 * 1024,
+
+            max_client_queue_len: 10_000,
+            max_client_queue_bytes: 16 * 1024 * 1024,
+            slow_consumer_policy: SlowConsumerPolicy::DropOldestAtMostOnce,
+
+            write_stall_timeout: Some(std::time::Duration::from_secs(30)),
+
+            max_client_packet_rate: Some(10_000),
+            max_client_byte_rate: Some(16 * 1024 * 1024),
+            max_client_rate_limit_violations: None,
+
+            max_packet_size: 1024 * 1024,
+
+            connect_timeout: Some(std::time::Duration::from_secs(10)),
+
+            max_topic_len: 1024,
+            max_topic_levels: 32,
+
+            generated_client_id_prefix: "mqtt-async-".to_owned(),
+
+            sys_interval: Some(std::time::Duration::from_secs(10)),
+
+            max_retained_messages: 10_000,
+            max_retained_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl SessionConfig {
+    fn validate(&self) -> Result<(), crate::Error> {
+        if self.client_queue_low_watermark > self.client_queue_high_watermark {
+            return Err(crate::Error::config("client_queue_low_watermark must not be greater than client_queue_high_watermark"));
+        }
+
+        if self.queue_low_watermark > self.queue_high_watermark {
+            return Err(crate::Error::config("queue_low_watermark must not be greater than queue_high_watermark"));
+        }
+
+        if self.max_client_packet_rate == Some(0) {
+            return Err(crate::Error::config("max_client_packet_rate must not be zero"));
+        }
+
+        if self.max_client_byte_rate == Some(0) {
+            return Err(crate::Error::config("max_client_byte_rate must not be zero"));
+        }
+
+        if self.sys_interval == Some(std::time::Duration::from_secs(0)) {
+            return Err(crate::Error::config("sys_interval must not be zero"));
+        }
+
+        Ok(())
+    }
 }
 
 impl Session {
-    pub fn new(buffer_pool: std::rc::Rc<crate::BufferPool>) -> std::rc::Rc<Self> {
-        std::rc::Rc::new(Session {
+    pub fn new(buffer_pool: std::rc::Rc<crate::BufferPool>, config: SessionConfig) -> Result<std::rc::Rc<Self>, crate::Error> {
+        config.validate()?;
+
+        Ok(std::rc::Rc::new(Session {
             inner: std::cell::RefCell::new(SessionInner {
+                config,
                 buffer_pool,
                 clients: Default::default(),
+
+                queued_bytes: 0,
+                congested: false,
+                congestion_wakers: Default::default(),
+
+                connections_per_ip: Default::default(),
+                accept_waker: None,
+
+                generated_client_ids: 0,
+
+                timers: Default::default(),
+                next_timer: 0,
+
+                retained: Default::default(),
+                retained_bytes: 0,
+
+                sys_topics: crate::sys::SysTopics::new(std::time::Instant::now()),
             }),
-        })
+            metrics: Default::default(),
+        }))
     }
 
-    pub(crate) fn poll_accept_ready(&self, _cx: &mut std::task::Context<'_>) -> std::task::Poll<()> {
-        let mut _inner = self.inner.borrow_mut();
+    pub(crate) fn poll_accept_ready(&self, cx: &mut std::task::Context<'_>, max_connections: Option<usize>) -> std::task::Poll<()> {
+        let mut inner = self.inner.borrow_mut();
+
+        if inner.congested {
+            register_waker(&mut inner.congestion_wakers, None, cx);
+            return std::task::Poll::Pending;
+        }
+
+        if let Some(max_connections) = max_connections {
+            if inner.clients.len() >= max_connections {
+                inner.accept_waker = Some(cx.waker().clone());
+                return std::task::Poll::Pending;
+            }
+        }
+
         std::task::Poll::Ready(())
     }
 
-    pub(crate) fn accept(self: std::rc::Rc<Self>, stream: std::net::TcpStream) -> std::io::Result<crate::Reader> {
+    // The number of clients connected from the given address.
+    pub(crate) fn connections_from(&self, ip: std::net::IpAddr) -> usize {
+        let inner = self.inner.borrow();
+        inner.connections_per_ip.get(&ip).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn accept(
+        self: std::rc::Rc<Self>,
+        stream: std::net::TcpStream,
+        addr: std::net::SocketAddr,
+        max_packet_size: Option<usize>,
+    ) -> Result<crate::Reader, crate::Error> {
         let mut inner = self.inner.borrow_mut();
         let inner = &mut *inner;
 
         let fd = std::os::unix::io::AsRawFd::as_raw_fd(&stream);
-        eprintln!("accepting client {:?}", fd);
 
         stream.set_nonblocking(true)?;
         let stream = std::rc::Rc::new(stream);
 
-        inner.clients.insert(fd, Client {
+        *inner.connections_per_ip.entry(addr.ip()).or_default() += 1;
+
+        let connect_deadline = inner.config.connect_timeout.map(|connect_timeout| std::time::Instant::now() + connect_timeout);
+
+        self.metrics.connections_accepted.inc();
+
+        let id = inner.clients.insert(Client {
+            peer_addr: addr,
+
+            connected: false,
+            connect_deadline,
+
+            client_id: None,
+            refused: false,
+
+            keep_alive: None,
+
             writer: crate::Writer::new(stream.clone()),
             pending_packets: Default::default(),
-            pending_write: None,
+
+            pending_writes: Default::default(),
+            pending_write_offset: 0,
+
+            writer_waker: None,
+
+            subscriptions: Default::default(),
+            next_packet_identifier: 1,
+
+            queued_bytes: 0,
+            congested: false,
+            congestion_wakers: Default::default(),
+
+            is_publisher: false,
+            blocked_by: None,
+
+            dropped_publishes: 0,
+            overflowed: false,
+            kicked: false,
+
+            write_deadline: None,
         });
 
-        let reader = crate::Reader::new(stream, inner.buffer_pool.clone(), self.clone());
+        info!(connection_id = id, peer_addr = addr, fd = fd; "accepting client");
+
+        let max_packet_size = max_packet_size.unwrap_or(inner.config.max_packet_size);
+        let reader = crate::Reader::new(id, stream, inner.buffer_pool.clone(), self.clone(), &inner.config, max_packet_size);
         Ok(reader)
     }
 
-    pub(crate) fn poll_recv_ready(&self, _cx: &mut std::task::Context<'_>, _fd: std::os::unix::io::RawFd) -> std::task::Poll<()> {
-        let mut _inner = self.inner.borrow_mut();
-        std::task::Poll::Ready(())
+    pub(crate) fn poll_recv_ready(&self, cx: &mut std::task::Context<'_>, id: crate::ConnectionId) -> std::task::Poll<Result<(), crate::Error>> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+
+        let client = match inner.clients.get_mut(id) {
+            Some(client) => client,
+            None => return std::task::Poll::Ready(Err(no_such_client(id))),
+        };
+
+        if inner.congested && client.is_publisher {
+            register_waker(&mut inner.congestion_wakers, Some(id), cx);
+            return std::task::Poll::Pending;
+        }
+
+        if let Some(blocked_by) = client.blocked_by {
+            match inner.clients.get_mut(blocked_by) {
+                Some(blocked_by) if blocked_by.congested => {
+                    register_waker(&mut blocked_by.congestion_wakers, id, cx);
+                    return std::task::Poll::Pending;
+                },
+
+                _ => if let Some(client) = inner.clients.get_mut(id) {
+                    client.blocked_by = None;
+                },
+            }
+        }
+
+        std::task::Poll::Ready(Ok(()))
     }
 
-    pub(crate) fn recv(&self, cx: &mut std::task::Context<'_>, fd: std::os::unix::io::RawFd, packet: mqtt3::proto::Packet) -> std::io::Result<()> {
+    pub(crate) fn recv(&self, cx: &mut std::task::Context<'_>, id: crate::ConnectionId, packet: mqtt3::proto::Packet) -> Result<(), crate::Error> {
         let mut inner = self.inner.borrow_mut();
-        eprintln!("fd {}: received {:?}", fd, packet);
+        let inner = &mut *inner;
+
+        let topic_limits = crate::topic::Limits {
+            max_len: inner.config.max_topic_len,
+            max_levels: inner.config.max_topic_levels,
+        };
+
+        // A client ID is generated before the client is borrowed, since it has to be checked against those of all the other clients.
+        // It isn't generated for a second CONNECT, which is rejected below, so that such a CONNECT doesn't use one up.
+        let needs_client_id = match &packet {
+            mqtt3::proto::Packet::Connect(mqtt3::proto::Connect { client_id: mqtt3::proto::ClientId::ServerGenerated, .. }) => true,
+            mqtt3::proto::Packet::Connect(mqtt3::proto::Connect { client_id: mqtt3::proto::ClientId::IdWithCleanSession(requested_client_id), .. }) =>
+                requested_client_id.is_empty(),
+            _ => false,
+        };
+        let generated_client_id =
+            if needs_client_id && inner.clients.get_mut(id).map_or(false, |client| !client.connected && !client.refused) {
+                Some(inner.generate_client_id())
+            }
+            else {
+                None
+            };
+
+        let Client { connected, connect_deadline, client_id, refused, keep_alive, pending_packets, subscriptions, is_publisher, .. } =
+            inner.clients.get_mut(id)
+            .ok_or_else(|| no_such_client(id))?;
+
+        let packet_type = crate::metrics::PacketType::of(&packet);
+        self.metrics.packets_received[packet_type as usize].inc();
 
-        let Client { pending_packets, .. } =
-            inner.clients.get_mut(&fd)
-            .unwrap_or_else(|| panic!("session received recv for fd {} which is not associated with any Client", fd));
+        debug!(
+            connection_id = id,
+            client_id = client_id.as_deref().unwrap_or("-"),
+            packet_type = packet_type;
+            "received {:?}", packet
+        );
+
+        if !*connected && !matches!(packet, mqtt3::proto::Packet::Connect(_)) {
+            return Err(crate::Error::protocol("client sent a packet other than CONNECT before CONNECT"));
+        }
+
+        let mut publish = None;
+        let mut new_subscriptions = vec![];
 
         match packet {
-            mqtt3::proto::Packet::Connect(_) => {
-                pending_packets.push_back(mqtt3::proto::Packet::ConnAck(mqtt3::proto::ConnAck {
+            mqtt3::proto::Packet::Connect(connect) => {
+                if *connected || *refused {
+                    return Err(crate::Error::protocol("client sent a second CONNECT"));
+                }
+
+                *connect_deadline = None;
+                *keep_alive = Some(connect.keep_alive);
+
+                let return_code = match connect.client_id {
+                    mqtt3::proto::ClientId::IdWithCleanSession(requested_client_id) |
+                    mqtt3::proto::ClientId::IdWithExistingSession(requested_client_id) if !requested_client_id.is_empty() => {
+                        info!(connection_id = id, client_id = requested_client_id; "client connected");
+                        *client_id = Some(requested_client_id);
+                        mqtt3::proto::ConnectReturnCode::Accepted
+                    },
+
+                    mqtt3::proto::ClientId::ServerGenerated |
+                    mqtt3::proto::ClientId::IdWithCleanSession(_) => {
+                        let generated_client_id = generated_client_id.expect("a client ID is generated for every CONNECT without one");
+                        info!(connection_id = id, client_id = generated_client_id; "client connected with a generated client ID");
+                        *client_id = Some(generated_client_id);
+                        mqtt3::proto::ConnectReturnCode::Accepted
+                    },
+
+                    // A generated client ID would be of no use to a client that wants its session to persist,
+                    // since it has no way to find out what it was to reconnect with it.
+                    mqtt3::proto::ClientId::IdWithExistingSession(_) => {
+                        warn!(connection_id = id; "refusing CONNECT with an empty client ID and no clean session");
+                        mqtt3::proto::ConnectReturnCode::Refused(mqtt3::proto::ConnectionRefusedReason::IdentifierRejected)
+                    },
+                };
+
+                match return_code {
+                    mqtt3::proto::ConnectReturnCode::Accepted => *connected = true,
+                    mqtt3::proto::ConnectReturnCode::Refused(_) => *refused = true,
+                }
+
+                pending_packets.push_back(Outgoing::Packet(mqtt3::proto::Packet::ConnAck(mqtt3::proto::ConnAck {
                     session_present: false,
-                    return_code: mqtt3::proto::ConnectReturnCode::Refused(mqtt3::proto::ConnectionRefusedReason::NotAuthorized),
-                }));
+                    return_code,
+                })));
+            },
+
+            mqtt3::proto::Packet::PingReq(_) => {
+                pending_packets.push_back(Outgoing::Packet(mqtt3::proto::Packet::PingResp(mqtt3::proto::PingResp)));
+            },
+
+            mqtt3::proto::Packet::Publish(packet) => {
+                crate::topic::validate_topic_name(&packet.topic_name, topic_limits)
+                    .map_err(|err| crate::Error::protocol(format!("client published to an invalid topic name: {}", err)))?;
+
+                // The $SYS topics are only published to by the broker itself. The publish is still acknowledged,
+                // but it isn't routed.
+                let is_sys_topic = packet.topic_name.split('/').next() == Some("$SYS");
+                if is_sys_topic {
+                    warn!(connection_id = id, topic_name = packet.topic_name; "ignoring publish to a $SYS topic");
+                }
+
+                match packet.packet_identifier_dup_qos {
+                    mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce => (),
+
+                    mqtt3::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _) => {
+                        pending_packets.push_back(Outgoing::Packet(mqtt3::proto::Packet::PubAck(mqtt3::proto::PubAck {
+                            packet_identifier,
+                        })));
+                    },
+
+                    mqtt3::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _) => {
+                        pending_packets.push_back(Outgoing::Packet(mqtt3::proto::Packet::PubRec(mqtt3::proto::PubRec {
+                            packet_identifier,
+                        })));
+                    },
+                }
+
+                if !is_sys_topic {
+                    *is_publisher = true;
+                    publish = Some(packet);
+                }
+            },
+
+            mqtt3::proto::Packet::PubRec(mqtt3::proto::PubRec { packet_identifier }) => {
+                pending_packets.push_back(Outgoing::Packet(mqtt3::proto::Packet::PubRel(mqtt3::proto::PubRel {
+                    packet_identifier,
+                })));
+            },
+
+            mqtt3::proto::Packet::PubRel(mqtt3::proto::PubRel { packet_identifier }) => {
+                pending_packets.push_back(Outgoing::Packet(mqtt3::proto::Packet::PubComp(mqtt3::proto::PubComp {
+                    packet_identifier,
+                })));
+            },
+
+            mqtt3::proto::Packet::Subscribe(mqtt3::proto::Subscribe { packet_identifier, subscribe_to }) => {
+                let qos = subscribe_to.into_iter().map(|mqtt3::proto::SubscribeTo { topic_filter, qos }| {
+                    if let Err(err) = crate::topic::validate_topic_filter(&topic_filter, topic_limits) {
+                        warn!(connection_id = id, topic_filter = topic_filter; "rejecting subscription: {}", err);
+                        return mqtt3::proto::SubAckQos::Failure;
+                    }
+
+                    subscriptions.insert(topic_filter.clone(), qos);
+                    new_subscriptions.push((topic_filter, qos));
+                    mqtt3::proto::SubAckQos::Success(qos)
+                }).collect();
+
+                pending_packets.push_back(Outgoing::Packet(mqtt3::proto::Packet::SubAck(mqtt3::proto::SubAck {
+                    packet_identifier,
+                    qos,
+                })));
+            },
+
+            mqtt3::proto::Packet::Unsubscribe(mqtt3::proto::Unsubscribe { packet_identifier, unsubscribe_from }) => {
+                for topic_filter in unsubscribe_from {
+                    subscriptions.remove(&topic_filter);
+                }
+
+                pending_packets.push_back(Outgoing::Packet(mqtt3::proto::Packet::UnsubAck(mqtt3::proto::UnsubAck {
+                    packet_identifier,
+                })));
             },
 
-            _ => (),
+            mqtt3::proto::Packet::Disconnect(_) |
+            mqtt3::proto::Packet::PubAck(_) |
+            mqtt3::proto::Packet::PubComp(_) => (),
+
+            mqtt3::proto::Packet::ConnAck(_) |
+            mqtt3::proto::Packet::PingResp(_) |
+            mqtt3::proto::Packet::SubAck(_) |
+            mqtt3::proto::Packet::UnsubAck(_) =>
+                return Err(crate::Error::protocol("client sent a packet that only a server can send")),
+        }
+
+        if let Some(publish) = publish {
+            inner.route(&self.metrics, Origin::Client(id), publish)?;
         }
 
-        // TODO:
-        // Have to get the BytesMut used for packet back, and put_back() it into the BufferPool.
-        // Do that with the new encoder. For now, just push a new BytesMut back.
-        inner.buffer_pool.put_back(Default::default());
+        // Sent after the SUBACK, which has already been queued.
+        if !new_subscriptions.is_empty() {
+            inner.send_retained(&self.metrics, id, &new_subscriptions);
+        }
+
+        // The buffer the packet was decoded from may no longer be shared once the packet is dropped.
+        inner.buffer_pool.reclaim();
 
-        match inner.poll_write(cx, fd) {
+        match inner.poll_write(&self.metrics, cx, id) {
             std::task::Poll::Ready(result) => result,
             std::task::Poll::Pending => Ok(()),
         }
     }
 
-    pub(crate) fn poll_write(&self, cx: &mut std::task::Context<'_>, fd: std::os::unix::io::RawFd) -> std::task::Poll<std::io::Result<()>> {
+    pub(crate) fn disconnect(&self, id: crate::ConnectionId) {
         let mut inner = self.inner.borrow_mut();
-        inner.poll_write(cx, fd)
-    }
-}
+        let inner = &mut *inner;
 
-impl SessionInner {
-    fn poll_write(&mut self, cx: &mut std::task::Context<'_>, fd: std::os::unix::io::RawFd) -> std::task::Poll<std::io::Result<()>> {
-        let Client { writer, pending_packets, pending_write } =
-            self.clients.get_mut(&fd)
-            .unwrap_or_else(|| panic!("session received poll_write for fd {} which is not associated with any Client", fd));
+        inner.buffer_pool.forget(id);
 
-        loop {
-            if let Some(mut buf) = pending_write.take() {
-                while !buf.is_empty() {
-                    match writer.poll(cx, &mut buf)? {
-                        std::task::Poll::Ready(()) => (),
-                        std::task::Poll::Pending => {
-                            *pending_write = Some(buf);
-                            return std::task::Poll::Pending;
-                        },
-                    }
+        if let Some(Client { peer_addr, client_id, mut pending_packets, pending_writes, congestion_wakers, dropped_publishes, blocked_by, .. }) = inner.clients.remove(id) {
+            if let std::collections::hash_map::Entry::Occupied(mut entry) = inner.connections_per_ip.entry(peer_addr.ip()) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
                 }
+            }
 
-                self.buffer_pool.put_back(buf);
+            if let Some(accept_waker) = inner.accept_waker.take() {
+                accept_waker.wake();
             }
 
-            if let Some(packet) = pending_packets.pop_front() {
-                match self.buffer_pool.poll_take(cx) {
-                    std::task::Poll::Ready(mut buf) => {
-                        mqtt3::proto::encode(packet, &mut buf).map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
-                        *pending_write = Some(buf);
-                    },
-                    std::task::Poll::Pending => {
-                        pending_packets.push_front(packet);
-                        return std::task::Poll::Pending;
-                    },
+            info!(connection_id = id, client_id = client_id.as_deref().unwrap_or("-"), peer_addr = peer_addr; "disconnecting client");
+
+            if dropped_publishes > 0 {
+                warn!(connection_id = id; "dropped {} publishes because the client's queue was full", dropped_publishes);
+            }
+
+            let mut queued_bytes = 0;
+
+            while let Some(pending_packet) = pending_packets.pop_front() {
+                if let Outgoing::Publish(encoded_publish, header) = pending_packet {
+                    queued_bytes += encoded_publish.len(&header);
                 }
             }
-            else {
-                break;
+
+            for pending_write in pending_writes {
+                match pending_write {
+                    PendingWrite::Buf(buf) => inner.buffer_pool.put_back(buf),
+                    PendingWrite::Publish(encoded_publish, header) => queued_bytes += encoded_publish.len(&header),
+                }
+            }
+
+            // Publishers blocked by this client are no longer blocked by it.
+            for (_, waker) in congestion_wakers {
+                waker.wake();
+            }
+
+            // Nor is this client waiting to be woken by anything.
+            inner.congestion_wakers.remove(&Some(id));
+            if let Some(blocked_by) = blocked_by.and_then(|blocked_by| inner.clients.get_mut(blocked_by)) {
+                blocked_by.congestion_wakers.remove(&id);
+            }
+
+            if dequeue(&mut inner.queued_bytes, &mut inner.congested, &mut inner.congestion_wakers, queued_bytes, inner.config.queue_low_watermark) {
+                info!("session queues are no longer congested with {} bytes", inner.queued_bytes);
             }
+
+            inner.buffer_pool.reclaim();
         }
+    }
 
-        std::task::Poll::Ready(Ok(()))
+    pub(crate) fn poll_write(&self, cx: &mut std::task::Context<'_>, id: crate::ConnectionId) -> std::task::Poll<Result<(), crate::Error>> {
+        let mut inner = self.inner.borrow_mut();
+        inner.poll_write(&self.metrics, cx, id)
+    }
+
+    pub(crate) fn metrics(&self) -> &crate::metrics::Metrics {
+        &self.metrics
+    }
+
+    pub(crate) fn gauges(&self) -> crate::metrics::Gauges {
+        let inner = self.inner.borrow();
+
+        let mut subscriptions = 0;
+        for (_, client) in inner.clients.iter() {
+            subscriptions += client.subscriptions.len();
+        }
+
+        crate::metrics::Gauges {
+            connections: inner.clients.len(),
+            subscriptions,
+            queued_bytes: inner.queued_bytes,
+            buffer_pool: inner.buffer_pool.stats(),
+        }
+    }
+
+    pub(crate) fn buffer_pool_stats(&self) -> crate::buffer_pool::BufferPoolStats {
+        self.inner.borrow().buffer_pool.stats()
+    }
+
+    // A snapshot of every client, for the admin API.
+    pub(crate) fn clients(&self) -> Vec<ClientInfo> {
+        let inner = self.inner.borrow();
+
+        inner.clients.iter()
+            .map(|(id, client)| ClientInfo {
+                id,
+                client_id: client.client_id.clone(),
+                peer_addr: client.peer_addr,
+                keep_alive: client.keep_alive,
+                queue_len: client.pending_packets.len(),
+                queued_bytes: client.queued_bytes,
+                dropped_publishes: client.dropped_publishes,
+                subscriptions: client.subscriptions.iter().map(|(topic_filter, &qos)| (topic_filter.clone(), qos)).collect(),
+            })
+            .collect()
+    }
+
+    // Disconnects every client with the given client ID, and returns how many there were.
+    pub(crate) fn kick(&self, client_id: &str) -> usize {
+        let mut inner = self.inner.borrow_mut();
+
+        let mut kicked = 0;
+        for (id, client) in inner.clients.iter_mut() {
+            if client.client_id.as_deref() != Some(client_id) {
+                continue;
+            }
+
+            info!(connection_id = id, client_id = client_id; "disconnecting client through the admin API");
+            client.kicked = true;
+            if let Some(writer_waker) = client.writer_waker.take() {
+                writer_waker.wake();
+            }
+            kicked += 1;
+        }
+
+        kicked
+    }
+
+    // The retained messages, as their topic names, QoS and payloads.
+    pub(crate) fn retained(&self) -> Vec<(String, mqtt3::proto::QoS, bytes::Bytes)> {
+        let inner = self.inner.borrow();
+
+        inner.retained.iter()
+            .map(|(topic_name, retained)| (topic_name.clone(), retained.qos, retained.encoded_publish.payload().clone()))
+            .collect()
+    }
+
+    // Removes the retained messages whose topic names match the given topic filter, and returns how many there were.
+    pub(crate) fn clear_retained(&self, topic_filter: &str) -> usize {
+        let mut inner = self.inner.borrow_mut();
+
+        let inner = &mut *inner;
+        let len = inner.retained.len();
+        let retained_bytes = &mut inner.retained_bytes;
+        inner.retained.retain(|topic_name, retained| {
+            if crate::topic::matches(topic_filter, topic_name) {
+                *retained_bytes -= retained.len(topic_name);
+                false
+            }
+            else {
+                true
+            }
+        });
+        len - inner.retained.len()
+    }
+
+    // Routes a publish made through the admin API, as if a client had published it. Unlike a client's publish, one that
+    // would take the retained messages over their limits is refused.
+    pub(crate) fn inject(&self, publish: mqtt3::proto::Publish) -> Result<(), crate::Error> {
+        let mut inner = self.inner.borrow_mut();
+
+        let topic_limits = crate::topic::Limits {
+            max_len: inner.config.max_topic_len,
+            max_levels: inner.config.max_topic_levels,
+        };
+        crate::topic::validate_topic_name(&publish.topic_name, topic_limits)
+            .map_err(|err| crate::Error::protocol(format!("invalid topic name: {}", err)))?;
+
+        inner.route(&self.metrics, Origin::Admin, publish)
+    }
+
+    // Registers the task to be woken at the given time.
+    pub(crate) fn register_timer(&self, cx: &mut std::task::Context<'_>, deadline: std::time::Instant) {
+        let mut inner = self.inner.borrow_mut();
+        let sequence = inner.next_timer;
+        inner.next_timer = inner.next_timer.wrapping_add(1);
+        inner.timers.insert((deadline, sequence), cx.waker().clone());
+    }
+
+    // Wakes the tasks whose timers have expired and publishes the $SYS topics if they're due. Returns when the next timer
+    // expires or the $SYS topics are next due, whichever is first.
+    pub(crate) fn poll_timers(&self, now: std::time::Instant) -> Option<std::time::Instant> {
+        let mut inner = self.inner.borrow_mut();
+
+        // Every timer before (now, u64::MAX) has expired, so split_off leaves exactly those in the map.
+        let pending = inner.timers.split_off(&(now, u64::MAX));
+        let expired = std::mem::replace(&mut inner.timers, pending);
+        for (_, waker) in expired {
+            waker.wake();
+        }
+
+        let next_sys_topics_update = match inner.config.sys_interval {
+            Some(sys_interval) => {
+                if inner.sys_topics.next_update(sys_interval) <= now {
+                    inner.publish_sys_topics(&self.metrics, now);
+                }
+                Some(inner.sys_topics.next_update(sys_interval))
+            },
+            None => None,
+        };
+
+        let next_timer = inner.timers.keys().next().map(|&(deadline, _)| deadline);
+        match (next_timer, next_sys_topics_update) {
+            (Some(next_timer), Some(next_sys_topics_update)) => Some(std::cmp::min(next_timer, next_sys_topics_update)),
+            (next_timer, next_sys_topics_update) => next_timer.or(next_sys_topics_update),
+        }
+    }
+
+    // Returns the clients that should be disconnected for having timed out, along with why: either their writes have been
+    // blocked since before `SessionConfig::write_stall_timeout` ago, or they haven't sent CONNECT within
+    // `SessionConfig::connect_timeout` of connecting.
+    pub(crate) fn timed_out_clients(&self, now: std::time::Instant) -> Vec<(crate::ConnectionId, crate::Error)> {
+        let mut inner = self.inner.borrow_mut();
+
+        inner.clients.iter_mut()
+            .filter_map(|(id, client)| match (client.write_deadline, client.connect_deadline) {
+                (Some(write_deadline), _) if write_deadline <= now => Some((id, crate::Error::policy("client stalled writing"))),
+                (_, Some(connect_deadline)) if connect_deadline <= now => Some((id, crate::Error::policy("client did not send CONNECT in time"))),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl SessionInner {
+    // Queues the publish for every client with a matching subscription, and if it's retained, replaces the retained message
+    // of its topic with it.
+    //
+    // The publish is only encoded once, and that encoding is shared by all of them.
+    // If this makes any of those clients' queues congested, the publishing client is blocked until that client's queue drains.
+    fn route(
+        &mut self,
+        metrics: &crate::metrics::Metrics,
+        origin: Origin,
+        publish: mqtt3::proto::Publish,
+    ) -> Result<(), crate::Error> {
+        let mqtt3::proto::Publish { packet_identifier_dup_qos, retain, topic_name, payload } = publish;
+
+        let publish_qos = match packet_identifier_dup_qos {
+            mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce => mqtt3::proto::QoS::AtMostOnce,
+            mqtt3::proto::PacketIdentifierDupQoS::AtLeastOnce(_, _) => mqtt3::proto::QoS::AtLeastOnce,
+            mqtt3::proto::PacketIdentifierDupQoS::ExactlyOnce(_, _) => mqtt3::proto::QoS::ExactlyOnce,
+        };
+
+        if retain {
+            let previous_len = self.retained.get(&topic_name).map(|retained| retained.len(&topic_name));
+            let retained_bytes = self.retained_bytes - previous_len.unwrap_or(0);
+
+            if payload.is_empty() {
+                self.retained.remove(&topic_name);
+                self.retained_bytes = retained_bytes;
+            }
+            else {
+                let retained_messages = self.retained.len() + usize::from(previous_len.is_none());
+                let retained_bytes = retained_bytes + topic_name.len() + payload.len();
+                let over_limits = retained_messages > self.config.max_retained_messages || retained_bytes > self.config.max_retained_bytes;
+
+                // The $SYS topics count towards the limits but are always retained, so that clients can't crowd them out.
+                // A client's publish is still routed, but the admin API is told instead.
+                match origin {
+                    Origin::Client(id) if over_limits =>
+                        warn!(connection_id = id; "not retaining publish to {:?}: too many retained messages", topic_name),
+
+                    Origin::Admin if over_limits =>
+                        return Err(crate::Error::resource_exhausted("too many retained messages")),
+
+                    Origin::Client(_) | Origin::Sys | Origin::Admin => {
+                        // Encoded separately from the routed publish with a copy of the payload, so that the retained message
+                        // doesn't pin the pooled buffer that the payload was decoded from.
+                        let encoded_publish = crate::EncodedPublish::new(&topic_name, bytes::Bytes::copy_from_slice(&payload))?;
+                        self.retained.insert(topic_name.clone(), Retained {
+                            encoded_publish: std::rc::Rc::new(encoded_publish),
+                            qos: publish_qos,
+                        });
+                        self.retained_bytes = retained_bytes;
+                    },
+                }
+            }
+        }
+
+        let mut encoded_publish: Option<std::rc::Rc<crate::EncodedPublish>> = None;
+        let mut blocked_by = None;
+        let mut evicted = false;
+
+        for (id, client) in self.clients.iter_mut() {
+            let subscription_qos =
+                client.subscriptions.iter()
+                .filter(|(topic_filter, _)| crate::topic::matches(topic_filter, &topic_name))
+                .map(|(_, &qos)| qos)
+                .max();
+            let qos = match subscription_qos {
+                Some(subscription_qos) => std::cmp::min(subscription_qos, publish_qos),
+                None => continue,
+            };
+
+            let encoded_publish =
+                if let Some(encoded_publish) = &encoded_publish {
+                    encoded_publish
+                }
+                else {
+                    // The payload is a slice of the pooled buffer it was read into, and keeps that whole buffer from being
+                    // reused until every queue has written it. Small payloads are copied out so that a few bytes queued to
+                    // a stalled subscriber can't pin a whole buffer each. Larger ones pin at most twice their length, which
+                    // the queue watermarks already account for well enough.
+                    let payload =
+                        if payload.len() < self.buffer_pool.buffer_capacity() / 2 {
+                            bytes::Bytes::copy_from_slice(&payload)
+                        }
+                        else {
+                            payload.clone()
+                        };
+                    encoded_publish.get_or_insert(std::rc::Rc::new(crate::EncodedPublish::new(&topic_name, payload)?))
+                };
+
+            // Publishes routed to existing subscriptions are never retained.
+            let header = encoded_publish.header(client.packet_identifier_dup_qos(qos), false);
+
+            let (dropped, queued) = client.enqueue(id, &self.config, metrics, encoded_publish, header);
+            if dropped > 0 {
+                evicted = true;
+                if dequeue(&mut self.queued_bytes, &mut self.congested, &mut self.congestion_wakers, dropped, self.config.queue_low_watermark) {
+                    info!("session queues are no longer congested with {} bytes", self.queued_bytes);
+                }
+            }
+
+            if let Some(len) = queued {
+                self.queued_bytes += len;
+
+                if client.queued_bytes > self.config.client_queue_high_watermark {
+                    blocked_by = Some(id);
+                }
+            }
+        }
+
+        if let (Origin::Client(publisher), Some(blocked_by)) = (origin, blocked_by) {
+            if let Some(client) = self.clients.get_mut(publisher) {
+                client.blocked_by = Some(blocked_by);
+            }
+        }
+
+        // The evicted publishes may have been the last to share the buffers their payloads were decoded from.
+        if evicted {
+            self.buffer_pool.reclaim();
+        }
+
+        self.check_congested();
+
+        Ok(())
+    }
+
+    // Queues the retained messages that match any of the given new subscriptions of the client.
+    fn send_retained(&mut self, metrics: &crate::metrics::Metrics, id: crate::ConnectionId, subscriptions: &[(String, mqtt3::proto::QoS)]) {
+        let client = match self.clients.get_mut(id) {
+            Some(client) => client,
+            None => return,
+        };
+
+        let mut evicted = false;
+
+        for (topic_name, retained) in &self.retained {
+            let subscription_qos =
+                subscriptions.iter()
+                .filter(|(topic_filter, _)| crate::topic::matches(topic_filter, topic_name))
+                .map(|&(_, qos)| qos)
+                .max();
+            let qos = match subscription_qos {
+                Some(subscription_qos) => std::cmp::min(subscription_qos, retained.qos),
+                None => continue,
+            };
+
+            let header = retained.encoded_publish.header(client.packet_identifier_dup_qos(qos), true);
+
+            let (dropped, queued) = client.enqueue(id, &self.config, metrics, &retained.encoded_publish, header);
+            if dropped > 0 {
+                evicted = true;
+                if dequeue(&mut self.queued_bytes, &mut self.congested, &mut self.congestion_wakers, dropped, self.config.queue_low_watermark) {
+                    info!("session queues are no longer congested with {} bytes", self.queued_bytes);
+                }
+            }
+
+            if let Some(len) = queued {
+                self.queued_bytes += len;
+            }
+        }
+
+        if evicted {
+            self.buffer_pool.reclaim();
+        }
+
+        self.check_congested();
+    }
+
+    // Generates a client ID from the prefix and the next counter value that no connected client has chosen as its own.
+    fn generate_client_id(&mut self) -> String {
+        loop {
+            let generated_client_id = format!("{}{}", self.config.generated_client_id_prefix, self.generated_client_ids);
+            self.generated_client_ids += 1;
+            if !self.clients.iter().any(|(_, client)| client.client_id.as_deref() == Some(&*generated_client_id)) {
+                return generated_client_id;
+            }
+        }
+    }
+
+    // Publishes the $SYS topics as retained messages.
+    fn publish_sys_topics(&mut self, metrics: &crate::metrics::Metrics, now: std::time::Instant) {
+        let stats = crate::sys::Stats {
+            clients_connected: self.clients.iter().filter(|(_, client)| client.connected).count(),
+            retained: self.retained.len(),
+
+            messages_received: metrics.packets_received.iter().map(crate::metrics::Counter::get).sum(),
+            messages_sent: metrics.packets_sent.iter().map(crate::metrics::Counter::get).sum(),
+            bytes_received: metrics.bytes_received.get(),
+            bytes_sent: metrics.bytes_sent.get(),
+            connections_accepted: metrics.connections_accepted.get(),
+        };
+
+        for (topic_name, payload) in self.sys_topics.update(now, &stats) {
+            let publish = mqtt3::proto::Publish {
+                packet_identifier_dup_qos: mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce,
+                retain: true,
+                topic_name,
+                payload: payload.into(),
+            };
+            if let Err(err) = self.route(metrics, Origin::Sys, publish) {
+                error!("could not publish $SYS topic: {}", err);
+            }
+        }
+    }
+
+    fn check_congested(&mut self) {
+        if !self.congested && self.queued_bytes > self.config.queue_high_watermark {
+            warn!("session queues are congested with {} bytes", self.queued_bytes);
+            self.congested = true;
+        }
+    }
+
+    fn poll_write(
+        &mut self,
+        metrics: &crate::metrics::Metrics,
+        cx: &mut std::task::Context<'_>,
+        id: crate::ConnectionId,
+    ) -> std::task::Poll<Result<(), crate::Error>> {
+        let Client {
+            writer,
+            pending_packets,
+            pending_writes,
+            pending_write_offset,
+            writer_waker,
+            queued_bytes,
+            congested,
+            congestion_wakers,
+            overflowed,
+            kicked,
+            write_deadline,
+            refused,
+            ..
+        } = match self.clients.get_mut(id) {
+            Some(client) => client,
+            None => return std::task::Poll::Ready(Err(no_such_client(id))),
+        };
+
+        if *overflowed {
+            return std::task::Poll::Ready(Err(crate::Error::resource_exhausted("client's queue overflowed")));
+        }
+
+        if *kicked {
+            return std::task::Poll::Ready(Err(crate::Error::policy("client was disconnected through the admin API")));
+        }
+
+        // Registered before anything else so that this client can still be woken to be disconnected while its writes are blocked.
+        match writer_waker {
+            Some(writer_waker) if writer_waker.will_wake(cx.waker()) => (),
+            writer_waker => *writer_waker = Some(cx.waker().clone()),
+        }
+
+        let mut budget = crate::Budget::new(WRITE_BUDGET);
+
+        loop {
+            let mut buffer_pool_pending = false;
+
+            while pending_writes.len() < MAX_GATHERED_WRITES {
+                let packet = match pending_packets.pop_front() {
+                    Some(packet) => packet,
+                    None => break,
+                };
+
+                match packet {
+                    Outgoing::Packet(packet) => {
+                        // Consecutive packets are encoded into the same pooled buffer until it fills up.
+                        let buf = match pending_writes.back_mut() {
+                            Some(PendingWrite::Buf(buf)) if buf.len() < COALESCE_LIMIT => buf,
+                            _ => match self.buffer_pool.poll_take(cx, id) {
+                                std::task::Poll::Ready(buf) => {
+                                    pending_writes.push_back(PendingWrite::Buf(buf));
+                                    match pending_writes.back_mut() {
+                                        Some(PendingWrite::Buf(buf)) => buf,
+                                        _ => unreachable!(),
+                                    }
+                                },
+                                std::task::Poll::Pending => {
+                                    pending_packets.push_front(Outgoing::Packet(packet));
+                                    buffer_pool_pending = true;
+                                    break;
+                                },
+                            },
+                        };
+                        metrics.packets_sent[crate::metrics::PacketType::of(&packet) as usize].inc();
+                        mqtt3::proto::encode(packet, buf).map_err(|err| crate::Error::internal(format!("could not encode packet: {}", err)))?;
+                    },
+
+                    // Already encoded, so it's written straight out of the shared encoding.
+                    Outgoing::Publish(encoded_publish, header) => {
+                        metrics.packets_sent[crate::metrics::PacketType::Publish as usize].inc();
+                        pending_writes.push_back(PendingWrite::Publish(encoded_publish, header));
+                    },
+                }
+            }
+
+            if pending_writes.is_empty() {
+                if buffer_pool_pending {
+                    return std::task::Poll::Pending;
+                }
+
+                break;
+            }
+
+            match budget.poll_consume(cx) {
+                std::task::Poll::Ready(()) => (),
+                std::task::Poll::Pending => return std::task::Poll::Pending,
+            }
+
+            let mut bufs = [std::io::IoSlice::new(&[]); MAX_GATHERED_WRITES * MAX_CHUNKS];
+            let mut num_bufs = 0;
+            let mut skip = *pending_write_offset;
+            for pending_write in &*pending_writes {
+                for chunk in &pending_write.chunks() {
+                    if skip >= chunk.len() {
+                        skip -= chunk.len();
+                        continue;
+                    }
+
+                    bufs[num_bufs] = std::io::IoSlice::new(&chunk[skip..]);
+                    num_bufs += 1;
+                    skip = 0;
+                }
+            }
+
+            let written = match writer.poll(cx, &bufs[..num_bufs])? {
+                std::task::Poll::Ready(written) => written,
+                std::task::Poll::Pending => {
+                    if write_deadline.is_none() {
+                        *write_deadline = self.config.write_stall_timeout.map(|write_stall_timeout| std::time::Instant::now() + write_stall_timeout);
+                    }
+
+                    return std::task::Poll::Pending;
+                },
+            };
+
+            *write_deadline = None;
+
+            metrics.bytes_sent.add(written as u64);
+
+            *pending_write_offset += written;
+            let mut written_publishes = 0;
+            while let Some(pending_write) = pending_writes.front() {
+                let len = pending_write.len();
+                if *pending_write_offset < len {
+                    break;
+                }
+
+                *pending_write_offset -= len;
+                match pending_writes.pop_front() {
+                    Some(PendingWrite::Buf(buf)) => self.buffer_pool.put_back(buf),
+                    Some(PendingWrite::Publish(_, _)) => written_publishes += len,
+                    None => unreachable!(),
+                }
+            }
+
+            if written_publishes > 0 {
+                if dequeue(queued_bytes, congested, congestion_wakers, written_publishes, self.config.client_queue_low_watermark) {
+                    info!(connection_id = id; "queue is no longer congested with {} bytes", queued_bytes);
+                }
+
+                if dequeue(&mut self.queued_bytes, &mut self.congested, &mut self.congestion_wakers, written_publishes, self.config.queue_low_watermark) {
+                    info!("session queues are no longer congested with {} bytes", self.queued_bytes);
+                }
+            }
+
+            // Written publishes have been dropped, so the buffers their payloads were decoded from may no longer be shared.
+            self.buffer_pool.reclaim();
+        }
+
+        if *refused {
+            return std::task::Poll::Ready(Err(crate::Error::policy("client's CONNECT was refused")));
+        }
+
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+fn no_such_client(id: crate::ConnectionId) -> crate::Error {
+    crate::Error::Transport(std::io::Error::new(std::io::ErrorKind::NotConnected, format!("client {} does not exist", id)))
+}
+
+// Discounts publishes that have been written out of, or dropped from, a queue. If the queue was congested and has now drained
+// to its low watermark, wakes everything that was blocked on it and returns true.
+fn dequeue<K>(
+    queued_bytes: &mut usize,
+    congested: &mut bool,
+    congestion_wakers: &mut std::collections::HashMap<K, std::task::Waker>,
+    len: usize,
+    low_watermark: usize,
+) -> bool {
+    *queued_bytes -= len;
+
+    if !*congested || *queued_bytes > low_watermark {
+        return false;
+    }
+
+    *congested = false;
+    for (_, waker) in congestion_wakers.drain() {
+        waker.wake();
+    }
+
+    true
+}
+
+// Registers the task with the given key to be woken once a congested queue drains. A task that's polled again while
+// the queue is congested replaces its waker rather than adding another.
+fn register_waker<K: std::hash::Hash + Eq>(
+    congestion_wakers: &mut std::collections::HashMap<K, std::task::Waker>,
+    key: K,
+    cx: &std::task::Context<'_>,
+) {
+    match congestion_wakers.entry(key) {
+        std::collections::hash_map::Entry::Occupied(mut entry) => if !entry.get().will_wake(cx.waker()) {
+            entry.insert(cx.waker().clone());
+        },
+        std::collections::hash_map::Entry::Vacant(entry) => {
+            entry.insert(cx.waker().clone());
+        },
+    }
+}
+
+impl Retained {
+    // The size of the retained message as counted towards `SessionConfig::max_retained_bytes`.
+    fn len(&self, topic_name: &str) -> usize {
+        topic_name.len() + self.encoded_publish.payload().len()
+    }
+}
+
+impl PendingWrite {
+    fn chunks(&self) -> [&[u8]; MAX_CHUNKS] {
+        match self {
+            PendingWrite::Buf(buf) => [buf, &[], &[], &[]],
+            PendingWrite::Publish(encoded_publish, header) => encoded_publish.chunks(header),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.chunks().iter().map(|chunk| chunk.len()).sum()
+    }
+}
+
+impl Client {
+    fn packet_identifier_dup_qos(&mut self, qos: mqtt3::proto::QoS) -> mqtt3::proto::PacketIdentifierDupQoS {
+        match qos {
+            mqtt3::proto::QoS::AtMostOnce => mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce,
+            mqtt3::proto::QoS::AtLeastOnce => mqtt3::proto::PacketIdentifierDupQoS::AtLeastOnce(self.next_packet_identifier(), false),
+            mqtt3::proto::QoS::ExactlyOnce => mqtt3::proto::PacketIdentifierDupQoS::ExactlyOnce(self.next_packet_identifier(), false),
+        }
+    }
+
+    // Queues a publish for this client, applying `SessionConfig::slow_consumer_policy` if its queue is full.
+    //
+    // Returns the number of bytes of publishes that were dropped from the queue to make room for it, and the size of the publish
+    // if it was queued rather than dropped. Accounting for either in the session's queued_bytes is left to the caller.
+    fn enqueue(
+        &mut self,
+        id: crate::ConnectionId,
+        config: &SessionConfig,
+        metrics: &crate::metrics::Metrics,
+        encoded_publish: &std::rc::Rc<crate::EncodedPublish>,
+        header: crate::encoded_publish::Header,
+    ) -> (usize, Option<usize>) {
+        let len = encoded_publish.len(&header);
+        let mut dropped = 0;
+
+        let is_full = |client: &Client|
+            client.pending_packets.len() >= config.max_client_queue_len ||
+            client.queued_bytes + len > config.max_client_queue_bytes;
+
+        if is_full(self) {
+            if config.slow_consumer_policy == SlowConsumerPolicy::DropOldestAtMostOnce {
+                while is_full(self) {
+                    let (encoded_publish, header) = match self.pending_packets.pop_oldest_at_most_once() {
+                        Some(publish) => publish,
+                        None => break,
+                    };
+
+                    let len = encoded_publish.len(&header);
+                    self.dropped_publishes += 1;
+                    metrics.publishes_dropped.inc();
+                    dropped += len;
+                    if dequeue(&mut self.queued_bytes, &mut self.congested, &mut self.congestion_wakers, len, config.client_queue_low_watermark) {
+                        info!(connection_id = id; "queue is no longer congested with {} bytes", self.queued_bytes);
+                    }
+                }
+            }
+
+            if is_full(self) {
+                self.dropped_publishes += 1;
+                metrics.publishes_dropped.inc();
+
+                if config.slow_consumer_policy == SlowConsumerPolicy::Disconnect && !self.overflowed {
+                    warn!(connection_id = id; "queue is full, disconnecting the client");
+                    self.overflowed = true;
+                    if let Some(writer_waker) = self.writer_waker.take() {
+                        writer_waker.wake();
+                    }
+                }
+
+                return (dropped, None);
+            }
+        }
+
+        self.queued_bytes += len;
+
+        if self.queued_bytes > config.client_queue_high_watermark && !self.congested {
+            warn!(connection_id = id; "queue is congested with {} bytes", self.queued_bytes);
+            self.congested = true;
+        }
+
+        self.pending_packets.push_back(Outgoing::Publish(encoded_publish.clone(), header));
+        metrics.publishes_routed.inc();
+        if let Some(writer_waker) = self.writer_waker.take() {
+            writer_waker.wake();
+        }
+
+        (dropped, Some(len))
+    }
+
+    fn next_packet_identifier(&mut self) -> mqtt3::proto::PacketIdentifier {
+        let packet_identifier =
+            mqtt3::proto::PacketIdentifier::new(self.next_packet_identifier)
+            .expect("next_packet_identifier is never 0");
+        self.next_packet_identifier = self.next_packet_identifier.checked_add(1).unwrap_or(1);
+        packet_identifier
+    }
+}
+
+impl PendingPackets {
+    fn len(&self) -> usize {
+        self.at_most_once.len() + self.others.len()
+    }
+
+    fn push_back(&mut self, packet: Outgoing) {
+        let number = self.next;
+        self.next += 1;
+
+        match packet {
+            Outgoing::Publish(encoded_publish, header) if header.qos() == mqtt3::proto::QoS::AtMostOnce =>
+                self.at_most_once.push_back((number, encoded_publish, header)),
+            packet => self.others.push_back((number, packet)),
+        }
+    }
+
+    // Puts back a packet that was just taken off the front.
+    fn push_front(&mut self, packet: Outgoing) {
+        let front = match (self.at_most_once.front(), self.others.front()) {
+            (Some(&(number, _, _)), Some(&(other_number, _))) => std::cmp::min(number, other_number),
+            (Some(&(number, _, _)), None) | (None, Some(&(number, _))) => number,
+            (None, None) => self.next,
+        };
+
+        // Numbered just before the packet that is now at the front, which the taken packet was numbered before.
+        let number = front.wrapping_sub(1);
+        match packet {
+            Outgoing::Publish(encoded_publish, header) if header.qos() == mqtt3::proto::QoS::AtMostOnce =>
+                self.at_most_once.push_front((number, encoded_publish, header)),
+            packet => self.others.push_front((number, packet)),
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<Outgoing> {
+        let at_most_once_is_older = match (self.at_most_once.front(), self.others.front()) {
+            (Some(&(number, _, _)), Some(&(other_number, _))) => number < other_number,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+
+        if at_most_once_is_older {
+            self.at_most_once.pop_front().map(|(_, encoded_publish, header)| Outgoing::Publish(encoded_publish, header))
+        }
+        else {
+            self.others.pop_front().map(|(_, packet)| packet)
+        }
+    }
+
+    fn pop_oldest_at_most_once(&mut self) -> Option<(std::rc::Rc<crate::EncodedPublish>, crate::encoded_publish::Header)> {
+        self.at_most_once.pop_front().map(|(_, encoded_publish, header)| (encoded_publish, header))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn invalid_config() {
+        for config in [
+            super::SessionConfig { client_queue_high_watermark: 100, client_queue_low_watermark: 101, ..Default::default() },
+            super::SessionConfig { queue_high_watermark: 100, queue_low_watermark: 101, ..Default::default() },
+            super::SessionConfig { max_client_packet_rate: Some(0), ..Default::default() },
+            super::SessionConfig { max_client_byte_rate: Some(0), ..Default::default() },
+            super::SessionConfig { sys_interval: Some(std::time::Duration::from_secs(0)), ..Default::default() },
+        ] {
+            assert!(matches!(config.validate(), Err(crate::Error::Config(_))));
+        }
+
+        assert!(super::SessionConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn connect_is_accepted() {
+        let session = crate::test_util::session();
+        let mut client = crate::test_util::TestClient::new(&session);
+
+        client.send(&crate::test_util::connect(b"a"));
+        client.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+    }
+
+    #[test]
+    fn generated_client_id_skips_connected_clients() {
+        let session = crate::test_util::session();
+
+        let mut client1 = crate::test_util::TestClient::new(&session);
+        client1.send(&crate::test_util::connect(b"mqtt-async-0"));
+        client1.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        let mut client2 = crate::test_util::TestClient::new(&session);
+        client2.send(&crate::test_util::connect(b""));
+        client2.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        let client_ids: Vec<_> = session.clients().into_iter().map(|client| client.client_id.unwrap()).collect();
+        assert_eq!(client_ids, ["mqtt-async-0", "mqtt-async-1"]);
+    }
+
+    #[test]
+    fn packet_before_connect_is_rejected() {
+        let session = crate::test_util::session();
+        let mut client = crate::test_util::TestClient::new(&session);
+
+        let err = client.send_rejected(&[0xc0, 0]);
+        assert!(matches!(err, crate::Error::Protocol(_)));
+    }
+
+    #[test]
+    fn second_connect_is_rejected() {
+        let session = crate::test_util::session();
+        let mut client1 = crate::test_util::TestClient::new(&session);
+        client1.send(&crate::test_util::connect(b"a"));
+        client1.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        let err = client1.send_rejected(&crate::test_util::connect(b""));
+        assert!(matches!(err, crate::Error::Protocol(_)));
+
+        // The rejected CONNECT didn't use up a generated client ID.
+        let mut client2 = crate::test_util::TestClient::new(&session);
+        client2.send(&crate::test_util::connect(b""));
+        client2.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        assert!(session.clients().into_iter().any(|client| client.client_id.as_deref() == Some("mqtt-async-0")));
+    }
+
+    #[test]
+    fn server_only_packet_is_rejected() {
+        let session = crate::test_util::session();
+        let mut client = crate::test_util::TestClient::new(&session);
+        client.send(&crate::test_util::connect(b"a"));
+        client.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        let err = client.send_rejected(&crate::test_util::CONNACK_ACCEPTED);
+        assert!(matches!(err, crate::Error::Protocol(_)));
+    }
+
+    #[test]
+    fn connect_timeout() {
+        let session = crate::test_util::session();
+        let idle = crate::test_util::TestClient::new(&session);
+        let mut client = crate::test_util::TestClient::new(&session);
+        client.send(&crate::test_util::connect(b"a"));
+        client.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        let connect_timeout = super::SessionConfig::default().connect_timeout.unwrap();
+        let now = std::time::Instant::now();
+        assert!(session.timed_out_clients(now).is_empty());
+
+        // Only the client that never sent a CONNECT times out.
+        let timed_out = session.timed_out_clients(now + connect_timeout);
+        assert_eq!(timed_out.len(), 1);
+        assert_eq!(timed_out[0].0, idle.reader.id());
+        assert!(matches!(timed_out[0].1, crate::Error::Policy(_)));
+    }
+
+    #[test]
+    fn pingreq() {
+        let session = crate::test_util::session();
+        let mut client = crate::test_util::TestClient::new(&session);
+        client.send(&crate::test_util::connect(b"a"));
+        client.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        client.send(&[0xc0, 0]);
+        client.expect(&session, &[0xd0, 0]);
+    }
+
+    #[test]
+    fn congested_subscriber_blocks_publisher() {
+        let config = super::SessionConfig {
+            client_queue_high_watermark: 20,
+            client_queue_low_watermark: 0,
+            sys_interval: None,
+            ..Default::default()
+        };
+        let session = super::Session::new(crate::BufferPool::new(Default::default()).unwrap(), config).unwrap();
+
+        let mut subscriber = crate::test_util::TestClient::new(&session);
+        subscriber.send(&crate::test_util::connect(b"s"));
+        subscriber.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        subscriber.send(&[0x82, 8, 0, 1, 0, 3, b't', b'/', b'x', 0]);
+        subscriber.expect(&session, &[0x90, 3, 0, 1, 0]);
+
+        let mut publisher = crate::test_util::TestClient::new(&session);
+        publisher.send(&crate::test_util::connect(b"p"));
+        publisher.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        // The third publish takes the subscriber's queue over its high watermark, so the publisher isn't read from
+        // until the subscriber's queue drains.
+        let publish = [0x30, 7, 0, 3, b't', b'/', b'x', b'h', b'i'];
+        std::io::Write::write_all(&mut publisher.stream, &[publish, publish, publish, publish].concat()).unwrap();
+
+        let wake_counter = std::sync::Arc::new(crate::test_util::WakeCounter(std::sync::atomic::AtomicUsize::new(0)));
+        let waker = std::task::Waker::from(wake_counter.clone());
+        let mut cx = std::task::Context::from_waker(&waker);
+        assert!(publisher.reader.poll(&mut cx).is_pending());
+        assert!(publisher.reader.poll(&mut cx).is_pending());
+
+        // However many times it's polled, the publisher is only registered once.
+        assert_eq!(session.inner.borrow_mut().clients.get_mut(subscriber.reader.id()).unwrap().congestion_wakers.len(), 1);
+        assert_eq!(wake_counter.0.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+        subscriber.expect(&session, &[publish, publish, publish].concat());
+        assert_eq!(wake_counter.0.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert!(session.inner.borrow_mut().clients.get_mut(subscriber.reader.id()).unwrap().congestion_wakers.is_empty());
+
+        // Once woken, the publisher's last publish is read and routed.
+        assert!(publisher.reader.poll(&mut cx).is_pending());
+        subscriber.expect(&session, &publish);
+    }
+
+    #[test]
+    fn publish_is_routed_to_subscribers() {
+        let session = crate::test_util::session();
+
+        let mut subscriber1 = crate::test_util::TestClient::new(&session);
+        subscriber1.send(&crate::test_util::connect(b"s1"));
+        subscriber1.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        subscriber1.send(&[0x82, 8, 0, 1, 0, 3, b't', b'/', b'#', 1]);
+        subscriber1.expect(&session, &[0x90, 3, 0, 1, 1]);
+
+        let mut subscriber2 = crate::test_util::TestClient::new(&session);
+        subscriber2.send(&crate::test_util::connect(b"s2"));
+        subscriber2.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        subscriber2.send(&[0x82, 8, 0, 9, 0, 3, b't', b'/', b'+', 0]);
+        subscriber2.expect(&session, &[0x90, 3, 0, 9, 0]);
+
+        let mut publisher = crate::test_util::TestClient::new(&session);
+        publisher.send(&crate::test_util::connect(b"p"));
+        publisher.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        // QoS 1 is acknowledged, and delivered at the lower of its QoS and each subscription's.
+        publisher.send(&[0x32, 9, 0, 3, b't', b'/', b'x', 0, 5, b'h', b'i']);
+        publisher.expect(&session, &[0x40, 2, 0, 5]);
+        subscriber1.expect(&session, &[0x32, 9, 0, 3, b't', b'/', b'x', 0, 1, b'h', b'i']);
+        subscriber2.expect(&session, &[0x30, 7, 0, 3, b't', b'/', b'x', b'h', b'i']);
+
+        // QoS 2 gets a PUBREC, and each delivery to a subscriber gets its own next packet identifier.
+        publisher.send(&[0x34, 9, 0, 3, b't', b'/', b'y', 0, 6, b'h', b'o']);
+        publisher.expect(&session, &[0x50, 2, 0, 6]);
+        subscriber1.expect(&session, &[0x32, 9, 0, 3, b't', b'/', b'y', 0, 2, b'h', b'o']);
+    }
+
+    #[test]
+    fn small_publishes_to_stalled_subscriber_do_not_pin_buffers() {
+        let buffer_pool = crate::BufferPool::new(crate::BufferPoolConfig {
+            capacity: 4,
+            buffer_capacity: 512,
+            ..Default::default()
+        }).unwrap();
+        let config = super::SessionConfig {
+            sys_interval: None,
+            ..Default::default()
+        };
+        let session = super::Session::new(buffer_pool, config).unwrap();
+
+        let mut subscriber = crate::test_util::TestClient::new(&session);
+        subscriber.send(&crate::test_util::connect(b"s"));
+        subscriber.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        subscriber.send(&[0x82, 8, 0, 1, 0, 3, b't', b'/', b'x', 0]);
+        subscriber.expect(&session, &[0x90, 3, 0, 1, 0]);
+
+        let mut publisher = crate::test_util::TestClient::new(&session);
+        publisher.send(&crate::test_util::connect(b"p"));
+        publisher.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        // Each publish is read into a buffer of its own, and queued to the subscriber, which isn't written to until the end.
+        let publish = [0x30, 7, 0, 3, b't', b'/', b'x', b'h', b'i'];
+        for _ in 0..20 {
+            publisher.send(&publish);
+        }
+        assert_eq!(session.buffer_pool_stats().free, 4);
+
+        subscriber.expect(&session, &[publish; 20].concat());
+    }
+
+    #[test]
+    fn partial_packet_does_not_hold_pooled_buffer() {
+        let session = crate::test_util::session();
+        let mut client = crate::test_util::TestClient::new(&session);
+        client.send(&crate::test_util::connect(b"a"));
+        client.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        let free = session.buffer_pool_stats().free;
+
+        // A QoS 1 publish with a payload too large to be stashed, sent in two halves.
+        let mut publish = vec![0x32, 0xcf, 0x01, 0, 3, b't', b'/', b'x', 0, 1];
+        publish.resize(3 + 0xcf, b'p');
+        let (first, second) = publish.split_at(100);
+
+        client.send(first);
+        assert_eq!(session.buffer_pool_stats().free, free);
+
+        client.send(second);
+        client.expect(&session, &[0x40, 2, 0, 1]);
+        assert_eq!(session.buffer_pool_stats().free, free);
+    }
+
+    #[test]
+    fn evicted_publish_wakes_buffer_waiter() {
+        let buffer_pool = crate::BufferPool::new(crate::BufferPoolConfig {
+            capacity: 1,
+            buffer_capacity: 512,
+            ..Default::default()
+        }).unwrap();
+        let config = super::SessionConfig {
+            max_client_queue_len: 1,
+            sys_interval: None,
+            ..Default::default()
+        };
+        let session = super::Session::new(buffer_pool, config).unwrap();
+
+        let mut subscriber = crate::test_util::TestClient::new(&session);
+        subscriber.send(&crate::test_util::connect(b"s"));
+        subscriber.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        subscriber.send(&[0x82, 8, 0, 1, 0, 3, b't', b'/', b'x', 0]);
+        subscriber.expect(&session, &[0x90, 3, 0, 1, 0]);
+
+        let mut publisher = crate::test_util::TestClient::new(&session);
+        publisher.send(&crate::test_util::connect(b"p"));
+        publisher.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+
+        // The payload is large enough to be queued straight out of the pool's only buffer, so the publisher is left waiting
+        // for a buffer to read its next packet into.
+        let mut publish = vec![0x30, 0xb5, 0x02, 0, 3, b't', b'/', b'x'];
+        publish.resize(3 + 0x135, b'p');
+        std::io::Write::write_all(&mut publisher.stream, &publish).unwrap();
+        let wake_counter = std::sync::Arc::new(crate::test_util::WakeCounter(std::sync::atomic::AtomicUsize::new(0)));
+        let waker = std::task::Waker::from(wake_counter.clone());
+        let mut cx = std::task::Context::from_waker(&waker);
+        assert!(publisher.reader.poll(&mut cx).is_pending());
+        assert_eq!(session.buffer_pool_stats().free, 0);
+        assert_eq!(session.buffer_pool_stats().waiting, 1);
+
+        // Another publish evicts the queued one from the subscriber's full queue, which frees the buffer.
+        session.inject(mqtt3::proto::Publish {
+            packet_identifier_dup_qos: mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce,
+            retain: false,
+            topic_name: "t/x".to_owned(),
+            payload: b"hi".to_vec().into(),
+        }).unwrap();
+        assert_eq!(wake_counter.0.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(session.buffer_pool_stats().free, 1);
+
+        subscriber.expect(&session, &[0x30, 7, 0, 3, b't', b'/', b'x', b'h', b'i']);
+    }
+
+    // Connects a subscriber with room for two packets in its queue, and publishes "a", "b", "c" and "d" to it, all at QoS 0
+    // except for "b". The subscriber isn't written to in between, so its queue overflows.
+    fn slow_consumer(slow_consumer_policy: super::SlowConsumerPolicy) -> (std::rc::Rc<super::Session>, crate::test_util::TestClient) {
+        let config = super::SessionConfig {
+            max_client_queue_len: 2,
+            slow_consumer_policy,
+            sys_interval: None,
+            ..Default::default()
+        };
+        let session = super::Session::new(crate::BufferPool::new(Default::default()).unwrap(), config).unwrap();
+
+        let mut subscriber = crate::test_util::TestClient::new(&session);
+        subscriber.send(&crate::test_util::connect(b"s"));
+        subscriber.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        subscriber.send(&[0x82, 8, 0, 1, 0, 3, b't', b'/', b'x', 1]);
+        subscriber.expect(&session, &[0x90, 3, 0, 1, 1]);
+
+        let mut publisher = crate::test_util::TestClient::new(&session);
+        publisher.send(&crate::test_util::connect(b"p"));
+        publisher.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        publisher.send(&[
+            &publish_at_most_once(b'a')[..],
+            &[0x32, 8, 0, 3, b't', b'/', b'x', 0, 1, b'b'],
+            &publish_at_most_once(b'c'),
+            &publish_at_most_once(b'd'),
+        ].concat());
+
+        (session, subscriber)
+    }
+
+    fn publish_at_most_once(payload: u8) -> [u8; 8] {
+        [0x30, 6, 0, 3, b't', b'/', b'x', payload]
+    }
+
+    fn dropped_publishes(session: &super::Session) -> u64 {
+        session.clients().into_iter().find(|client| client.client_id.as_deref() == Some("s")).unwrap().dropped_publishes
+    }
+
+    #[test]
+    fn slow_consumer_drop_oldest_at_most_once() {
+        let (session, mut subscriber) = slow_consumer(super::SlowConsumerPolicy::DropOldestAtMostOnce);
+
+        // "a" and then "c" made room for the publishes after them, and the QoS 1 publish kept its place in the queue.
+        subscriber.expect(&session, &[&[0x32, 8, 0, 3, b't', b'/', b'x', 0, 1, b'b'][..], &publish_at_most_once(b'd')].concat());
+        assert_eq!(dropped_publishes(&session), 2);
+    }
+
+    #[test]
+    fn slow_consumer_drop_newest() {
+        let (session, mut subscriber) = slow_consumer(super::SlowConsumerPolicy::DropNewest);
+
+        subscriber.expect(&session, &[&publish_at_most_once(b'a')[..], &[0x32, 8, 0, 3, b't', b'/', b'x', 0, 1, b'b']].concat());
+        assert_eq!(dropped_publishes(&session), 2);
+    }
+
+    #[test]
+    fn slow_consumer_disconnect() {
+        let (session, subscriber) = slow_consumer(super::SlowConsumerPolicy::Disconnect);
+
+        assert_eq!(dropped_publishes(&session), 2);
+
+        let waker = crate::test_util::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        assert!(matches!(
+            session.poll_write(&mut cx, subscriber.reader.id()),
+            std::task::Poll::Ready(Err(crate::Error::ResourceExhausted(_))),
+        ));
+    }
+
+    // Connects a subscriber that never reads, and publishes to it until its writes block.
+    fn stalled_subscriber(session: &std::rc::Rc<super::Session>) -> crate::test_util::TestClient {
+        let mut subscriber = crate::test_util::TestClient::new(session);
+        subscriber.send(&crate::test_util::connect(b"s"));
+        subscriber.expect(session, &crate::test_util::CONNACK_ACCEPTED);
+        subscriber.send(&[0x82, 8, 0, 1, 0, 3, b't', b'/', b'x', 0]);
+        subscriber.expect(session, &[0x90, 3, 0, 1, 0]);
+
+        let waker = crate::test_util::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        let payload = bytes::Bytes::from(vec![0_u8; 64 * 1024]);
+        for _ in 0..1024 {
+            session.inject(mqtt3::proto::Publish {
+                packet_identifier_dup_qos: mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce,
+                retain: false,
+                topic_name: "t/x".to_owned(),
+                payload: payload.clone(),
+            }).unwrap();
+
+            match session.poll_write(&mut cx, subscriber.reader.id()) {
+                std::task::Poll::Ready(Ok(())) => (),
+                std::task::Poll::Ready(Err(err)) => panic!("writes failed: {}", err),
+                std::task::Poll::Pending => return subscriber,
+            }
+        }
+
+        panic!("writes never blocked");
+    }
+
+    #[test]
+    fn stalled_writes_time_out() {
+        let session = crate::test_util::session();
+        let subscriber = stalled_subscriber(&session);
+
+        let write_stall_timeout = super::SessionConfig::default().write_stall_timeout.unwrap();
+        let now = std::time::Instant::now();
+        assert!(session.timed_out_clients(now).is_empty());
+
+        let timed_out = session.timed_out_clients(now + write_stall_timeout);
+        assert_eq!(timed_out.len(), 1);
+        assert_eq!(timed_out[0].0, subscriber.reader.id());
+        assert!(matches!(timed_out[0].1, crate::Error::Policy(_)));
+    }
+
+    #[test]
+    fn write_progress_resets_stall_timeout() {
+        let session = crate::test_util::session();
+        let mut subscriber = stalled_subscriber(&session);
+
+        let write_stall_timeout = super::SessionConfig::default().write_stall_timeout.unwrap();
+        let deadline = std::time::Instant::now() + write_stall_timeout;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        // Once the subscriber reads some of what was written, the next write makes progress, even if it then blocks again.
+        let mut received = vec![0; 1024 * 1024];
+        std::io::Read::read_exact(&mut subscriber.stream, &mut received).unwrap();
+        let waker = crate::test_util::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        assert!(!matches!(session.poll_write(&mut cx, subscriber.reader.id()), std::task::Poll::Ready(Err(_))));
+
+        assert!(session.timed_out_clients(deadline).is_empty());
+    }
+
+    #[test]
+    fn max_packet_size() {
+        let config = super::SessionConfig {
+            max_packet_size: 100,
+            sys_interval: None,
+            ..Default::default()
+        };
+        let session = super::Session::new(crate::BufferPool::new(Default::default()).unwrap(), config).unwrap();
+
+        // A QoS 1 publish of exactly 100 bytes, including its two-byte fixed header.
+        let mut publish = vec![0x32, 98, 0, 3, b't', b'/', b'x', 0, 1];
+        publish.resize(100, b'p');
+
+        let mut client1 = crate::test_util::TestClient::new(&session);
+        client1.send(&crate::test_util::connect(b"a"));
+        client1.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        client1.send(&publish);
+        client1.expect(&session, &[0x40, 2, 0, 1]);
+
+        // A packet one byte larger is rejected from its fixed header alone, without waiting for the rest of it.
+        let mut client2 = crate::test_util::TestClient::new(&session);
+        client2.send(&crate::test_util::connect(b"b"));
+        client2.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        let err = client2.send_rejected(&[0x32, 99]);
+        assert!(matches!(err, crate::Error::Policy(_)));
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_truncation)]
+    fn partial_writes_resume_mid_chunk() {
+        let session = crate::test_util::session();
+        let mut subscriber = crate::test_util::TestClient::new(&session);
+        subscriber.send(&crate::test_util::connect(b"s"));
+        subscriber.expect(&session, &crate::test_util::CONNACK_ACCEPTED);
+        subscriber.send(&[0x82, 8, 0, 1, 0, 3, b't', b'/', b'x', 0]);
+        subscriber.expect(&session, &[0x90, 3, 0, 1, 0]);
+
+        let waker = crate::test_util::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+
+        // Publishes of varying sizes, so that the writes that the socket only partly accepts end at all sorts of offsets within
+        // and between the chunks of the queued publishes. The subscriber only reads whenever its socket is full.
+        let mut expected = vec![];
+        let mut received = vec![];
+        let mut blocked = 0;
+        for i in 0..40_000_usize {
+            let payload: Vec<u8> = (0..i % 1000).map(|j| (i + j) as u8).collect();
+
+            let remaining_length = 5 + payload.len();
+            expected.push(0x30);
+            if remaining_length < 0x80 {
+                expected.push(remaining_length as u8);
+            }
+            else {
+                expected.extend_from_slice(&[(remaining_length % 0x80) as u8 | 0x80, (remaining_length / 0x80) as u8]);
+            }
+            expected.extend_from_slice(&[0, 3, b't', b'/', b'x']);
+            expected.extend_from_slice(&payload);
+
+            session.inject(mqtt3::proto::Publish {
+                packet_identifier_dup_qos: mqtt3::proto::PacketIdentifierDupQoS::AtMostOnce,
+                retain: false,
+                topic_name: "t/x".to_owned(),
+                payload: payload.into(),
+            }).unwrap();
+
+            if session.poll_write(&mut cx, subscriber.reader.id()).is_pending() {
+                blocked += 1;
+                let mut buf = [0; 4096];
+                let read = std::io::Read::read(&mut subscriber.stream, &mut buf).unwrap();
+                received.extend_from_slice(&buf[..read]);
+            }
+        }
+        assert!(blocked > 0);
+
+        let mut buf = vec![0; 64 * 1024];
+        while received.len() < expected.len() {
+            assert!(!matches!(session.poll_write(&mut cx, subscriber.reader.id()), std::task::Poll::Ready(Err(_))));
+            let read = std::io::Read::read(&mut subscriber.stream, &mut buf).unwrap();
+            received.extend_from_slice(&buf[..read]);
+        }
+        assert!(received == expected);
     }
 }
@@ -0,0 +1,229 @@
+// Identifies a connection. Connection IDs are reused after the connection is removed, but with a new generation,
+// so that an ID that outlived its connection (such as one in a stale epoll event, or in a waker) never refers to
+// a different connection that happened to reuse the same slot or fd.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct ConnectionId {
+    index: u32,
+    generation: u32,
+}
+
+impl ConnectionId {
+    pub(crate) fn to_u64(self) -> u64 {
+        (u64::from(self.generation) << 32) | u64::from(self.index)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn from_u64(raw: u64) -> Self {
+        ConnectionId {
+            index: raw as u32,
+            generation: (raw >> 32) as u32,
+        }
+    }
+
+    fn index(self) -> usize {
+        self.index as usize
+    }
+}
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.index, self.generation)
+    }
+}
+
+// A table of values with O(1) insertion, lookup and removal, that allocates a new `ConnectionId` for each inserted value.
+pub(crate) struct Slab<T> {
+    entries: Vec<Entry<T>>,
+    free: Vec<u32>,
+}
+
+enum Entry<T> {
+    Occupied { generation: u32, value: T },
+    Vacant { generation: u32 },
+}
+
+impl<T> Slab<T> {
+    pub(crate) fn insert(&mut self, value: T) -> ConnectionId {
+        if let Some(index) = self.free.pop() {
+            let entry = &mut self.entries[index as usize];
+            let generation = match entry {
+                Entry::Vacant { generation } => generation.wrapping_add(1),
+                Entry::Occupied { .. } => unreachable!("free list contains occupied entry {}", index),
+            };
+            *entry = Entry::Occupied { generation, value };
+            ConnectionId { index, generation }
+        }
+        else {
+            let index = std::convert::TryInto::try_into(self.entries.len()).expect("too many entries");
+            self.entries.push(Entry::Occupied { generation: 0, value });
+            ConnectionId { index, generation: 0 }
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, id: ConnectionId) -> Option<&mut T> {
+        match self.entries.get_mut(id.index()) {
+            Some(Entry::Occupied { generation, value }) if *generation == id.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: ConnectionId) -> Option<T> {
+        let entry = self.entries.get_mut(id.index())?;
+        match entry {
+            Entry::Occupied { generation, .. } if *generation == id.generation => (),
+            _ => return None,
+        }
+
+        let entry = std::mem::replace(entry, Entry::Vacant { generation: id.generation });
+        self.free.push(id.index);
+
+        match entry {
+            Entry::Occupied { value, .. } => Some(value),
+            Entry::Vacant { .. } => unreachable!(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len() - self.free.len()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (ConnectionId, &T)> {
+        self.entries.iter().enumerate().filter_map(|(index, entry)| match entry {
+            #[allow(clippy::cast_possible_truncation)]
+            Entry::Occupied { generation, value } => Some((ConnectionId { index: index as u32, generation: *generation }, value)),
+            Entry::Vacant { .. } => None,
+        })
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = (ConnectionId, &mut T)> {
+        self.entries.iter_mut().enumerate().filter_map(|(index, entry)| match entry {
+            #[allow(clippy::cast_possible_truncation)]
+            Entry::Occupied { generation, value } => Some((ConnectionId { index: index as u32, generation: *generation }, value)),
+            Entry::Vacant { .. } => None,
+        })
+    }
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Self {
+        Slab {
+            entries: vec![],
+            free: vec![],
+        }
+    }
+}
+
+// A table of values keyed by `ConnectionId`s that were allocated by a `Slab`, with O(1) insertion, lookup and removal.
+pub(crate) struct SecondaryMap<T> {
+    entries: Vec<Option<(u32, T)>>,
+}
+
+impl<T> SecondaryMap<T> {
+    pub(crate) fn insert(&mut self, id: ConnectionId, value: T) {
+        let index = id.index();
+        if index >= self.entries.len() {
+            self.entries.resize_with(index + 1, || None);
+        }
+        self.entries[index] = Some((id.generation, value));
+    }
+
+    pub(crate) fn get_mut(&mut self, id: ConnectionId) -> Option<&mut T> {
+        match self.entries.get_mut(id.index()) {
+            Some(Some((generation, value))) if *generation == id.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: ConnectionId) -> Option<T> {
+        let entry = self.entries.get_mut(id.index())?;
+        match entry {
+            Some((generation, _)) if *generation == id.generation => entry.take().map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+impl<T> Default for SecondaryMap<T> {
+    fn default() -> Self {
+        SecondaryMap {
+            entries: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn u64_round_trip() {
+        for &(index, generation) in &[(0, 0), (1, 0), (0, 1), (7, 3), (u32::MAX, 0), (0, u32::MAX), (u32::MAX, u32::MAX)] {
+            let id = super::ConnectionId { index, generation };
+            assert_eq!(super::ConnectionId::from_u64(id.to_u64()), id);
+        }
+
+        for &raw in &[0, 1, 1 << 32, u64::MAX - 1, u64::MAX] {
+            assert_eq!(super::ConnectionId::from_u64(raw).to_u64(), raw);
+        }
+    }
+
+    #[test]
+    fn reuse_bumps_generation() {
+        let mut slab: super::Slab<&str> = Default::default();
+        let a = slab.insert("a");
+        let b = slab.insert("b");
+        assert_eq!(slab.len(), 2);
+
+        assert_eq!(slab.remove(a), Some("a"));
+        let c = slab.insert("c");
+        assert_eq!(c.index, a.index);
+        assert_eq!(c.generation, a.generation + 1);
+        assert_ne!(c, a);
+
+        assert_eq!(slab.get_mut(b).copied(), Some("b"));
+        assert_eq!(slab.get_mut(c).copied(), Some("c"));
+        assert_eq!(slab.len(), 2);
+
+        let ids: Vec<_> = slab.iter().map(|(id, &value)| (id, value)).collect();
+        assert_eq!(ids, [(c, "c"), (b, "b")]);
+    }
+
+    #[test]
+    fn stale_id_is_rejected() {
+        let mut slab: super::Slab<&str> = Default::default();
+        let a = slab.insert("a");
+        assert_eq!(slab.remove(a), Some("a"));
+
+        // The slot is vacant...
+        assert_eq!(slab.get_mut(a), None);
+        assert_eq!(slab.remove(a), None);
+
+        // ... and once it's reused, the old ID doesn't refer to the new value.
+        let b = slab.insert("b");
+        assert_eq!(slab.get_mut(a), None);
+        assert_eq!(slab.remove(a), None);
+        assert_eq!(slab.get_mut(b).copied(), Some("b"));
+        assert_eq!(slab.len(), 1);
+
+        // Nor does an ID that was never allocated.
+        assert_eq!(slab.get_mut(super::ConnectionId { index: 1, generation: 0 }), None);
+    }
+
+    #[test]
+    fn secondary_map_rejects_stale_id() {
+        let mut slab: super::Slab<()> = Default::default();
+        let mut map: super::SecondaryMap<&str> = Default::default();
+
+        let a = slab.insert(());
+        map.insert(a, "a");
+        slab.remove(a);
+        let b = slab.insert(());
+
+        assert_eq!(map.get_mut(b), None);
+        assert_eq!(map.remove(b), None);
+        assert_eq!(map.get_mut(a).copied(), Some("a"));
+
+        map.insert(b, "b");
+        assert_eq!(map.get_mut(a), None);
+        assert_eq!(map.remove(b), Some("b"));
+        assert_eq!(map.get_mut(b), None);
+    }
+}
@@ -0,0 +1,189 @@
+// The broker's status topics under "$SYS/broker/", which the session publishes as retained messages every
+// `SessionConfig::sys_interval`.
+//
+// Load averages are exponentially-weighted moving averages of the rate of a counter per minute, over the last 1, 5 and
+// 15 minutes, updated each time the topics are published.
+pub(crate) struct SysTopics {
+    started_at: std::time::Instant,
+
+    // When the topics were last published, if they have been.
+    updated_at: Option<std::time::Instant>,
+
+    messages_received: LoadAverage,
+    messages_sent: LoadAverage,
+    bytes_received: LoadAverage,
+    bytes_sent: LoadAverage,
+    connections: LoadAverage,
+}
+
+// The values of the broker's status that the topics are published from.
+pub(crate) struct Stats {
+    pub(crate) clients_connected: usize,
+    pub(crate) retained: usize,
+
+    // Totals since the broker started.
+    pub(crate) messages_received: u64,
+    pub(crate) messages_sent: u64,
+    pub(crate) bytes_received: u64,
+    pub(crate) bytes_sent: u64,
+    pub(crate) connections_accepted: u64,
+}
+
+// The windows of the load averages, in seconds, and the names of their topics.
+const LOAD_AVERAGE_WINDOWS: [(f64, &str); 3] = [(60., "1min"), (300., "5min"), (900., "15min")];
+
+#[derive(Default)]
+struct LoadAverage {
+    // The value of the counter when the averages were last updated.
+    last: u64,
+
+    averages: [f64; LOAD_AVERAGE_WINDOWS.len()],
+}
+
+impl SysTopics {
+    pub(crate) fn new(started_at: std::time::Instant) -> Self {
+        SysTopics {
+            started_at,
+            updated_at: None,
+
+            messages_received: Default::default(),
+            messages_sent: Default::default(),
+            bytes_received: Default::default(),
+            bytes_sent: Default::default(),
+            connections: Default::default(),
+        }
+    }
+
+    // When the topics should next be published. They're first published as soon as the broker starts.
+    pub(crate) fn next_update(&self, interval: std::time::Duration) -> std::time::Instant {
+        self.updated_at.map_or(self.started_at, |updated_at| updated_at + interval)
+    }
+
+    // Updates the load averages, and returns the topic names and payloads to publish.
+    pub(crate) fn update(&mut self, now: std::time::Instant, stats: &Stats) -> Vec<(String, String)> {
+        let elapsed = self.updated_at.map_or(std::time::Duration::from_secs(0), |updated_at| now.saturating_duration_since(updated_at));
+        self.updated_at = Some(now);
+
+        self.messages_received.update(stats.messages_received, elapsed);
+        self.messages_sent.update(stats.messages_sent, elapsed);
+        self.bytes_received.update(stats.bytes_received, elapsed);
+        self.bytes_sent.update(stats.bytes_sent, elapsed);
+        self.connections.update(stats.connections_accepted, elapsed);
+
+        let mut topics = vec![
+            ("$SYS/broker/version".to_owned(), format!("mqtt-async version {}", env!("CARGO_PKG_VERSION"))),
+            ("$SYS/broker/uptime".to_owned(), format!("{} seconds", now.saturating_duration_since(self.started_at).as_secs())),
+            ("$SYS/broker/clients/connected".to_owned(), stats.clients_connected.to_string()),
+            ("$SYS/broker/messages/received".to_owned(), stats.messages_received.to_string()),
+            ("$SYS/broker/messages/sent".to_owned(), stats.messages_sent.to_string()),
+            ("$SYS/broker/bytes/received".to_owned(), stats.bytes_received.to_string()),
+            ("$SYS/broker/bytes/sent".to_owned(), stats.bytes_sent.to_string()),
+            ("$SYS/broker/retained messages/count".to_owned(), stats.retained.to_string()),
+        ];
+
+        for (name, load_average) in &[
+            ("messages/received", &self.messages_received),
+            ("messages/sent", &self.messages_sent),
+            ("bytes/received", &self.bytes_received),
+            ("bytes/sent", &self.bytes_sent),
+            ("connections", &self.connections),
+        ] {
+            for ((_, window_name), average) in LOAD_AVERAGE_WINDOWS.iter().zip(&load_average.averages) {
+                topics.push((format!("$SYS/broker/load/{}/{}", name, window_name), format!("{:.2}", average)));
+            }
+        }
+
+        topics
+    }
+}
+
+impl LoadAverage {
+    fn update(&mut self, value: u64, elapsed: std::time::Duration) {
+        let delta = value.wrapping_sub(self.last);
+        self.last = value;
+
+        let elapsed = elapsed.as_secs_f64();
+        if elapsed <= 0. {
+            return;
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let rate = delta as f64 * 60. / elapsed;
+
+        for ((window, _), average) in LOAD_AVERAGE_WINDOWS.iter().zip(&mut self.averages) {
+            let decay = (-elapsed / window).exp();
+            *average = *average * decay + rate * (1. - decay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn load_average_first_update() {
+        let mut load_average = super::LoadAverage::default();
+
+        // Without any elapsed time there's no rate yet, only the counter's starting value.
+        load_average.update(1000, std::time::Duration::from_secs(0));
+        assert_eq!(load_average.last, 1000);
+        assert!(load_average.averages.iter().all(|&average| average == 0.));
+    }
+
+    #[test]
+    fn load_average_decays_towards_rate() {
+        let mut load_average = super::LoadAverage::default();
+
+        // 60 in one minute moves each average towards 60 per minute by 1 - e^(-60 / window).
+        load_average.update(60, std::time::Duration::from_secs(60));
+        for (&(window, _), &average) in super::LOAD_AVERAGE_WINDOWS.iter().zip(&load_average.averages) {
+            let expected = 60. * (1. - (-60. / window).exp());
+            assert!((average - expected).abs() < 1e-9, "{} != {}", average, expected);
+        }
+        assert!(load_average.averages[0] > load_average.averages[1]);
+        assert!(load_average.averages[1] > load_average.averages[2]);
+    }
+
+    #[test]
+    fn load_average_converges_to_steady_rate() {
+        let mut load_average = super::LoadAverage::default();
+
+        // 50 every 10 seconds is 300 per minute.
+        let mut value = 0;
+        for _ in 0..2000 {
+            value += 50;
+            load_average.update(value, std::time::Duration::from_secs(10));
+        }
+        for &average in &load_average.averages {
+            assert!((average - 300.).abs() < 1e-6, "{}", average);
+        }
+    }
+
+    #[test]
+    fn update_topics() {
+        let started_at = std::time::Instant::now();
+        let mut sys_topics = super::SysTopics::new(started_at);
+        let interval = std::time::Duration::from_secs(10);
+        assert_eq!(sys_topics.next_update(interval), started_at);
+
+        let stats = super::Stats {
+            clients_connected: 3,
+            retained: 4,
+            messages_received: 5,
+            messages_sent: 6,
+            bytes_received: 7,
+            bytes_sent: 8,
+            connections_accepted: 9,
+        };
+        let now = started_at + std::time::Duration::from_secs(42);
+        let topics = sys_topics.update(now, &stats);
+        assert_eq!(sys_topics.next_update(interval), now + interval);
+
+        assert_eq!(topics.len(), 8 + 5 * 3);
+        assert!(topics.iter().all(|(topic_name, _)| topic_name.starts_with("$SYS/broker/")));
+        let get = |name: &str| topics.iter().find(|(topic_name, _)| topic_name == name).map(|(_, payload)| &**payload);
+        assert_eq!(get("$SYS/broker/uptime"), Some("42 seconds"));
+        assert_eq!(get("$SYS/broker/clients/connected"), Some("3"));
+        assert_eq!(get("$SYS/broker/retained messages/count"), Some("4"));
+        assert_eq!(get("$SYS/broker/load/connections/1min"), Some("0.00"));
+    }
+}
@@ -0,0 +1,97 @@
+// Fixtures shared by the tests of several modules.
+
+// A client connected to the session over loopback, whose packets are read by polling its Reader directly.
+pub(crate) struct TestClient {
+    pub(crate) stream: std::net::TcpStream,
+    pub(crate) reader: crate::Reader,
+}
+
+impl TestClient {
+    pub(crate) fn new(session: &std::rc::Rc<crate::Session>) -> Self {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+
+        // Otherwise a small packet sent right after another can be held back until the first one is acknowledged.
+        stream.set_nodelay(true).unwrap();
+
+        let (server_stream, addr) = listener.accept().unwrap();
+        let reader = session.clone().accept(server_stream, addr, None).unwrap();
+
+        TestClient {
+            stream,
+            reader,
+        }
+    }
+
+    // Sends the packet and has the session receive it, along with anything else the client sent before it.
+    pub(crate) fn send(&mut self, packet: &[u8]) {
+        std::io::Write::write_all(&mut self.stream, packet).unwrap();
+
+        // The packet is on loopback, so it's readable as soon as it's been written.
+        let waker = noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        assert!(self.reader.poll(&mut cx).is_pending());
+    }
+
+    // Sends the packet and has the session receive it, and returns the error that the session rejected it with.
+    pub(crate) fn send_rejected(&mut self, packet: &[u8]) -> crate::Error {
+        std::io::Write::write_all(&mut self.stream, packet).unwrap();
+
+        let waker = noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        match self.reader.poll(&mut cx) {
+            std::task::Poll::Ready(Err(err)) => err,
+            result => panic!("packet was not rejected: {:?}", result),
+        }
+    }
+
+    pub(crate) fn expect(&mut self, session: &crate::Session, packet: &[u8]) {
+        let waker = noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        assert!(matches!(session.poll_write(&mut cx, self.reader.id()), std::task::Poll::Ready(Ok(()))));
+
+        let mut received = vec![0; packet.len()];
+        std::io::Read::read_exact(&mut self.stream, &mut received).unwrap();
+        assert_eq!(received, packet);
+    }
+}
+
+pub(crate) fn noop_waker() -> std::task::Waker {
+    const RAW_WAKER_VTABLE: std::task::RawWakerVTable = std::task::RawWakerVTable::new(
+        |_| std::task::RawWaker::new(std::ptr::null(), &RAW_WAKER_VTABLE),
+        |_| (),
+        |_| (),
+        |_| (),
+    );
+    unsafe { std::task::Waker::from_raw(std::task::RawWaker::new(std::ptr::null(), &RAW_WAKER_VTABLE)) }
+}
+
+// Counts how many times a task is woken.
+pub(crate) struct WakeCounter(pub(crate) std::sync::atomic::AtomicUsize);
+
+impl std::task::Wake for WakeCounter {
+    fn wake(self: std::sync::Arc<Self>) {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+// A session with the default configs, except that the $SYS topics aren't published, so that they don't get in the way of
+// the packets a test expects.
+pub(crate) fn session() -> std::rc::Rc<crate::Session> {
+    let config = crate::SessionConfig {
+        sys_interval: None,
+        ..Default::default()
+    };
+    crate::Session::new(crate::BufferPool::new(Default::default()).unwrap(), config).unwrap()
+}
+
+// A CONNECT with a clean session and the given client ID.
+pub(crate) fn connect(client_id: &[u8]) -> Vec<u8> {
+    #[allow(clippy::cast_possible_truncation)]
+    let mut packet = vec![0x10, (12 + client_id.len()) as u8, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 60, 0, client_id.len() as u8];
+    packet.extend_from_slice(client_id);
+    packet
+}
+
+pub(crate) const CONNACK_ACCEPTED: [u8; 4] = [0x20, 2, 0, 0];
@@ -0,0 +1,101 @@
+// A rate limit. The bucket holds up to one second's worth of tokens and refills continuously at the given rate.
+//
+// Tokens can be consumed past zero, in which case the bucket is in debt until it refills back to zero. This lets a caller
+// that only finds out how much it used after the fact, like a read of an unknown number of bytes, still be limited.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: std::time::Instant,
+}
+
+impl TokenBucket {
+    // The rate must not be zero, since a bucket that never refills would never get out of debt.
+    pub(crate) fn new(rate: u32) -> Self {
+        debug_assert_ne!(rate, 0);
+
+        let rate = f64::from(rate);
+
+        TokenBucket {
+            rate,
+            tokens: rate,
+            refilled_at: std::time::Instant::now(),
+        }
+    }
+
+    // Consumes a token if there is one.
+    pub(crate) fn try_consume(&mut self) -> bool {
+        self.refill(std::time::Instant::now());
+
+        if self.tokens < 1. {
+            return false;
+        }
+
+        self.tokens -= 1.;
+        true
+    }
+
+    pub(crate) fn consume(&mut self, tokens: usize) {
+        #[allow(clippy::cast_precision_loss)]
+        let tokens = tokens as f64;
+        self.tokens -= tokens;
+    }
+
+    // Returns when the bucket will be out of debt, or None if it isn't in debt.
+    pub(crate) fn ready_at(&mut self) -> Option<std::time::Instant> {
+        self.refill(std::time::Instant::now());
+
+        if self.tokens >= 0. {
+            return None;
+        }
+
+        Some(self.refilled_at + std::time::Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+
+    fn refill(&mut self, now: std::time::Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn try_consume_until_empty() {
+        let mut bucket = super::TokenBucket::new(3);
+
+        assert!(bucket.try_consume());
+        assert!(bucket.try_consume());
+        assert!(bucket.try_consume());
+        assert!(!bucket.try_consume());
+    }
+
+    #[test]
+    fn refill_is_capped_at_rate() {
+        let mut bucket = super::TokenBucket::new(10);
+        let start = bucket.refilled_at;
+
+        bucket.consume(5);
+        bucket.refill(start + std::time::Duration::from_millis(200));
+        assert!((bucket.tokens - 7.).abs() < 1e-9);
+
+        bucket.refill(start + std::time::Duration::from_secs(60));
+        assert!((bucket.tokens - 10.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ready_at_once_out_of_debt() {
+        let mut bucket = super::TokenBucket::new(100);
+        assert_eq!(bucket.ready_at(), None);
+
+        // 50 tokens of debt take half a second to refill at 100 per second.
+        bucket.consume(150);
+        let ready_at = bucket.ready_at().expect("bucket is in debt");
+        let wait = ready_at.duration_since(bucket.refilled_at);
+        assert!(wait <= std::time::Duration::from_millis(500));
+        assert!(wait > std::time::Duration::from_millis(490));
+
+        bucket.refill(ready_at);
+        assert!(bucket.tokens.abs() < 1e-9);
+    }
+}
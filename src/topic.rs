@@ -0,0 +1,196 @@
+// Returns whether the given topic name matches the given topic filter, ie whether a publish to the topic name
+// should be routed to a subscription with the topic filter.
+//
+// The arms that match are kept apart, since they match for different reasons.
+#[allow(clippy::match_same_arms)]
+pub(crate) fn matches(topic_filter: &str, topic_name: &str) -> bool {
+    // Topic names starting with '$', like the $SYS topics, are reserved for the broker's own use, so wildcards at the start
+    // of a topic filter don't match them. They can only be subscribed to by topic filters that start with '$' too.
+    if topic_name.starts_with('$') && topic_filter.starts_with(['#', '+']) {
+        return false;
+    }
+
+    let mut topic_filter_levels = topic_filter.split('/');
+    let mut topic_name_levels = topic_name.split('/');
+
+    loop {
+        match (topic_filter_levels.next(), topic_name_levels.next()) {
+            // The multi-level wildcard also matches the parent level, so "a/#" matches "a"
+            (Some("#"), _) => return true,
+
+            (Some("+"), Some(_)) => (),
+
+            (Some(topic_filter_level), Some(topic_name_level)) =>
+                if topic_filter_level != topic_name_level {
+                    return false;
+                },
+
+            (None, None) => return true,
+
+            (Some(_), None) | (None, Some(_)) => return false,
+        }
+    }
+}
+
+// The limits that topic names and filters are validated against, besides the rules of the MQTT spec.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Limits {
+    // The most bytes in a topic name or filter.
+    pub(crate) max_len: usize,
+
+    // The most levels in a topic name or filter.
+    pub(crate) max_levels: usize,
+}
+
+// Returns why the given topic name is invalid, if it is.
+pub(crate) fn validate_topic_name(topic_name: &str, limits: Limits) -> Result<(), &'static str> {
+    validate(topic_name, limits)?;
+
+    if topic_name.contains(['+', '#']) {
+        return Err("topic name contains a wildcard");
+    }
+
+    Ok(())
+}
+
+// Returns why the given topic filter is invalid, if it is.
+pub(crate) fn validate_topic_filter(topic_filter: &str, limits: Limits) -> Result<(), &'static str> {
+    validate(topic_filter, limits)?;
+
+    let mut levels = topic_filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        match level {
+            "#" if levels.peek().is_some() => return Err("topic filter has a multi-level wildcard that is not its last level"),
+            "#" | "+" => (),
+            level if level.contains(['+', '#']) => return Err("topic filter has a wildcard that is not a whole level"),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+// The rules common to topic names and filters.
+fn validate(topic: &str, limits: Limits) -> Result<(), &'static str> {
+    if topic.is_empty() {
+        return Err("topic is empty");
+    }
+
+    // Topics are length-prefixed with a u16 on the wire.
+    if topic.len() > std::cmp::min(limits.max_len, usize::from(u16::MAX)) {
+        return Err("topic is too long");
+    }
+
+    if topic.contains('\0') {
+        return Err("topic contains a NUL character");
+    }
+
+    if topic.split('/').count() > limits.max_levels {
+        return Err("topic has too many levels");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn matches_dollar_topics() {
+        assert!(!super::matches("#", "$SYS/broker/uptime"));
+        assert!(!super::matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(!super::matches("+/#", "$SYS/broker/uptime"));
+
+        assert!(super::matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(super::matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+        assert!(super::matches("$SYS/broker/uptime", "$SYS/broker/uptime"));
+
+        // Only a '$' at the start of the topic name is special.
+        assert!(super::matches("#", "a/$b"));
+        assert!(super::matches("a/+", "a/$b"));
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(super::matches("sport/tennis", "sport/tennis"));
+        assert!(!super::matches("sport/tennis", "sport/tennis/player1"));
+        assert!(!super::matches("sport/tennis/player1", "sport/tennis"));
+
+        // The multi-level wildcard matches any number of levels, including none.
+        assert!(super::matches("sport/#", "sport"));
+        assert!(super::matches("sport/#", "sport/"));
+        assert!(super::matches("sport/#", "sport/tennis/player1"));
+        assert!(!super::matches("sport/#", "sports"));
+        assert!(super::matches("#", "sport"));
+        assert!(super::matches("#", "/"));
+
+        // The single-level wildcard matches exactly one level, which can be empty.
+        assert!(super::matches("sport/+", "sport/tennis"));
+        assert!(super::matches("sport/+", "sport/"));
+        assert!(!super::matches("sport/+", "sport"));
+        assert!(!super::matches("sport/+", "sport/tennis/player1"));
+        assert!(super::matches("+/+", "/finance"));
+        assert!(super::matches("/+", "/finance"));
+        assert!(!super::matches("+", "/finance"));
+        assert!(super::matches("sport/+/player1", "sport/tennis/player1"));
+        assert!(super::matches("+/tennis/#", "sport/tennis"));
+    }
+
+    const LIMITS: super::Limits = super::Limits {
+        max_len: 16,
+        max_levels: 4,
+    };
+
+    #[test]
+    fn validate_topic_name() {
+        assert_eq!(super::validate_topic_name("a/b/c/d", LIMITS), Ok(()));
+        assert_eq!(super::validate_topic_name("/", LIMITS), Ok(()));
+        assert_eq!(super::validate_topic_name("$SYS/a", LIMITS), Ok(()));
+
+        assert_eq!(super::validate_topic_name("", LIMITS), Err("topic is empty"));
+        assert_eq!(super::validate_topic_name("a/+", LIMITS), Err("topic name contains a wildcard"));
+        assert_eq!(super::validate_topic_name("a/#", LIMITS), Err("topic name contains a wildcard"));
+        assert_eq!(super::validate_topic_name("a+b", LIMITS), Err("topic name contains a wildcard"));
+        assert_eq!(super::validate_topic_name("a/\0", LIMITS), Err("topic contains a NUL character"));
+    }
+
+    #[test]
+    fn validate_topic_filter() {
+        assert_eq!(super::validate_topic_filter("#", LIMITS), Ok(()));
+        assert_eq!(super::validate_topic_filter("+", LIMITS), Ok(()));
+        assert_eq!(super::validate_topic_filter("a/+/b/#", LIMITS), Ok(()));
+        assert_eq!(super::validate_topic_filter("+/+", LIMITS), Ok(()));
+        assert_eq!(super::validate_topic_filter("/#", LIMITS), Ok(()));
+
+        assert_eq!(super::validate_topic_filter("", LIMITS), Err("topic is empty"));
+        assert_eq!(super::validate_topic_filter("a/#/b", LIMITS), Err("topic filter has a multi-level wildcard that is not its last level"));
+        assert_eq!(super::validate_topic_filter("#/", LIMITS), Err("topic filter has a multi-level wildcard that is not its last level"));
+        assert_eq!(super::validate_topic_filter("a#", LIMITS), Err("topic filter has a wildcard that is not a whole level"));
+        assert_eq!(super::validate_topic_filter("a/b+", LIMITS), Err("topic filter has a wildcard that is not a whole level"));
+        assert_eq!(super::validate_topic_filter("a/++", LIMITS), Err("topic filter has a wildcard that is not a whole level"));
+        assert_eq!(super::validate_topic_filter("a/\0/#", LIMITS), Err("topic contains a NUL character"));
+    }
+
+    #[test]
+    fn validate_limits() {
+        assert_eq!(super::validate_topic_name("0123456789abcdef", LIMITS), Ok(()));
+        assert_eq!(super::validate_topic_name("0123456789abcdefg", LIMITS), Err("topic is too long"));
+        assert_eq!(super::validate_topic_filter("0123456789abcde#", LIMITS), Err("topic filter has a wildcard that is not a whole level"));
+        assert_eq!(super::validate_topic_filter("0123456789abcd/#", LIMITS), Ok(()));
+        assert_eq!(super::validate_topic_filter("0123456789abcde/#", LIMITS), Err("topic is too long"));
+
+        assert_eq!(super::validate_topic_name("a/b/c/d", LIMITS), Ok(()));
+        assert_eq!(super::validate_topic_name("a/b/c/d/e", LIMITS), Err("topic has too many levels"));
+        assert_eq!(super::validate_topic_name("///", LIMITS), Ok(()));
+        assert_eq!(super::validate_topic_name("////", LIMITS), Err("topic has too many levels"));
+        assert_eq!(super::validate_topic_filter("a/b/c/#", LIMITS), Ok(()));
+        assert_eq!(super::validate_topic_filter("a/b/c/d/#", LIMITS), Err("topic has too many levels"));
+
+        // However large max_len is, a topic still has to fit in the u16 length prefix on the wire.
+        let limits = super::Limits {
+            max_len: usize::MAX,
+            max_levels: usize::MAX,
+        };
+        assert_eq!(super::validate_topic_name(&"a".repeat(usize::from(u16::MAX)), limits), Ok(()));
+        assert_eq!(super::validate_topic_name(&"a".repeat(usize::from(u16::MAX) + 1), limits), Err("topic is too long"));
+    }
+}
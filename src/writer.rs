@@ -11,12 +11,11 @@ impl Writer {
         }
     }
 
-    pub(crate) fn poll(&mut self, _cx: &mut std::task::Context<'_>, buf: &mut bytes::BytesMut) -> std::task::Poll<std::io::Result<()>> {
-        match (&*self.inner).write(&buf) {
-            Ok(written) => {
-                bytes::Buf::advance(buf, written);
-                std::task::Poll::Ready(Ok(()))
-            },
+    // Writes as much of the given buffers as possible with a single syscall, and returns the number of bytes written.
+    pub(crate) fn poll(&mut self, _cx: &mut std::task::Context<'_>, bufs: &[std::io::IoSlice<'_>]) -> std::task::Poll<std::io::Result<usize>> {
+        match (&*self.inner).write_vectored(bufs) {
+            Ok(0) if bufs.iter().any(|buf| !buf.is_empty()) => std::task::Poll::Ready(Err(std::io::ErrorKind::WriteZero.into())),
+            Ok(written) => std::task::Poll::Ready(Ok(written)),
             Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => std::task::Poll::Pending,
             Err(err) => std::task::Poll::Ready(Err(err)),
         }